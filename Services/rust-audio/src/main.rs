@@ -1,23 +1,57 @@
+use rust_audio::audio::analyze_audio_server::AnalyzeAudioServer;
 use rust_audio::audio::boost_audio_server::BoostAudioServer;
 use rust_audio::audio::convert_audio_server::ConvertAudioServer;
+use rust_audio::audio::job_audio_server::JobAudioServer;
 use rust_audio::audio::merge_audio_server::MergeAudioServer;
 use rust_audio::audio::metadata_audio_server::MetadataAudioServer;
+use rust_audio::audio::pipeline_audio_server::PipelineAudioServer;
 use rust_audio::audio::trim_audio_server::TrimAudioServer;
+use rust_audio::services::analyze::AnalyzeService;
 use rust_audio::services::boost::BoostService;
 use rust_audio::services::convert::ConvertService;
+use rust_audio::services::job::JobService;
 use rust_audio::services::merge::MergeService;
 use rust_audio::services::metadata::MetadataService;
+use rust_audio::services::pipeline::PipelineService;
 use rust_audio::services::trim::TrimService;
+use rust_audio::utils::{batch, pool, process};
+use std::time::Duration;
 use tonic::transport::Server;
 
 use rust_audio::audio::compress_audio_server::CompressAudioServer;
 use rust_audio::services::compress::CompressService;
 
+fn env_usize(name: &str) -> Option<usize> {
+    std::env::var(name).ok().and_then(|v| v.parse().ok())
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let addr = "0.0.0.0:50051".parse()?;
 
-    println!("Audio service running on {}", addr);
+    // Blocking ffmpeg work: POOL_WORKERS run at once, POOL_QUEUE_DEPTH may wait
+    // for a slot, anything beyond that gets RESOURCE_EXHAUSTED.
+    let workers = env_usize("POOL_WORKERS")
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(2, |n| n.get()));
+    let queue_depth = env_usize("POOL_QUEUE_DEPTH").unwrap_or(workers * 4);
+    pool::init(workers, queue_depth);
+
+    // Files of a single batch request processed at the same time.
+    let batch_limit = env_usize("BATCH_PARALLELISM").unwrap_or(4);
+    batch::init(batch_limit);
+
+    // Upper bound for a single ffmpeg/ffprobe run; unlimited when unset.
+    if let Some(secs) = env_usize("FFMPEG_TIMEOUT_SECS") {
+        process::set_timeout(Duration::from_secs(secs as u64));
+    }
+
+    // Number of asynchronous jobs processed at the same time.
+    let job_workers = env_usize("JOB_WORKERS").unwrap_or(2);
+
+    println!(
+        "Audio service running on {} ({} workers, queue depth {}, {} files per batch)",
+        addr, workers, queue_depth, batch_limit
+    );
 
     Server::builder()
         .add_service(
@@ -50,6 +84,21 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .max_decoding_message_size(100 * 1024 * 1024)
                 .max_encoding_message_size(100 * 1024 * 1024),
         )
+        .add_service(
+            PipelineAudioServer::new(PipelineService::default())
+                .max_decoding_message_size(100 * 1024 * 1024)
+                .max_encoding_message_size(100 * 1024 * 1024),
+        )
+        .add_service(
+            AnalyzeAudioServer::new(AnalyzeService::default())
+                .max_decoding_message_size(100 * 1024 * 1024)
+                .max_encoding_message_size(100 * 1024 * 1024),
+        )
+        .add_service(
+            JobAudioServer::new(JobService::new(job_workers))
+                .max_decoding_message_size(100 * 1024 * 1024)
+                .max_encoding_message_size(100 * 1024 * 1024),
+        )
         .serve(addr)
         .await?;
 
@@ -0,0 +1,401 @@
+use crate::audio::{
+    self, AudioInfo, AudioResponse, LoudnessInfo, LoudnessRequest, LoudnessResponse,
+    LoudnessStreamRequest, ProbeRequest, ProbeResponse, ProbeStreamRequest, SilenceInfo,
+    SilenceRegion, SilenceRequest, SilenceResponse, SilenceStreamRequest, SpectrogramRequest,
+    SpectrogramStreamRequest, Tag, WaveformRequest, WaveformStreamRequest,
+    analyze_audio_server::AnalyzeAudio,
+};
+use crate::utils::batch::{BatchOutput, FileOutcome, run_batch};
+use crate::utils::error::AudioError;
+use crate::utils::loudness::{Loudness, ReplayGain, loudness_file, loudness_path};
+use crate::utils::pool::pool;
+use crate::utils::probe::{ProbeInfo, probe_bytes, probe_path};
+use crate::utils::silence::{Silence, SilenceOptions, detect_silence_file, detect_silence_path};
+use crate::utils::spectrogram::{
+    Colormap, FreqScale, SpectrogramOptions, Window, spectrogram_file, spectrogram_path,
+};
+use crate::utils::stream::{AudioChunkStream, output_path, receive_upload, send_file};
+use crate::utils::waveform::{Peaks, waveform_file, waveform_path};
+use std::path::Path;
+use tonic::{Request, Response, Status, Streaming};
+
+fn ext_of(name: &str) -> Option<&str> {
+    Path::new(name).extension().and_then(|e| e.to_str())
+}
+
+fn audio_info(filename: String, info: ProbeInfo) -> AudioInfo {
+    AudioInfo {
+        filename,
+        duration: info.duration,
+        sample_rate: info.sample_rate,
+        channels: info.channels,
+        codec: info.codec,
+        container: info.container,
+        bitrate: info.bitrate,
+        bit_depth: info.bit_depth,
+        tags: info
+            .tags
+            .into_iter()
+            .map(|(key, value)| Tag { key, value })
+            .collect(),
+        error: String::new(),
+    }
+}
+
+/// One report per input, in order: `ok` builds it from a success, `failed`
+/// from the file's error. Fails only when no file could be analyzed.
+fn per_file<T, R>(
+    names: Vec<String>,
+    outcomes: Vec<FileOutcome<T>>,
+    ok: impl Fn(String, T) -> R,
+    failed: impl Fn(String, String) -> R,
+) -> Result<Vec<R>, Status> {
+    let batch = BatchOutput::collect(names, outcomes);
+    if batch.outputs.is_empty() && batch.has_failures() {
+        return Err(batch.failure());
+    }
+
+    let BatchOutput {
+        outputs, results, ..
+    } = batch;
+    let mut outputs = outputs.into_iter();
+    Ok(results
+        .into_iter()
+        .map(|r| match r.ok.then(|| outputs.next()).flatten() {
+            Some((_, value)) => ok(r.filename, value),
+            None => failed(r.filename, r.error),
+        })
+        .collect())
+}
+
+fn probe_response(
+    names: Vec<String>,
+    outcomes: Vec<FileOutcome<ProbeInfo>>,
+) -> Result<Response<ProbeResponse>, Status> {
+    let files = per_file(names, outcomes, audio_info, |filename, error| AudioInfo {
+        filename,
+        error,
+        ..Default::default()
+    })?;
+    Ok(Response::new(ProbeResponse { files }))
+}
+
+pub(crate) fn loudness_info(filename: String, l: &Loudness) -> LoudnessInfo {
+    LoudnessInfo {
+        filename,
+        integrated_lufs: l.integrated,
+        short_term_max_lufs: l.short_term_max,
+        momentary_max_lufs: l.momentary_max,
+        loudness_range_lu: l.range,
+        sample_peak_dbfs: l.sample_peak,
+        true_peak_dbtp: l.true_peak,
+        error: String::new(),
+        rms_dbfs: l.rms,
+    }
+}
+
+pub(crate) fn replaygain_message(rg: &ReplayGain) -> audio::ReplayGain {
+    audio::ReplayGain {
+        track_gain_db: rg.track_gain,
+        track_peak: rg.track_peak,
+        album_gain_db: rg.album_gain,
+        album_peak: rg.album_peak,
+    }
+}
+
+fn loudness_response(
+    names: Vec<String>,
+    outcomes: Vec<FileOutcome<Loudness>>,
+) -> Result<Response<LoudnessResponse>, Status> {
+    let files = per_file(
+        names,
+        outcomes,
+        |filename, l| loudness_info(filename, &l),
+        |filename, error| LoudnessInfo {
+            filename,
+            error,
+            ..Default::default()
+        },
+    )?;
+    Ok(Response::new(LoudnessResponse { files }))
+}
+
+fn silence_info(filename: String, silence: Silence) -> SilenceInfo {
+    SilenceInfo {
+        filename,
+        duration: silence.seconds(silence.frames),
+        regions: silence
+            .regions
+            .iter()
+            .map(|&(start, end)| SilenceRegion {
+                start_s: silence.seconds(start),
+                end_s: silence.seconds(end),
+                start_sample: start,
+                end_sample: end,
+            })
+            .collect(),
+        error: String::new(),
+    }
+}
+
+fn silence_response(
+    names: Vec<String>,
+    outcomes: Vec<FileOutcome<Silence>>,
+) -> Result<Response<SilenceResponse>, Status> {
+    let files = per_file(names, outcomes, silence_info, |filename, error| {
+        SilenceInfo {
+            filename,
+            error,
+            ..Default::default()
+        }
+    })?;
+    Ok(Response::new(SilenceResponse { files }))
+}
+
+/// Pair each inline file with its name (`input` when missing).
+fn named_files(file_data: Vec<Vec<u8>>, filenames: &[String]) -> Vec<(String, Vec<u8>)> {
+    file_data
+        .into_iter()
+        .enumerate()
+        .map(|(i, data)| (filenames.get(i).cloned().unwrap_or("input".into()), data))
+        .collect()
+}
+
+/// Waveform settings with defaults applied: (points per second, bits, output format).
+fn waveform_options(req: &WaveformRequest) -> Result<(u32, u32, String), Status> {
+    let points = if req.points_per_second == 0 {
+        100
+    } else {
+        req.points_per_second
+    };
+    let bits = if req.bits == 0 { 16 } else { req.bits };
+    let format = match req.output_format.to_ascii_lowercase().as_str() {
+        "" | "json" => "json",
+        "dat" => "dat",
+        other => {
+            return Err(Status::invalid_argument(format!(
+                "Invalid waveform output_format '{}' (must be 'json' or 'dat')",
+                other
+            )));
+        }
+    };
+    Ok((points, bits, format.to_string()))
+}
+
+fn encode_peaks(peaks: &Peaks, format: &str) -> Vec<u8> {
+    match format {
+        "dat" => peaks.to_dat(),
+        _ => peaks.to_json().into_bytes(),
+    }
+}
+
+/// Spectrogram settings with defaults applied.
+fn spectrogram_options(req: &SpectrogramRequest) -> Result<SpectrogramOptions, Status> {
+    let or = |v: u32, default: u32| if v == 0 { default } else { v };
+    Ok(SpectrogramOptions {
+        fft_size: or(req.fft_size, 2048) as usize,
+        window: Window::parse(&req.window)?,
+        scale: FreqScale::parse(&req.scale)?,
+        min_db: req.min_db.unwrap_or(-120.0),
+        max_db: req.max_db.unwrap_or(0.0),
+        colormap: Colormap::parse(&req.colormap)?,
+        width: or(req.width, 1024),
+        height: or(req.height, 512),
+    })
+}
+
+fn stem_of(filename: &str) -> &str {
+    Path::new(filename)
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("output")
+}
+
+#[derive(Debug, Default)]
+pub struct AnalyzeService {}
+
+#[tonic::async_trait]
+impl AnalyzeAudio for AnalyzeService {
+    async fn probe(
+        &self,
+        request: Request<ProbeRequest>,
+    ) -> Result<Response<ProbeResponse>, Status> {
+        let req = request.into_inner();
+        let items = named_files(req.file_data, &req.filenames);
+        let names = items.iter().map(|(name, _)| name.clone()).collect();
+        let results = run_batch(items, move |_, (filename, data)| {
+            probe_bytes(&data, ext_of(&filename)).map(|info| (filename, info))
+        })
+        .await?;
+
+        probe_response(names, results)
+    }
+
+    async fn probe_stream(
+        &self,
+        request: Request<Streaming<ProbeStreamRequest>>,
+    ) -> Result<Response<ProbeResponse>, Status> {
+        let upload = receive_upload(request.into_inner()).await?;
+        let names = upload.files.iter().map(|(name, _)| name.clone()).collect();
+        let results = run_batch(upload.files, move |_, (filename, in_path)| {
+            probe_path(&in_path).map(|info| (filename, info))
+        })
+        .await?;
+
+        probe_response(names, results)
+    }
+
+    async fn waveform(
+        &self,
+        request: Request<WaveformRequest>,
+    ) -> Result<Response<AudioResponse>, Status> {
+        let req = request.into_inner();
+        let (points, bits, format) = waveform_options(&req)?;
+        let ext = ext_of(&req.filename).unwrap_or("dat").to_string();
+
+        let fmt = format.clone();
+        let data = pool()
+            .run(move || {
+                waveform_file(req.file_data, &ext, points, bits)
+                    .map(|peaks| encode_peaks(&peaks, &fmt))
+            })
+            .await??;
+
+        Ok(Response::new(AudioResponse {
+            file_data: data,
+            filename: format!("{}.{}", stem_of(&req.filename), format),
+            format,
+            results: Vec::new(),
+        }))
+    }
+
+    type WaveformStreamStream = AudioChunkStream;
+
+    async fn waveform_stream(
+        &self,
+        request: Request<Streaming<WaveformStreamRequest>>,
+    ) -> Result<Response<Self::WaveformStreamStream>, Status> {
+        let mut upload = receive_upload(request.into_inner()).await?;
+        let (points, bits, format) = waveform_options(&upload.header)?;
+        let (filename, in_path) = upload.files.remove(0);
+
+        let fmt = format.clone();
+        let out_path = output_path()?;
+        let out_path = pool()
+            .run(move || {
+                let peaks = waveform_path(&in_path, points, bits)?;
+                std::fs::write(&out_path, encode_peaks(&peaks, &fmt))
+                    .map_err(AudioError::io("write waveform"))?;
+                Ok::<_, AudioError>(out_path)
+            })
+            .await??;
+
+        let name = format!("{}.{}", stem_of(&filename), format);
+        Ok(Response::new(send_file(out_path, format, name)))
+    }
+
+    async fn spectrogram(
+        &self,
+        request: Request<SpectrogramRequest>,
+    ) -> Result<Response<AudioResponse>, Status> {
+        let req = request.into_inner();
+        let opts = spectrogram_options(&req)?;
+        let ext = ext_of(&req.filename).unwrap_or("dat").to_string();
+
+        let png = pool()
+            .run(move || spectrogram_file(req.file_data, &ext, &opts))
+            .await??;
+
+        Ok(Response::new(AudioResponse {
+            file_data: png,
+            format: "png".to_string(),
+            filename: format!("{}.png", stem_of(&req.filename)),
+            results: Vec::new(),
+        }))
+    }
+
+    type SpectrogramStreamStream = AudioChunkStream;
+
+    async fn spectrogram_stream(
+        &self,
+        request: Request<Streaming<SpectrogramStreamRequest>>,
+    ) -> Result<Response<Self::SpectrogramStreamStream>, Status> {
+        let mut upload = receive_upload(request.into_inner()).await?;
+        let opts = spectrogram_options(&upload.header)?;
+        let (filename, in_path) = upload.files.remove(0);
+
+        let out_path = output_path()?;
+        let out_path = pool()
+            .run(move || {
+                let png = spectrogram_path(&in_path, &opts)?;
+                std::fs::write(&out_path, png).map_err(AudioError::io("write spectrogram"))?;
+                Ok::<_, AudioError>(out_path)
+            })
+            .await??;
+
+        let name = format!("{}.png", stem_of(&filename));
+        Ok(Response::new(send_file(out_path, "png".to_string(), name)))
+    }
+
+    async fn analyze_loudness(
+        &self,
+        request: Request<LoudnessRequest>,
+    ) -> Result<Response<LoudnessResponse>, Status> {
+        let req = request.into_inner();
+        let items = named_files(req.file_data, &req.filenames);
+        let names = items.iter().map(|(name, _)| name.clone()).collect();
+        let results = run_batch(items, move |_, (filename, data)| {
+            let ext = ext_of(&filename).unwrap_or("dat").to_string();
+            loudness_file(data, &ext).map(|l| (filename, l))
+        })
+        .await?;
+
+        loudness_response(names, results)
+    }
+
+    async fn analyze_loudness_stream(
+        &self,
+        request: Request<Streaming<LoudnessStreamRequest>>,
+    ) -> Result<Response<LoudnessResponse>, Status> {
+        let upload = receive_upload(request.into_inner()).await?;
+        let names = upload.files.iter().map(|(name, _)| name.clone()).collect();
+        let results = run_batch(upload.files, move |_, (filename, in_path)| {
+            loudness_path(&in_path).map(|l| (filename, l))
+        })
+        .await?;
+
+        loudness_response(names, results)
+    }
+
+    async fn detect_silence(
+        &self,
+        request: Request<SilenceRequest>,
+    ) -> Result<Response<SilenceResponse>, Status> {
+        let req = request.into_inner();
+        let opts = SilenceOptions::new(req.threshold_db, req.min_duration_ms)?;
+        let items = named_files(req.file_data, &req.filenames);
+        let names = items.iter().map(|(name, _)| name.clone()).collect();
+        let results = run_batch(items, move |_, (filename, data)| {
+            let ext = ext_of(&filename).unwrap_or("dat").to_string();
+            detect_silence_file(data, &ext, &opts).map(|s| (filename, s))
+        })
+        .await?;
+
+        silence_response(names, results)
+    }
+
+    async fn detect_silence_stream(
+        &self,
+        request: Request<Streaming<SilenceStreamRequest>>,
+    ) -> Result<Response<SilenceResponse>, Status> {
+        let upload = receive_upload(request.into_inner()).await?;
+        let opts = SilenceOptions::new(upload.header.threshold_db, upload.header.min_duration_ms)?;
+        let names = upload.files.iter().map(|(name, _)| name.clone()).collect();
+        let results = run_batch(upload.files, move |_, (filename, in_path)| {
+            detect_silence_path(&in_path, &opts).map(|s| (filename, s))
+        })
+        .await?;
+
+        silence_response(names, results)
+    }
+}
@@ -1,9 +1,72 @@
 use crate::audio::{
-    AudioResponse, BoostManualRequest, BoostNormalizeRequest, boost_audio_server::BoostAudio,
+    self, AudioResponse, BoostManualRequest, BoostManualStreamRequest, BoostNormalizeRequest,
+    BoostNormalizeStreamRequest, FileResult, boost_audio_server::BoostAudio,
 };
-use crate::utils::boost::{boost_file, normalize_file};
-use crate::utils::zip::make_zip;
-use tonic::{Request, Response, Status};
+use crate::services::analyze::{loudness_info, replaygain_message};
+use crate::utils::batch::{BatchOutput, FileOutcome, respond, run_batch};
+use crate::utils::boost::{
+    DEFAULT_CEILING, GainReport, LoudnessTarget, NormalizeMode, album_path, album_plan, boost_file,
+    boost_path, normalize_file, normalize_path,
+};
+use crate::utils::loudness::{LoudnessMeter, meter_path};
+use crate::utils::stream::{
+    AudioChunkStream, output_path, read_outputs, receive_upload, send_outputs,
+};
+use crate::utils::temp::spool;
+use tempfile::TempPath;
+use tonic::{Request, Response, Status, Streaming};
+
+fn normalize_mode(req: &BoostNormalizeRequest) -> Result<NormalizeMode, Status> {
+    let target = LoudnessTarget::resolve(&req.preset, req.target_i, req.target_tp, req.target_lra)?;
+    Ok(NormalizeMode::parse(&req.mode, target, req.target_db)?)
+}
+
+fn attach_report(result: &mut FileResult, report: GainReport) {
+    result.gain = Some(audio::GainReport {
+        applied_gain_db: report.applied_gain,
+        before: Some(loudness_info(String::new(), &report.before)),
+        after: Some(loudness_info(String::new(), &report.after)),
+        mode: report.mode.as_str().to_string(),
+        clipped_samples: report.clipped,
+        warning: report.warning().unwrap_or_default(),
+        replaygain: Some(replaygain_message(&report.replaygain)),
+    });
+}
+
+/// Limiter ceiling for BoostManual; `None` when the user turned limiting off.
+fn boost_ceiling(req: &BoostManualRequest) -> Option<f64> {
+    req.limit
+        .unwrap_or(true)
+        .then(|| req.ceiling_db.unwrap_or(DEFAULT_CEILING))
+}
+
+/// Album mode, second pass: derive the common gain from every file that could
+/// be measured and apply it to each of them.
+async fn normalize_album(
+    measured: Vec<FileOutcome<(TempPath, LoudnessMeter)>>,
+    mode: NormalizeMode,
+) -> Result<Vec<FileOutcome<(TempPath, GainReport)>>, Status> {
+    let meters: Vec<&LoudnessMeter> = measured
+        .iter()
+        .filter_map(|outcome| outcome.as_ref().ok())
+        .map(|(_, (_, meter))| meter)
+        .collect();
+    let plan = if meters.is_empty() {
+        None
+    } else {
+        Some(album_plan(&meters, mode)?)
+    };
+
+    run_batch(measured, move |_, outcome| {
+        let (filename, (in_path, meter)) = outcome?;
+        let plan = plan.as_ref().expect("measured files have a plan");
+        let ext = filename.split('.').next_back().unwrap_or("mp3").to_string();
+        let out_path = output_path()?;
+        album_path(&in_path, &out_path, &ext, plan, meter.finish())
+            .map(|report| (filename, (out_path, report)))
+    })
+    .await
+}
 
 #[derive(Debug, Default)]
 pub struct BoostService {}
@@ -15,36 +78,30 @@ impl BoostAudio for BoostService {
         request: Request<BoostManualRequest>,
     ) -> Result<Response<AudioResponse>, Status> {
         let req = request.into_inner();
-        let mut outputs = Vec::new();
+        let gain = req.gain;
+        let ceiling = boost_ceiling(&req);
+        let items: Vec<(String, Vec<u8>)> = req
+            .file_data
+            .into_iter()
+            .enumerate()
+            .map(|(i, data)| {
+                (
+                    req.filenames.get(i).cloned().unwrap_or("output".into()),
+                    data,
+                )
+            })
+            .collect();
 
-        for (i, data) in req.file_data.into_iter().enumerate() {
-            let filename = req.filenames.get(i).cloned().unwrap_or("output".into());
-            let ext = filename.split('.').last().unwrap_or("mp3");
+        let names = items.iter().map(|(name, _)| name.clone()).collect();
 
-            match boost_file(data, ext, req.gain) {
-                Ok(bytes) => outputs.push((filename, bytes)),
-                Err(e) => return Err(Status::internal(e)),
-            }
-        }
+        let results = run_batch(items, move |_, (filename, data)| {
+            let ext = filename.split('.').next_back().unwrap_or("mp3").to_string();
+            boost_file(data, &ext, gain, ceiling).map(|out| (filename, out))
+        })
+        .await?;
+        let batch = BatchOutput::collect(names, results).with_reports(attach_report);
 
-        if outputs.len() == 1 {
-            let (filename, bytes) = outputs.into_iter().next().unwrap();
-            let ext = filename.split('.').last().unwrap_or("mp3");
-            Ok(Response::new(AudioResponse {
-                file_data: bytes,
-                format: ext.to_string(),
-                filename,
-            }))
-        } else {
-            match make_zip(outputs) {
-                Ok(zip_bytes) => Ok(Response::new(AudioResponse {
-                    file_data: zip_bytes,
-                    format: "zip".to_string(),
-                    filename: "sonic-tools.zip".to_string(),
-                })),
-                Err(e) => Err(Status::internal(e)),
-            }
-        }
+        respond(batch, None).await
     }
 
     async fn boost_normalize(
@@ -52,35 +109,92 @@ impl BoostAudio for BoostService {
         request: Request<BoostNormalizeRequest>,
     ) -> Result<Response<AudioResponse>, Status> {
         let req = request.into_inner();
-        let mut outputs = Vec::new();
+        let mode = normalize_mode(&req)?;
+        let items: Vec<(String, Vec<u8>)> = req
+            .file_data
+            .into_iter()
+            .enumerate()
+            .map(|(i, data)| {
+                (
+                    req.filenames.get(i).cloned().unwrap_or("output".into()),
+                    data,
+                )
+            })
+            .collect();
 
-        for (i, data) in req.file_data.into_iter().enumerate() {
-            let filename = req.filenames.get(i).cloned().unwrap_or("output".into());
-            let ext = filename.split('.').last().unwrap_or("mp3");
+        let names = items.iter().map(|(name, _)| name.clone()).collect();
 
-            match normalize_file(data, ext) {
-                Ok(bytes) => outputs.push((filename, bytes)),
-                Err(e) => return Err(Status::internal(e)),
-            }
+        if req.album {
+            let measured = run_batch(items, move |_, (filename, data)| {
+                let ext = filename.split('.').next_back().unwrap_or("mp3").to_string();
+                let in_path = spool(&data, &ext)?;
+                meter_path(&in_path).map(|meter| (filename, (in_path, meter)))
+            })
+            .await?;
+            let results = normalize_album(measured, mode).await?;
+            let outcomes = read_outputs(results).await;
+            let batch = BatchOutput::collect(names, outcomes).with_reports(attach_report);
+            return respond(batch, None).await;
         }
 
-        if outputs.len() == 1 {
-            let (filename, bytes) = outputs.into_iter().next().unwrap();
-            let ext = filename.split('.').last().unwrap_or("mp3");
-            Ok(Response::new(AudioResponse {
-                file_data: bytes,
-                format: ext.to_string(),
-                filename,
-            }))
+        let results = run_batch(items, move |_, (filename, data)| {
+            let ext = filename.split('.').next_back().unwrap_or("mp3").to_string();
+            normalize_file(data, &ext, mode).map(|out| (filename, out))
+        })
+        .await?;
+        let batch = BatchOutput::collect(names, results).with_reports(attach_report);
+
+        respond(batch, None).await
+    }
+
+    type BoostManualStreamStream = AudioChunkStream;
+
+    async fn boost_manual_stream(
+        &self,
+        request: Request<Streaming<BoostManualStreamRequest>>,
+    ) -> Result<Response<Self::BoostManualStreamStream>, Status> {
+        let upload = receive_upload(request.into_inner()).await?;
+        let gain = upload.header.gain;
+        let ceiling = boost_ceiling(&upload.header);
+        let names = upload.files.iter().map(|(name, _)| name.clone()).collect();
+        let results = run_batch(upload.files, move |_, (filename, in_path)| {
+            let ext = filename.split('.').next_back().unwrap_or("mp3").to_string();
+            let out_path = output_path()?;
+            boost_path(&in_path, &out_path, &ext, gain, ceiling)
+                .map(|report| (filename, (out_path, report)))
+        })
+        .await?;
+        let batch = BatchOutput::collect(names, results).with_reports(attach_report);
+
+        send_outputs(batch, None).await
+    }
+
+    type BoostNormalizeStreamStream = AudioChunkStream;
+
+    async fn boost_normalize_stream(
+        &self,
+        request: Request<Streaming<BoostNormalizeStreamRequest>>,
+    ) -> Result<Response<Self::BoostNormalizeStreamStream>, Status> {
+        let upload = receive_upload(request.into_inner()).await?;
+        let mode = normalize_mode(&upload.header)?;
+        let names = upload.files.iter().map(|(name, _)| name.clone()).collect();
+        let results = if upload.header.album {
+            let measured = run_batch(upload.files, move |_, (filename, in_path)| {
+                meter_path(&in_path).map(|meter| (filename, (in_path, meter)))
+            })
+            .await?;
+            normalize_album(measured, mode).await?
         } else {
-            match make_zip(outputs) {
-                Ok(zip_bytes) => Ok(Response::new(AudioResponse {
-                    file_data: zip_bytes,
-                    format: "zip".to_string(),
-                    filename: "sonic-tools.zip".to_string(),
-                })),
-                Err(e) => Err(Status::internal(e)),
-            }
-        }
+            run_batch(upload.files, move |_, (filename, in_path)| {
+                let ext = filename.split('.').next_back().unwrap_or("mp3").to_string();
+                let out_path = output_path()?;
+                normalize_path(&in_path, &out_path, &ext, mode)
+                    .map(|report| (filename, (out_path, report)))
+            })
+            .await?
+        };
+        let batch = BatchOutput::collect(names, results).with_reports(attach_report);
+
+        send_outputs(batch, None).await
     }
 }
@@ -1,11 +1,41 @@
 use crate::audio::{
-    AudioResponse, CompressPercentageRequest, CompressQualityRequest, CompressSizeRequest,
-    compress_audio_server::CompressAudio,
+    AudioResponse, CompressPercentageRequest, CompressPercentageStreamRequest,
+    CompressQualityRequest, CompressQualityStreamRequest, CompressSizeRequest,
+    CompressSizeStreamRequest, compress_audio_server::CompressAudio,
 };
-use crate::utils::compress::compress_file;
-use crate::utils::ffmpeg::{probe_bitrate, probe_duration};
-use crate::utils::zip::make_zip;
-use tonic::{Request, Response, Status};
+use crate::utils::batch::{BatchOutput, respond, run_batch};
+use crate::utils::compress::{compress_file, compress_path};
+use crate::utils::probe::{probe_bytes, probe_path};
+use crate::utils::stream::{AudioChunkStream, output_path, receive_upload, send_outputs};
+use tonic::{Request, Response, Status, Streaming};
+
+/// Bitrate (kbps) that keeps `percentage` percent of `original_bitrate` (bps).
+fn percentage_bitrate(original_bitrate: i32, percentage: i32) -> i32 {
+    let target_bitrate = ((original_bitrate as f32) * (percentage as f32 / 100.0)) as i32 / 1000;
+    target_bitrate.max(32)
+}
+
+/// Bitrate (kbps) that makes `duration` seconds of audio fit in `size_mb` MiB.
+fn size_bitrate(size_mb: i32, duration: f32) -> i32 {
+    let target_size_bytes = (size_mb as u64) * 1024 * 1024;
+    let target_bitrate = ((target_size_bytes as f32 * 8.0) / duration) as i32 / 1000;
+
+    // enforce min bitrate
+    target_bitrate.max(32)
+}
+
+fn quality_bitrate(quality: &str) -> i32 {
+    match quality {
+        "low" => 64,
+        "medium" => 128,
+        "high" => 256,
+        _ => 128,
+    }
+}
+
+fn ext_of(filename: &str) -> &str {
+    filename.split('.').next_back().unwrap_or("mp3")
+}
 
 #[derive(Debug, Default)]
 pub struct CompressService {}
@@ -18,59 +48,42 @@ impl CompressAudio for CompressService {
     ) -> Result<Response<AudioResponse>, Status> {
         println!("Starting compression by percentage");
         let req = request.into_inner();
-        let mut outputs = Vec::new();
-
-        for (i, data) in req.file_data.into_iter().enumerate() {
-            let filename = req.filenames.get(i).cloned().unwrap_or("output".into());
+        let percentage = req.percentage;
+        let items: Vec<(String, Vec<u8>)> = req
+            .file_data
+            .into_iter()
+            .enumerate()
+            .map(|(i, data)| {
+                (
+                    req.filenames.get(i).cloned().unwrap_or("output".into()),
+                    data,
+                )
+            })
+            .collect();
+
+        let names = items.iter().map(|(name, _)| name.clone()).collect();
+        // Probe + compress of one file run together on the processing pool.
+        let results = run_batch(items, move |_, (filename, data)| {
             println!("filename gotten");
-            let ext = filename.split('.').last().unwrap_or("mp3");
+            let ext = filename.split('.').next_back().unwrap_or("mp3").to_string();
             println!("extension goten");
 
-            let original_bitrate = match probe_bitrate(&data, ext) {
-                Ok(b) => b,
-                Err(e) => return Err(Status::internal(e)),
-            };
-
-            println!("original bitrate gotten");
-            let mut target_bitrate =
-                ((original_bitrate as f32) * (req.percentage as f32 / 100.0)) as i32 / 1000;
-            println!("first target bitrate set");
-
-            if target_bitrate < 32 {
-                target_bitrate = 32
-            }
-            println!("final target bitrate set");
-
             let out_name = format!(
                 "{}.{}",
                 filename.trim_end_matches(&format!(".{}", ext)),
                 ext
             );
-            println!("compressin file");
-            match compress_file(data, ext, Some(target_bitrate)) {
-                Ok(bytes) => outputs.push((out_name, bytes)),
-                Err(e) => return Err(Status::internal(e)),
-            }
-        }
-
-        if outputs.len() == 1 {
-            let (filename, bytes) = outputs.into_iter().next().unwrap();
-            let ext = filename.split('.').last().unwrap_or("mp3");
-            Ok(Response::new(AudioResponse {
-                file_data: bytes,
-                format: ext.to_string(),
-                filename: filename,
-            }))
-        } else {
-            match make_zip(outputs) {
-                Ok(zip_bytes) => Ok(Response::new(AudioResponse {
-                    file_data: zip_bytes,
-                    format: "zip".to_string(),
-                    filename: "sonic-tools.zip".to_string(),
-                })),
-                Err(e) => Err(Status::internal(e)),
-            }
-        }
+
+            let original_bitrate = probe_bytes(&data, Some(&ext))?.bitrate as i32;
+            println!("original bitrate gotten");
+            let target_bitrate = percentage_bitrate(original_bitrate, percentage);
+            println!("final target bitrate set");
+            compress_file(data, &ext, Some(target_bitrate)).map(|bytes| (out_name, bytes))
+        })
+        .await?;
+        let batch = BatchOutput::collect(names, results);
+
+        respond(batch, None).await
     }
 
     async fn compress_size(
@@ -79,25 +92,24 @@ impl CompressAudio for CompressService {
     ) -> Result<Response<AudioResponse>, Status> {
         println!("compress started");
         let req = request.into_inner();
-        let mut outputs = Vec::new();
-
-        for (i, data) in req.file_data.into_iter().enumerate() {
+        let size = req.size;
+        let items: Vec<(String, Vec<u8>)> = req
+            .file_data
+            .into_iter()
+            .enumerate()
+            .map(|(i, data)| {
+                (
+                    req.filenames.get(i).cloned().unwrap_or("output".into()),
+                    data,
+                )
+            })
+            .collect();
+
+        let names = items.iter().map(|(name, _)| name.clone()).collect();
+
+        let results = run_batch(items, move |_, (filename, data)| {
             println!("loop starting");
-            let filename = req.filenames.get(i).cloned().unwrap_or("output".into());
-            let ext = filename.split('.').last().unwrap_or("mp3");
-
-            // 1. Probe duration
-            let duration = match probe_duration(&data) {
-                Ok(d) => d,
-                Err(e) => return Err(Status::internal(e)),
-            };
-
-            // 2. Calculate target bitrate
-            let target_size_bytes = (req.size as u64) * 1024 * 1024;
-            let target_bitrate = ((target_size_bytes as f32 * 8.0) / duration) as i32 / 1000;
-
-            // enforce min bitrate
-            let target_bitrate = target_bitrate.max(32);
+            let ext = filename.split('.').next_back().unwrap_or("mp3").to_string();
 
             let out_name = format!(
                 "{}_compressed.{}",
@@ -105,35 +117,21 @@ impl CompressAudio for CompressService {
                 ext
             );
 
-            println!("Target bitrate calc: {} kbps", target_bitrate);
+            // 1. Probe duration
+            let duration = probe_bytes(&data, Some(&ext))?.duration as f32;
 
-            match compress_file(data, ext, Some(target_bitrate)) {
-                Ok(bytes) => outputs.push((out_name, bytes)),
-                Err(e) => return Err(Status::internal(e)),
-            }
+            // 2. Calculate target bitrate
+            let target_bitrate = size_bitrate(size, duration);
+            println!("Target bitrate calc: {} kbps", target_bitrate);
 
+            let result = compress_file(data, &ext, Some(target_bitrate));
             println!("one looped finished");
-        }
+            result.map(|bytes| (out_name, bytes))
+        })
+        .await?;
+        let batch = BatchOutput::collect(names, results);
 
-        println!("loop finished");
-        if outputs.len() == 1 {
-            let (filename, bytes) = outputs.into_iter().next().unwrap();
-            let ext = filename.split('.').last().unwrap_or("mp3");
-            Ok(Response::new(AudioResponse {
-                file_data: bytes,
-                format: ext.to_string(),
-                filename,
-            }))
-        } else {
-            match make_zip(outputs) {
-                Ok(zip_bytes) => Ok(Response::new(AudioResponse {
-                    file_data: zip_bytes,
-                    format: "zip".to_string(),
-                    filename: "sonic-tools.zip".to_string(),
-                })),
-                Err(e) => Err(Status::internal(e)),
-            }
-        }
+        respond(batch, None).await
     }
 
     async fn compress_quality(
@@ -141,45 +139,104 @@ impl CompressAudio for CompressService {
         request: Request<CompressQualityRequest>,
     ) -> Result<Response<AudioResponse>, Status> {
         let req = request.into_inner();
-        let mut outputs = Vec::new();
+        let bitrate = Some(quality_bitrate(&req.quality));
+        let items: Vec<(String, Vec<u8>)> = req
+            .file_data
+            .into_iter()
+            .enumerate()
+            .map(|(i, data)| {
+                (
+                    req.filenames.get(i).cloned().unwrap_or("output".into()),
+                    data,
+                )
+            })
+            .collect();
 
         println!("compress_quality loop starting");
-        for (i, data) in req.file_data.into_iter().enumerate() {
-            let filename = req.filenames.get(i).cloned().unwrap_or("output".into());
-            let ext = filename.split('.').last().unwrap_or("mp3");
-            let bitrate = match req.quality.as_str() {
-                "low" => Some(64),
-                "medium" => Some(128),
-                "high" => Some(256),
-                _ => Some(128),
-            };
-
-            match compress_file(data, ext, bitrate) {
-                Ok(bytes) => outputs.push((filename, bytes)),
-                Err(e) => return Err(Status::internal(e)),
-            }
+        let names = items.iter().map(|(name, _)| name.clone()).collect();
+        let results = run_batch(items, move |_, (filename, data)| {
+            let ext = filename.split('.').next_back().unwrap_or("mp3").to_string();
+            let result = compress_file(data, &ext, bitrate);
             println!("one looped finished");
-        }
-        print!("loop finished");
-
-        if outputs.len() == 1 {
-            let (filename, bytes) = outputs.into_iter().next().unwrap();
-            let ext = filename.split('.').last().unwrap_or("mp3");
-            Ok(Response::new(AudioResponse {
-                file_data: bytes,
-                format: ext.to_string(),
-                filename: filename,
-            }))
-        } else {
-            println!("starting to make zip");
-            match make_zip(outputs) {
-                Ok(zip_bytes) => Ok(Response::new(AudioResponse {
-                    file_data: zip_bytes,
-                    format: "zip".to_string(),
-                    filename: "sonic-tools.zip".to_string(),
-                })),
-                Err(e) => Err(Status::internal(e)),
-            }
-        }
+            result.map(|bytes| (filename, bytes))
+        })
+        .await?;
+        let batch = BatchOutput::collect(names, results);
+        println!("loop finished");
+
+        respond(batch, None).await
+    }
+
+    type CompressPercentageStreamStream = AudioChunkStream;
+
+    async fn compress_percentage_stream(
+        &self,
+        request: Request<Streaming<CompressPercentageStreamRequest>>,
+    ) -> Result<Response<Self::CompressPercentageStreamStream>, Status> {
+        let upload = receive_upload(request.into_inner()).await?;
+        let percentage = upload.header.percentage;
+        let names = upload.files.iter().map(|(name, _)| name.clone()).collect();
+        let results = run_batch(upload.files, move |_, (filename, in_path)| {
+            let ext = ext_of(&filename).to_string();
+            let out_path = output_path()?;
+            let original_bitrate = probe_path(&in_path)?.bitrate as i32;
+            let target_bitrate = percentage_bitrate(original_bitrate, percentage);
+            compress_path(&in_path, &out_path, &ext, Some(target_bitrate))
+                .map(|_| (filename, out_path))
+        })
+        .await?;
+        let batch = BatchOutput::collect(names, results);
+
+        send_outputs(batch, None).await
+    }
+
+    type CompressSizeStreamStream = AudioChunkStream;
+
+    async fn compress_size_stream(
+        &self,
+        request: Request<Streaming<CompressSizeStreamRequest>>,
+    ) -> Result<Response<Self::CompressSizeStreamStream>, Status> {
+        let upload = receive_upload(request.into_inner()).await?;
+        let size = upload.header.size;
+        let names = upload.files.iter().map(|(name, _)| name.clone()).collect();
+        let results = run_batch(upload.files, move |_, (filename, in_path)| {
+            let ext = ext_of(&filename).to_string();
+            let out_name = format!(
+                "{}_compressed.{}",
+                filename.trim_end_matches(&format!(".{}", ext)),
+                ext
+            );
+
+            let out_path = output_path()?;
+            let duration = probe_path(&in_path)?.duration as f32;
+            let target_bitrate = size_bitrate(size, duration);
+            compress_path(&in_path, &out_path, &ext, Some(target_bitrate))
+                .map(|_| (out_name, out_path))
+        })
+        .await?;
+        let batch = BatchOutput::collect(names, results);
+
+        send_outputs(batch, None).await
+    }
+
+    type CompressQualityStreamStream = AudioChunkStream;
+
+    async fn compress_quality_stream(
+        &self,
+        request: Request<Streaming<CompressQualityStreamRequest>>,
+    ) -> Result<Response<Self::CompressQualityStreamStream>, Status> {
+        let upload = receive_upload(request.into_inner()).await?;
+        let req = upload.header;
+        let bitrate = Some(quality_bitrate(&req.quality));
+        let names = upload.files.iter().map(|(name, _)| name.clone()).collect();
+        let results = run_batch(upload.files, move |_, (filename, in_path)| {
+            let ext = ext_of(&filename).to_string();
+            let out_path = output_path()?;
+            compress_path(&in_path, &out_path, &ext, bitrate).map(|_| (filename, out_path))
+        })
+        .await?;
+        let batch = BatchOutput::collect(names, results);
+
+        send_outputs(batch, None).await
     }
 }
@@ -1,12 +1,32 @@
-use crate::audio::{AudioResponse, ConvertRequest, convert_audio_server::ConvertAudio};
-use crate::utils::zip::make_zip;
+use crate::audio::{
+    AudioResponse, ConvertRequest, ConvertStreamRequest, convert_audio_server::ConvertAudio,
+};
+use crate::utils::batch::{BatchOutput, respond, run_batch};
+use crate::utils::conversion::{convert_file, convert_path};
+use crate::utils::stream::{AudioChunkStream, output_path, receive_upload, send_outputs};
 use std::path::Path;
-use tonic::{Request, Response, Status};
+use tonic::{Request, Response, Status, Streaming};
 
 fn ext_of(name: &str) -> Option<&str> {
     Path::new(name).extension().and_then(|e| e.to_str())
 }
 
+/// Output filename for `filename` converted to `output_fmt` (already lowercased).
+fn converted_name(filename: &str, output_fmt: &str) -> String {
+    let stem = Path::new(filename)
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("output");
+
+    let out_ext = match output_fmt {
+        "aac" => "aac",
+        "alac" | "m4a" => "m4a",
+        other => other, // mp3, wav, flac, ogg, opus, aiff...
+    };
+
+    format!("{}.{}", stem, out_ext)
+}
+
 #[derive(Debug, Default)]
 pub struct ConvertService {}
 
@@ -18,54 +38,57 @@ impl ConvertAudio for ConvertService {
     ) -> Result<Response<AudioResponse>, Status> {
         println!("Starting convert service");
         let req = request.into_inner();
-        let mut outputs = Vec::new();
+        let output_fmt = req.output_format.to_ascii_lowercase();
+        let bitrate = req.bitrate;
+        let items: Vec<(String, Vec<u8>)> = req
+            .file_data
+            .into_iter()
+            .enumerate()
+            .map(|(i, data)| {
+                (
+                    req.filenames.get(i).cloned().unwrap_or("output".into()),
+                    data,
+                )
+            })
+            .collect();
 
-        for (i, data) in req.file_data.into_iter().enumerate() {
-            println!("Loop {}: got {} bytes", i, data.len());
+        let names = items.iter().map(|(name, _)| name.clone()).collect();
 
-            let filename = req.filenames.get(i).cloned().unwrap_or("output".into());
-            let output_fmt = req.output_format.to_ascii_lowercase();
+        let results = run_batch(items, move |i, (filename, data)| {
+            println!("Loop {}: got {} bytes", i, data.len());
+            let out_name = converted_name(&filename, &output_fmt);
+            let input_ext = ext_of(&filename);
 
-            let stem = Path::new(&filename)
-                .file_stem()
-                .and_then(|s| s.to_str())
-                .unwrap_or("output");
+            convert_file(data, &output_fmt, bitrate, input_ext).map(|bytes| (out_name, bytes))
+        })
+        .await?;
+        let batch = BatchOutput::collect(names, results);
+        println!("Total outputs: {}", batch.outputs.len());
+        respond(batch, Some(req.output_format)).await
+    }
 
-            let out_ext = match output_fmt.as_str() {
-                "aac" => "aac",
-                "alac" | "m4a" => "m4a",
-                other => other, // mp3, wav, flac, ogg, opus, aiff...
-            };
+    type ConvertStreamStream = AudioChunkStream;
 
-            let out_name = format!("{}.{}", stem, out_ext);
+    async fn convert_stream(
+        &self,
+        request: Request<Streaming<ConvertStreamRequest>>,
+    ) -> Result<Response<Self::ConvertStreamStream>, Status> {
+        println!("Starting streaming convert");
+        let upload = receive_upload(request.into_inner()).await?;
+        let req = upload.header;
+        let output_fmt = req.output_format.to_ascii_lowercase();
+        let bitrate = req.bitrate;
 
-            let input_ext = ext_of(&filename);
+        let names = upload.files.iter().map(|(name, _)| name.clone()).collect();
 
-            match crate::utils::conversion::convert_file(data, &output_fmt, req.bitrate, input_ext)
-            {
-                Ok(bytes) => outputs.push((out_name, bytes)),
-                Err(e) => return Err(Status::internal(e)),
-            }
-        }
+        let results = run_batch(upload.files, move |_, (filename, in_path)| {
+            let out_name = converted_name(&filename, &output_fmt);
+            let out_path = output_path()?;
+            convert_path(&in_path, &out_path, &output_fmt, bitrate).map(|_| (out_name, out_path))
+        })
+        .await?;
+        let batch = BatchOutput::collect(names, results);
 
-        println!("Total outputs: {}", outputs.len());
-        if outputs.len() == 1 {
-            let (filename, bytes) = outputs.into_iter().next().unwrap();
-            Ok(Response::new(AudioResponse {
-                file_data: bytes,
-                format: req.output_format,
-                filename,
-            }))
-        } else {
-            println!("Building zip with {} files", outputs.len());
-            match make_zip(outputs) {
-                Ok(zip_bytes) => Ok(Response::new(AudioResponse {
-                    file_data: zip_bytes,
-                    format: "zip".to_string(),
-                    filename: "sonic-tools.zip".to_string(),
-                })),
-                Err(e) => Err(Status::internal(e)),
-            }
-        }
+        send_outputs(batch, Some(req.output_format)).await
     }
 }
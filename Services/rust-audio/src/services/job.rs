@@ -0,0 +1,156 @@
+use crate::audio::{
+    AudioResponse, JobId, JobState, JobStatus, ProgressEvent, SubmitJobRequest,
+    analyze_audio_server::AnalyzeAudio, boost_audio_server::BoostAudio,
+    compress_audio_server::CompressAudio, convert_audio_server::ConvertAudio,
+    job_audio_server::JobAudio, merge_audio_server::MergeAudio,
+    metadata_audio_server::MetadataAudio, pipeline_audio_server::PipelineAudio,
+    submit_job_request::Job, trim_audio_server::TrimAudio,
+};
+use crate::services::analyze::AnalyzeService;
+use crate::services::boost::BoostService;
+use crate::services::compress::CompressService;
+use crate::services::convert::ConvertService;
+use crate::services::merge::MergeService;
+use crate::services::metadata::MetadataService;
+use crate::services::pipeline::PipelineService;
+use crate::services::trim::TrimService;
+use crate::utils::jobs::JobQueue;
+use std::pin::Pin;
+use tokio::sync::mpsc;
+use tokio_stream::Stream;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status};
+
+type ProgressStream = Pin<Box<dyn Stream<Item = Result<ProgressEvent, Status>> + Send>>;
+
+fn is_finished(ev: &ProgressEvent) -> bool {
+    !matches!(
+        ev.state(),
+        JobState::Unspecified | JobState::Queued | JobState::Running
+    )
+}
+
+pub struct JobService {
+    queue: JobQueue,
+}
+
+impl JobService {
+    /// `workers` jobs run at the same time; the rest wait in the queue.
+    pub fn new(workers: usize) -> Self {
+        Self {
+            queue: JobQueue::new(workers),
+        }
+    }
+}
+
+/// Run a submitted job through the same handler its unary RPC uses.
+async fn run(job: Job) -> Result<AudioResponse, Status> {
+    let resp = match job {
+        Job::Convert(r) => ConvertService::default().convert(Request::new(r)).await?,
+        Job::CompressPercentage(r) => {
+            CompressService::default()
+                .compress_percentage(Request::new(r))
+                .await?
+        }
+        Job::CompressSize(r) => {
+            CompressService::default()
+                .compress_size(Request::new(r))
+                .await?
+        }
+        Job::CompressQuality(r) => {
+            CompressService::default()
+                .compress_quality(Request::new(r))
+                .await?
+        }
+        Job::Trim(r) => TrimService::default().trim(Request::new(r)).await?,
+        Job::Merge(r) => MergeService::default().merge(Request::new(r)).await?,
+        Job::Metadata(r) => MetadataService::default().metadata(Request::new(r)).await?,
+        Job::BoostManual(r) => {
+            BoostService::default()
+                .boost_manual(Request::new(r))
+                .await?
+        }
+        Job::BoostNormalize(r) => {
+            BoostService::default()
+                .boost_normalize(Request::new(r))
+                .await?
+        }
+        Job::Pipeline(r) => PipelineService::default().pipeline(Request::new(r)).await?,
+        Job::Waveform(r) => AnalyzeService::default().waveform(Request::new(r)).await?,
+        Job::Spectrogram(r) => {
+            AnalyzeService::default()
+                .spectrogram(Request::new(r))
+                .await?
+        }
+        Job::Replaygain(r) => {
+            MetadataService::default()
+                .replay_gain(Request::new(r))
+                .await?
+        }
+        Job::Split(r) => TrimService::default().split(Request::new(r)).await?,
+        Job::Fade(r) => TrimService::default().fade(Request::new(r)).await?,
+    };
+    Ok(resp.into_inner())
+}
+
+#[tonic::async_trait]
+impl JobAudio for JobService {
+    async fn submit_job(
+        &self,
+        request: Request<SubmitJobRequest>,
+    ) -> Result<Response<JobStatus>, Status> {
+        let job = request
+            .into_inner()
+            .job
+            .ok_or_else(|| Status::invalid_argument("job required"))?;
+
+        let status = self.queue.submit(Box::pin(run(job)))?;
+        println!("Job {} queued", status.job_id);
+        Ok(Response::new(status))
+    }
+
+    async fn get_job_status(&self, request: Request<JobId>) -> Result<Response<JobStatus>, Status> {
+        let id = request.into_inner().job_id;
+        Ok(Response::new(self.queue.status(&id)?))
+    }
+
+    async fn cancel_job(&self, request: Request<JobId>) -> Result<Response<JobStatus>, Status> {
+        let id = request.into_inner().job_id;
+        Ok(Response::new(self.queue.cancel(&id)?))
+    }
+
+    async fn fetch_result(
+        &self,
+        request: Request<JobId>,
+    ) -> Result<Response<AudioResponse>, Status> {
+        let id = request.into_inner().job_id;
+        Ok(Response::new(self.queue.take_result(&id)?))
+    }
+
+    type WatchProgressStream = ProgressStream;
+
+    async fn watch_progress(
+        &self,
+        request: Request<JobId>,
+    ) -> Result<Response<Self::WatchProgressStream>, Status> {
+        let id = request.into_inner().job_id;
+        let mut progress = self.queue.watch(&id)?;
+        let (tx, rx) = mpsc::channel(4);
+
+        tokio::spawn(async move {
+            loop {
+                let ev = progress.borrow_and_update().clone();
+                let done = is_finished(&ev);
+                if tx.send(Ok(ev)).await.is_err() || done {
+                    return;
+                }
+                // Sender dropped means the job was forgotten (fetched or expired).
+                if progress.changed().await.is_err() {
+                    return;
+                }
+            }
+        });
+
+        Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
+    }
+}
@@ -1,7 +1,32 @@
 // src/services/merge.rs
-use crate::audio::{AudioResponse, MergeRequest, merge_audio_server::MergeAudio};
-use crate::utils::merge::merge_sequential;
-use tonic::{Request, Response, Status};
+use crate::audio::{
+    AudioResponse, MergeRequest, MergeStreamRequest, merge_audio_server::MergeAudio,
+};
+use crate::utils::fade::FadeCurve;
+use crate::utils::merge::{Join, MergeOptions, merge_paths, merge_sequential};
+use crate::utils::pool::pool;
+use crate::utils::stream::{AudioChunkStream, output_path, receive_upload, send_file};
+use tonic::{Request, Response, Status, Streaming};
+
+/// Joins and loudness matching from the request.
+fn merge_options(req: &MergeRequest) -> Result<MergeOptions, Status> {
+    let joins = req
+        .joins
+        .iter()
+        .map(|j| {
+            Ok(Join {
+                crossfade_ms: j.crossfade_ms,
+                curve: FadeCurve::parse(&j.curve)?,
+                gap_ms: j.gap_ms,
+            })
+        })
+        .collect::<Result<_, Status>>()?;
+    Ok(MergeOptions {
+        joins,
+        match_loudness: req.match_loudness,
+        target_lufs: req.target_lufs,
+    })
+}
 
 #[derive(Debug, Default)]
 pub struct MergeService {}
@@ -13,6 +38,7 @@ impl MergeAudio for MergeService {
         request: Request<MergeRequest>,
     ) -> Result<Response<AudioResponse>, Status> {
         let req = request.into_inner();
+        let opts = merge_options(&req)?;
 
         if req.file_data.is_empty() {
             return Err(Status::invalid_argument("no files provided"));
@@ -42,13 +68,50 @@ impl MergeAudio for MergeService {
             ));
         }
 
-        match merge_sequential(inputs, &out_fmt) {
+        let fmt = out_fmt.clone();
+        match pool()
+            .run(move || merge_sequential(inputs, &fmt, &opts))
+            .await?
+        {
             Ok(bytes) => Ok(Response::new(AudioResponse {
                 file_data: bytes,
                 format: out_fmt.clone(),
                 filename: format!("merged.{}", out_fmt),
+                results: Vec::new(),
             })),
-            Err(e) => Err(Status::internal(e)),
+            Err(e) => Err(e.into()),
         }
     }
+
+    type MergeStreamStream = AudioChunkStream;
+
+    async fn merge_stream(
+        &self,
+        request: Request<Streaming<MergeStreamRequest>>,
+    ) -> Result<Response<Self::MergeStreamStream>, Status> {
+        let upload = receive_upload(request.into_inner()).await?;
+        let req = upload.header;
+        let opts = merge_options(&req)?;
+
+        let out_fmt = req.output_format.to_lowercase();
+        if out_fmt.is_empty() {
+            return Err(Status::invalid_argument(
+                "output_format required (e.g., mp3, wav, flac, m4a)",
+            ));
+        }
+
+        // Upload order is the filenames order, so the merge order is preserved.
+        let inputs: Vec<_> = upload.files.into_iter().map(|(_, path)| path).collect();
+        let fmt = out_fmt.clone();
+        let out_path = output_path()?;
+        let out_path = pool()
+            .run(move || merge_paths(&inputs, &out_path, &fmt, &opts).map(|_| out_path))
+            .await??;
+
+        Ok(Response::new(send_file(
+            out_path,
+            out_fmt.clone(),
+            format!("merged.{}", out_fmt),
+        )))
+    }
 }
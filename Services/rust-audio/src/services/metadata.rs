@@ -1,6 +1,60 @@
-use crate::audio::{AudioResponse, MetadataRequest, metadata_audio_server::MetadataAudio};
-use crate::utils::metadata::write_metadata;
-use tonic::{Request, Response, Status};
+use crate::audio::{
+    AudioResponse, FileResult, GainReport, MetadataRequest, MetadataStreamRequest,
+    ReplayGainRequest, ReplayGainStreamRequest, metadata_audio_server::MetadataAudio,
+};
+use crate::services::analyze::{loudness_info, replaygain_message};
+use crate::utils::batch::{BatchOutput, FileOutcome, respond, run_batch};
+use crate::utils::error::AudioError;
+use crate::utils::loudness::{Loudness, LoudnessMeter, ReplayGain, meter_path};
+use crate::utils::metadata::{write_metadata, write_metadata_path, write_replaygain_path};
+use crate::utils::pool::pool;
+use crate::utils::stream::{
+    AudioChunkStream, output_path, read_outputs, receive_upload, send_file, send_outputs,
+};
+use crate::utils::temp::spool;
+use tempfile::TempPath;
+use tonic::{Request, Response, Status, Streaming};
+
+type Tagged = (Loudness, ReplayGain);
+
+/// Work out every file's ReplayGain (album values from all measured files)
+/// and write it as tags into a copy of the file.
+async fn tag_replaygain(
+    measured: Vec<FileOutcome<(TempPath, LoudnessMeter)>>,
+    album: bool,
+) -> Result<Vec<FileOutcome<(TempPath, Tagged)>>, Status> {
+    let meters: Vec<&LoudnessMeter> = measured
+        .iter()
+        .filter_map(|outcome| outcome.as_ref().ok())
+        .map(|(_, (_, meter))| meter)
+        .collect();
+    let album = (album && !meters.is_empty()).then(|| LoudnessMeter::combine(&meters));
+
+    run_batch(measured, move |_, outcome| {
+        let (filename, (in_path, meter)) = outcome?;
+        let track = meter.finish();
+        if !track.integrated.is_finite() {
+            return Err(AudioError::InvalidArgument(
+                "file is silent, cannot compute ReplayGain".into(),
+            ));
+        }
+        let rg = ReplayGain::new(&track, album.as_ref().filter(|a| a.integrated.is_finite()));
+        let ext = filename.split('.').next_back().unwrap_or("mp3").to_string();
+        let out_path = output_path()?;
+        write_replaygain_path(&in_path, &out_path, &ext, &rg)
+            .map(|_| (filename, (out_path, (track, rg))))
+    })
+    .await
+}
+
+fn attach_replaygain(result: &mut FileResult, (track, rg): Tagged) {
+    result.gain = Some(GainReport {
+        before: Some(loudness_info(String::new(), &track)),
+        mode: "tags".to_string(),
+        replaygain: Some(replaygain_message(&rg)),
+        ..Default::default()
+    });
+}
 
 #[derive(Debug, Default)]
 pub struct MetadataService {}
@@ -15,23 +69,117 @@ impl MetadataAudio for MetadataService {
 
         let req = request.into_inner();
         let filename = req.filename;
-        let ext = filename.split('.').last().unwrap_or("mp3");
-
-        match write_metadata(
-            req.file_data,
-            ext,
-            req.title,
-            req.artist,
-            req.album,
-            req.year,
-            req.cover_art,
-        ) {
+        let ext = filename.split('.').next_back().unwrap_or("mp3").to_string();
+
+        let fmt = ext.clone();
+        let result = pool()
+            .run(move || {
+                write_metadata(
+                    req.file_data,
+                    &fmt,
+                    req.title,
+                    req.artist,
+                    req.album,
+                    req.year,
+                    req.cover_art,
+                )
+            })
+            .await?;
+
+        match result {
             Ok(bytes) => Ok(Response::new(AudioResponse {
                 file_data: bytes,
-                format: ext.to_string(),
+                format: ext,
                 filename,
+                results: Vec::new(),
             })),
-            Err(e) => Err(Status::internal(e)),
+            Err(e) => Err(e.into()),
         }
     }
+
+    type MetadataStreamStream = AudioChunkStream;
+
+    async fn metadata_stream(
+        &self,
+        request: Request<Streaming<MetadataStreamRequest>>,
+    ) -> Result<Response<Self::MetadataStreamStream>, Status> {
+        println!("Starting streaming metadata write");
+
+        let mut upload = receive_upload(request.into_inner()).await?;
+        let req = upload.header;
+        let (filename, in_path) = upload.files.remove(0);
+        let ext = filename.split('.').next_back().unwrap_or("mp3").to_string();
+
+        let fmt = ext.clone();
+        let out_path = output_path()?;
+        let out_path = pool()
+            .run(move || {
+                write_metadata_path(
+                    &in_path,
+                    &out_path,
+                    &fmt,
+                    req.title,
+                    req.artist,
+                    req.album,
+                    req.year,
+                    req.cover_art,
+                )
+                .map(|_| out_path)
+            })
+            .await??;
+
+        Ok(Response::new(send_file(out_path, ext, filename)))
+    }
+
+    async fn replay_gain(
+        &self,
+        request: Request<ReplayGainRequest>,
+    ) -> Result<Response<AudioResponse>, Status> {
+        let req = request.into_inner();
+        let items: Vec<(String, Vec<u8>)> = req
+            .file_data
+            .into_iter()
+            .enumerate()
+            .map(|(i, data)| {
+                (
+                    req.filenames.get(i).cloned().unwrap_or("output".into()),
+                    data,
+                )
+            })
+            .collect();
+        let names = items.iter().map(|(name, _)| name.clone()).collect();
+
+        let measured = run_batch(items, move |_, (filename, data)| {
+            let ext = filename.split('.').next_back().unwrap_or("mp3").to_string();
+            let in_path = spool(&data, &ext)?;
+            meter_path(&in_path).map(|meter| (filename, (in_path, meter)))
+        })
+        .await?;
+        let results = tag_replaygain(measured, req.album).await?;
+
+        let outcomes = read_outputs(results).await;
+        let batch = BatchOutput::collect(names, outcomes).with_reports(attach_replaygain);
+
+        respond(batch, None).await
+    }
+
+    type ReplayGainStreamStream = AudioChunkStream;
+
+    async fn replay_gain_stream(
+        &self,
+        request: Request<Streaming<ReplayGainStreamRequest>>,
+    ) -> Result<Response<Self::ReplayGainStreamStream>, Status> {
+        let upload = receive_upload(request.into_inner()).await?;
+        let album = upload.header.album;
+        let names = upload.files.iter().map(|(name, _)| name.clone()).collect();
+
+        let measured = run_batch(upload.files, move |_, (filename, in_path)| {
+            meter_path(&in_path).map(|meter| (filename, (in_path, meter)))
+        })
+        .await?;
+        let results = tag_replaygain(measured, album).await?;
+        let batch = BatchOutput::collect(names, results).with_reports(attach_replaygain);
+
+        send_outputs(batch, None).await
+    }
 }
@@ -1,6 +1,9 @@
+pub mod analyze;
 pub mod boost;
 pub mod compress;
 pub mod convert;
+pub mod job;
 pub mod merge;
 pub mod metadata;
+pub mod pipeline;
 pub mod trim;
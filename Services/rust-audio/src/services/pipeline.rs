@@ -0,0 +1,139 @@
+use crate::audio::{
+    AudioResponse, PipelineRequest, PipelineStreamRequest, pipeline_audio_server::PipelineAudio,
+    pipeline_step,
+};
+use crate::services::trim::trim_ranges;
+use crate::utils::boost::LoudnessTarget;
+use crate::utils::fade::FadeCurve;
+use crate::utils::ffmpeg_job::plan_for;
+use crate::utils::pipeline::{Step, Tags, output_format, pipeline_file, pipeline_path};
+use crate::utils::pool::pool;
+use crate::utils::stream::{AudioChunkStream, output_path, receive_upload, send_file};
+use std::path::Path;
+use tonic::{Request, Response, Status, Streaming};
+
+/// Turn the proto steps into pipeline steps, rejecting empty ones.
+fn steps_of(req: &PipelineRequest) -> Result<Vec<Step>, Status> {
+    if req.steps.is_empty() {
+        return Err(Status::invalid_argument("at least one step required"));
+    }
+
+    req.steps
+        .iter()
+        .enumerate()
+        .map(|(i, step)| {
+            let step = step
+                .step
+                .clone()
+                .ok_or_else(|| Status::invalid_argument(format!("step {} is empty", i)))?;
+            Ok(match step {
+                pipeline_step::Step::Trim(t) => Step::Trim {
+                    ranges: trim_ranges(
+                        (t.start.as_ref(), t.start_s),
+                        (t.end.as_ref(), t.end_s),
+                        &t.ranges,
+                    )?,
+                    action: if t.action.is_empty() {
+                        "keep".to_string()
+                    } else {
+                        t.action
+                    },
+                },
+                pipeline_step::Step::Boost(b) => Step::Gain(b.gain),
+                pipeline_step::Step::Normalize(n) => Step::Normalize(LoudnessTarget::resolve(
+                    &n.preset,
+                    n.target_i,
+                    n.target_tp,
+                    n.target_lra,
+                )?),
+                pipeline_step::Step::Convert(c) => Step::Convert {
+                    output_format: c.output_format,
+                    bitrate: c.bitrate,
+                },
+                pipeline_step::Step::Metadata(m) => Step::Metadata(Tags {
+                    title: m.title,
+                    artist: m.artist,
+                    album: m.album,
+                    year: m.year,
+                    cover_art: m.cover_art,
+                }),
+                pipeline_step::Step::Fade(f) => Step::Fade {
+                    in_ms: f.fade_in_ms,
+                    out_ms: f.fade_out_ms,
+                    curve: FadeCurve::parse(&f.curve)?,
+                },
+            })
+        })
+        .collect()
+}
+
+/// Output filename and format: the input stem with the extension of the
+/// format the pipeline ends up encoding to.
+fn output_name(filename: &str, steps: &[Step]) -> Result<(String, String), Status> {
+    let ext = filename.split('.').next_back().unwrap_or("mp3");
+    let plan = plan_for(&output_format(ext, steps))?;
+    let stem = Path::new(filename)
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("output");
+    Ok((
+        format!("{}.{}", stem, plan.out_ext),
+        plan.out_ext.to_string(),
+    ))
+}
+
+#[derive(Debug, Default)]
+pub struct PipelineService {}
+
+#[tonic::async_trait]
+impl PipelineAudio for PipelineService {
+    async fn pipeline(
+        &self,
+        request: Request<PipelineRequest>,
+    ) -> Result<Response<AudioResponse>, Status> {
+        println!("Pipeline request received");
+
+        let req = request.into_inner();
+        let steps = steps_of(&req)?;
+        let (out_name, format) = output_name(&req.filename, &steps)?;
+        let ext = req
+            .filename
+            .split('.')
+            .next_back()
+            .unwrap_or("mp3")
+            .to_string();
+
+        let bytes = pool()
+            .run(move || pipeline_file(req.file_data, &ext, &steps))
+            .await??;
+
+        Ok(Response::new(AudioResponse {
+            file_data: bytes,
+            format,
+            filename: out_name,
+            results: Vec::new(),
+        }))
+    }
+
+    type PipelineStreamStream = AudioChunkStream;
+
+    async fn pipeline_stream(
+        &self,
+        request: Request<Streaming<PipelineStreamRequest>>,
+    ) -> Result<Response<Self::PipelineStreamStream>, Status> {
+        println!("Streaming pipeline request received");
+
+        let mut upload = receive_upload(request.into_inner()).await?;
+        let steps = steps_of(&upload.header)?;
+        let (filename, in_path) = upload.files.remove(0);
+        let (out_name, format) = output_name(&filename, &steps)?;
+        let ext = filename.split('.').next_back().unwrap_or("mp3").to_string();
+
+        let out_path = output_path()?;
+        let out_path = pool()
+            .run(move || pipeline_path(&in_path, &out_path, &ext, &steps).map(|_| out_path))
+            .await??;
+
+        Ok(Response::new(send_file(out_path, format, out_name)))
+    }
+}
@@ -1,6 +1,205 @@
-use crate::audio::{AudioResponse, TrimRequest, trim_audio_server::TrimAudio};
-use crate::utils::trim::trim_file;
-use tonic::{Request, Response, Status};
+use crate::audio::{
+    AudioResponse, FadeOptions, FadeRequest, FadeStreamRequest, SplitRequest, SplitStreamRequest,
+    TrimPosition, TrimRange, TrimRequest, TrimStreamRequest, split_request,
+    trim_audio_server::TrimAudio, trim_position,
+};
+use crate::utils::fade::{FadeCurve, Fades, fade_file, fade_path};
+use crate::utils::pool::pool;
+use crate::utils::silence::SilenceOptions;
+use crate::utils::split::{Naming, SplitMode, split_file, split_path};
+use crate::utils::stream::{AudioChunkStream, output_path, receive_upload, send_file};
+use crate::utils::trim::{
+    Position, Range, strip_silence_file, strip_silence_path, trim_file, trim_path,
+};
+use crate::utils::zip::{make_zip, make_zip_path};
+use std::path::Path;
+use tonic::{Request, Response, Status, Streaming};
+
+/// Fill in the default action and reject inverted keep ranges, or ranges
+/// given with strip_silence.
+fn validate(req: &TrimRequest) -> Result<String, Status> {
+    // Default action
+    let action = if req.action.is_empty() {
+        "keep".to_string()
+    } else {
+        req.action.clone()
+    };
+
+    // strip_silence finds its own ranges
+    if action == "strip_silence"
+        && (req.start_s.is_some()
+            || req.end_s.is_some()
+            || req.start.is_some()
+            || req.end.is_some()
+            || !req.ranges.is_empty())
+    {
+        return Err(Status::invalid_argument(
+            "start, end and ranges do not apply to strip_silence",
+        ));
+    }
+
+    // Validate ranges
+    if action == "keep"
+        && let (Some(start), Some(end)) = (req.start_s, req.end_s)
+        && start >= end
+    {
+        return Err(Status::invalid_argument("start must be < end for keep"));
+    }
+
+    Ok(action)
+}
+
+/// One cut position from either the precise field or the legacy whole seconds.
+fn position(
+    precise: Option<&TrimPosition>,
+    seconds: Option<i32>,
+    name: &str,
+) -> Result<Option<Position>, Status> {
+    let at = match (precise.and_then(|p| p.at.as_ref()), seconds) {
+        (Some(_), Some(_)) => {
+            return Err(Status::invalid_argument(format!(
+                "set either {} or {}_s, not both",
+                name, name
+            )));
+        }
+        (Some(at), None) => at,
+        (None, None) => return Ok(None),
+        (None, Some(seconds)) => return Ok(Some(Position::seconds(seconds.max(0) as u64)?)),
+    };
+    Ok(Some(match at {
+        trim_position::At::Ms(ms) => Position::millis(*ms)?,
+        trim_position::At::Sample(frame) => Position::Frame(*frame),
+        trim_position::At::Timecode(text) => Position::timecode(text)?,
+    }))
+}
+
+fn ranges(req: &TrimRequest) -> Result<Vec<Range>, Status> {
+    trim_ranges(
+        (req.start.as_ref(), req.start_s),
+        (req.end.as_ref(), req.end_s),
+        &req.ranges,
+    )
+}
+
+/// The ranges to keep or remove: the `list`, or the single range given by
+/// start and end (each a precise position or whole seconds). Also used by
+/// the pipeline's trim step.
+pub(crate) fn trim_ranges(
+    start: (Option<&TrimPosition>, Option<i32>),
+    end: (Option<&TrimPosition>, Option<i32>),
+    list: &[TrimRange],
+) -> Result<Vec<Range>, Status> {
+    if list.is_empty() {
+        return Ok(vec![Range {
+            start: position(start.0, start.1, "start")?,
+            end: position(end.0, end.1, "end")?,
+        }]);
+    }
+
+    if start.0.is_some() || end.0.is_some() || start.1.is_some() || end.1.is_some() {
+        return Err(Status::invalid_argument(
+            "set either ranges or start/end, not both",
+        ));
+    }
+    list.iter()
+        .map(|r| {
+            Ok(Range {
+                start: position(r.start.as_ref(), None, "start")?,
+                end: position(r.end.as_ref(), None, "end")?,
+            })
+        })
+        .collect()
+}
+
+/// Silence settings for "strip_silence".
+fn silence_options(req: &TrimRequest) -> Result<SilenceOptions, Status> {
+    Ok(SilenceOptions::new(
+        req.silence_threshold_db,
+        req.min_silence_ms,
+    )?)
+}
+
+/// Edge fades of a trim or split; micro-fades are on unless turned off.
+fn fades(opts: Option<&FadeOptions>) -> Result<Fades, Status> {
+    let Some(opts) = opts else {
+        return Ok(Fades::default());
+    };
+    Ok(Fades {
+        in_ms: opts.fade_in_ms,
+        out_ms: opts.fade_out_ms,
+        curve: FadeCurve::parse(&opts.curve)?,
+        micro: opts.micro_fades.unwrap_or(true),
+    })
+}
+
+/// Fades of a standalone fade; there are no cuts to smooth.
+fn whole_file_fades(req: &FadeRequest) -> Result<Fades, Status> {
+    Ok(Fades {
+        in_ms: req.fade_in_ms,
+        out_ms: req.fade_out_ms,
+        curve: FadeCurve::parse(&req.curve)?,
+        micro: false,
+    })
+}
+
+/// Split settings: how to cut, how to name the parts and their format.
+struct SplitOptions {
+    mode: SplitMode,
+    naming: Naming,
+    output_format: String,
+    stem: String,
+    fades: Fades,
+}
+
+fn split_options(req: &SplitRequest, filename: &str) -> Result<SplitOptions, Status> {
+    let mode = match &req.mode {
+        Some(split_request::Mode::Points(points)) => {
+            if points.at.is_empty() {
+                return Err(Status::invalid_argument("no cut points given"));
+            }
+            let points = points
+                .at
+                .iter()
+                .map(|p| {
+                    position(Some(p), None, "point")?
+                        .ok_or_else(|| Status::invalid_argument("empty cut point"))
+                })
+                .collect::<Result<_, Status>>()?;
+            SplitMode::Points(points)
+        }
+        Some(split_request::Mode::SegmentMs(ms)) => SplitMode::Length(*ms),
+        Some(split_request::Mode::Silence(silence)) => SplitMode::Silence(SilenceOptions::new(
+            silence.threshold_db,
+            silence.min_silence_ms,
+        )?),
+        Some(split_request::Mode::Chapters(true)) => SplitMode::Chapters,
+        Some(split_request::Mode::Chapters(false)) | None => {
+            return Err(Status::invalid_argument(
+                "choose how to split: points, segment_ms, silence or chapters",
+            ));
+        }
+    };
+
+    let path = Path::new(filename);
+    let ext = path.extension().and_then(|e| e.to_str()).unwrap_or("mp3");
+    let output_format = if req.output_format.is_empty() {
+        ext.to_string()
+    } else {
+        req.output_format.to_ascii_lowercase()
+    };
+
+    Ok(SplitOptions {
+        mode,
+        naming: Naming::parse(&req.naming)?,
+        output_format,
+        stem: path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or("output")
+            .to_string(),
+        fades: fades(req.fade.as_ref())?,
+    })
+}
 
 #[derive(Debug, Default)]
 pub struct TrimService {}
@@ -13,37 +212,218 @@ impl TrimAudio for TrimService {
         let req = request.into_inner();
 
         let filename = req.filename.clone(); // ideally rename to filename in proto
-        let ext = filename.split('.').last().unwrap_or("mp3");
+        let ext = filename.split('.').next_back().unwrap_or("mp3").to_string();
 
-        // Default action
-        let action = if req.action.is_empty() {
-            "keep".to_string()
-        } else {
-            req.action
+        let action = validate(&req)?;
+
+        // Run ffmpeg trim
+        let silence = silence_options(&req)?;
+        let ranges = ranges(&req)?;
+        let fades = fades(req.fade.as_ref())?;
+        let fmt = ext.clone();
+        let result = pool()
+            .run(move || {
+                if action == "strip_silence" {
+                    strip_silence_file(req.file_data, &fmt, &silence, req.max_gap_ms, fades)
+                } else {
+                    trim_file(req.file_data, &fmt, &ranges, &action, fades)
+                }
+            })
+            .await?;
+        let trimmed = match result {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                eprintln!("Trim error: {}", e);
+                return Err(e.into());
+            }
         };
 
-        // Validate ranges
-        if action == "keep" {
-            if let (Some(start), Some(end)) = (req.start_s, req.end_s) {
-                if start >= end {
-                    return Err(Status::invalid_argument("start must be < end for keep"));
+        Ok(Response::new(AudioResponse {
+            file_data: trimmed,
+            format: ext,
+            filename,
+            results: Vec::new(),
+        }))
+    }
+
+    type TrimStreamStream = AudioChunkStream;
+
+    async fn trim_stream(
+        &self,
+        request: Request<Streaming<TrimStreamRequest>>,
+    ) -> Result<Response<Self::TrimStreamStream>, Status> {
+        println!("Streaming trim request received");
+
+        let mut upload = receive_upload(request.into_inner()).await?;
+        let req = upload.header;
+        let action = validate(&req)?;
+        let silence = silence_options(&req)?;
+        let ranges = ranges(&req)?;
+        let fades = fades(req.fade.as_ref())?;
+        let (filename, in_path) = upload.files.remove(0);
+        let ext = filename.split('.').next_back().unwrap_or("mp3").to_string();
+
+        let fmt = ext.clone();
+        let out_path = output_path()?;
+        let result = pool()
+            .run(move || {
+                if action == "strip_silence" {
+                    strip_silence_path(&in_path, &out_path, &fmt, &silence, req.max_gap_ms, fades)
+                } else {
+                    trim_path(&in_path, &out_path, &fmt, &ranges, &action, fades)
                 }
+                .map(|_| out_path)
+            })
+            .await?;
+        let out_path = match result {
+            Ok(path) => path,
+            Err(e) => {
+                eprintln!("Trim error: {}", e);
+                return Err(e.into());
             }
-        }
+        };
 
-        // Run ffmpeg trim
-        let trimmed = match trim_file(req.file_data, ext, req.start_s, req.end_s, &action) {
+        Ok(Response::new(send_file(out_path, ext, filename)))
+    }
+
+    async fn split(
+        &self,
+        request: Request<SplitRequest>,
+    ) -> Result<Response<AudioResponse>, Status> {
+        println!("Split request received");
+
+        let req = request.into_inner();
+        let opts = split_options(&req, &req.filename)?;
+        let ext = req
+            .filename
+            .split('.')
+            .next_back()
+            .unwrap_or("mp3")
+            .to_string();
+        let filename = format!("{}.zip", opts.stem);
+
+        let result = pool()
+            .run(move || {
+                let parts = split_file(
+                    req.file_data,
+                    &ext,
+                    &opts.output_format,
+                    &opts.mode,
+                    &opts.naming,
+                    &opts.stem,
+                    opts.fades,
+                )?;
+                make_zip(parts)
+            })
+            .await?;
+        let zipped = match result {
             Ok(bytes) => bytes,
             Err(e) => {
-                eprintln!("Trim error: {}", e);
-                return Err(Status::internal(format!("Trim failed: {}", e)));
+                eprintln!("Split error: {}", e);
+                return Err(e.into());
             }
         };
 
         Ok(Response::new(AudioResponse {
-            file_data: trimmed,
-            format: ext.to_string(),
+            file_data: zipped,
+            format: "zip".to_string(),
             filename,
+            results: Vec::new(),
         }))
     }
+
+    type SplitStreamStream = AudioChunkStream;
+
+    async fn split_stream(
+        &self,
+        request: Request<Streaming<SplitStreamRequest>>,
+    ) -> Result<Response<Self::SplitStreamStream>, Status> {
+        println!("Streaming split request received");
+
+        let mut upload = receive_upload(request.into_inner()).await?;
+        let (filename, in_path) = upload.files.remove(0);
+        let opts = split_options(&upload.header, &filename)?;
+        let name = format!("{}.zip", opts.stem);
+
+        let zip_path = output_path()?;
+        let result = pool()
+            .run(move || {
+                let parts = split_path(
+                    &in_path,
+                    &opts.output_format,
+                    &opts.mode,
+                    &opts.naming,
+                    &opts.stem,
+                    opts.fades,
+                )?;
+                make_zip_path(&parts, &zip_path).map(|_| zip_path)
+            })
+            .await?;
+        let zip_path = match result {
+            Ok(path) => path,
+            Err(e) => {
+                eprintln!("Split error: {}", e);
+                return Err(e.into());
+            }
+        };
+
+        Ok(Response::new(send_file(zip_path, "zip".to_string(), name)))
+    }
+
+    async fn fade(&self, request: Request<FadeRequest>) -> Result<Response<AudioResponse>, Status> {
+        println!("Fade request received");
+
+        let req = request.into_inner();
+        let fades = whole_file_fades(&req)?;
+        let filename = req.filename.clone();
+        let ext = filename.split('.').next_back().unwrap_or("mp3").to_string();
+
+        let fmt = ext.clone();
+        let result = pool()
+            .run(move || fade_file(req.file_data, &fmt, fades))
+            .await?;
+        let faded = match result {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                eprintln!("Fade error: {}", e);
+                return Err(e.into());
+            }
+        };
+
+        Ok(Response::new(AudioResponse {
+            file_data: faded,
+            format: ext,
+            filename,
+            results: Vec::new(),
+        }))
+    }
+
+    type FadeStreamStream = AudioChunkStream;
+
+    async fn fade_stream(
+        &self,
+        request: Request<Streaming<FadeStreamRequest>>,
+    ) -> Result<Response<Self::FadeStreamStream>, Status> {
+        println!("Streaming fade request received");
+
+        let mut upload = receive_upload(request.into_inner()).await?;
+        let fades = whole_file_fades(&upload.header)?;
+        let (filename, in_path) = upload.files.remove(0);
+        let ext = filename.split('.').next_back().unwrap_or("mp3").to_string();
+
+        let fmt = ext.clone();
+        let out_path = output_path()?;
+        let result = pool()
+            .run(move || fade_path(&in_path, &out_path, &fmt, fades).map(|_| out_path))
+            .await?;
+        let out_path = match result {
+            Ok(path) => path,
+            Err(e) => {
+                eprintln!("Fade error: {}", e);
+                return Err(e.into());
+            }
+        };
+
+        Ok(Response::new(send_file(out_path, ext, filename)))
+    }
 }
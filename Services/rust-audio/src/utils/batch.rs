@@ -0,0 +1,295 @@
+use crate::audio::{AudioResponse, FileResult};
+use crate::utils::error::AudioError;
+use crate::utils::pool::pool;
+use crate::utils::process::{self, with_job};
+use crate::utils::zip::{make_zip, unique_name};
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tonic::{Code, Response, Status};
+
+/// Name of the archive entry listing the files that failed.
+pub const ERRORS_FILE: &str = "errors.txt";
+
+/// Result of processing one batch file: output name and data, or the error.
+pub type FileOutcome<T> = Result<(String, T), AudioError>;
+
+static LIMIT: OnceLock<usize> = OnceLock::new();
+
+/// Configure how many files of one batch request are processed at the same
+/// time. Must be called before the first batch runs; later calls are ignored.
+pub fn init(limit: usize) {
+    let _ = LIMIT.set(limit.max(1));
+}
+
+/// Per-request concurrency; defaults to 4. The shared pool still caps the
+/// total number of ffmpeg runs across all requests.
+pub fn limit() -> usize {
+    *LIMIT.get_or_init(|| 4)
+}
+
+/// Run `f` on every item of a batch on the processing pool, at most `limit()`
+/// at once, and return the outcomes in input order. A file the pool turns
+/// away fails on its own; only a cancelled job aborts the whole batch.
+pub async fn run_batch<I, T, F>(items: Vec<I>, f: F) -> Result<Vec<FileOutcome<T>>, Status>
+where
+    I: Send + 'static,
+    T: Send + 'static,
+    F: Fn(usize, I) -> FileOutcome<T> + Send + Sync + 'static,
+{
+    let count = items.len();
+    let slots = Arc::new(Semaphore::new(limit()));
+    let f = Arc::new(f);
+    let ctx = process::current();
+    let mut tasks = JoinSet::new();
+
+    for (i, item) in items.into_iter().enumerate() {
+        let slot = slots
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(|_| Status::internal("batch limiter closed"))?;
+        let f = f.clone();
+        let ctx = ctx.as_ref().map(|c| c.for_file(i));
+
+        tasks.spawn(async move {
+            let _slot = slot;
+            let work = pool().run(move || f(i, item));
+            // Spawned tasks do not inherit the job context; hand it over explicitly.
+            let result = match ctx {
+                Some(ctx) => {
+                    ctx.progress.begin_file(i, count);
+                    with_job(ctx, work).await
+                }
+                None => work.await,
+            };
+            (i, result)
+        });
+    }
+
+    let mut results: Vec<Option<FileOutcome<T>>> = (0..count).map(|_| None).collect();
+    while let Some(joined) = tasks.join_next().await {
+        let (i, result) =
+            joined.map_err(|e| Status::internal(format!("batch task failed: {}", e)))?;
+        let outcome = result.unwrap_or_else(|status| Err(pool_error(status)));
+        if matches!(outcome, Err(AudioError::Cancelled)) {
+            return Err(AudioError::Cancelled.into());
+        }
+        results[i] = Some(outcome);
+    }
+
+    Ok(results.into_iter().flatten().collect())
+}
+
+/// A pool rejection as the error of the one file it hit.
+fn pool_error(status: Status) -> AudioError {
+    match status.code() {
+        Code::ResourceExhausted => AudioError::Busy(status.message().to_string()),
+        Code::Cancelled => AudioError::Cancelled,
+        _ => AudioError::ProcessingFailed {
+            message: status.message().to_string(),
+            stderr: String::new(),
+        },
+    }
+}
+
+/// Successful outputs of a batch, in input order, plus the outcome of every
+/// input file.
+pub struct BatchOutput<T> {
+    pub outputs: Vec<(String, T)>,
+    pub results: Vec<FileResult>,
+    errors: Vec<AudioError>,
+}
+
+impl<T> BatchOutput<T> {
+    /// Pair each input filename with its outcome. Output names are made unique
+    /// here so `results` matches the zip entries.
+    pub fn collect(inputs: Vec<String>, outcomes: Vec<FileOutcome<T>>) -> Self {
+        let mut seen = HashMap::new();
+        let mut outputs = Vec::new();
+        let mut results = Vec::new();
+        let mut errors = Vec::new();
+
+        for (filename, outcome) in inputs.into_iter().zip(outcomes) {
+            match outcome {
+                Ok((name, data)) => {
+                    let output_name = unique_name(&mut seen, &name);
+                    results.push(FileResult {
+                        filename,
+                        ok: true,
+                        output_name: output_name.clone(),
+                        error: String::new(),
+                        gain: None,
+                    });
+                    outputs.push((output_name, data));
+                }
+                Err(error) => {
+                    println!("{} failed: {}", filename, error);
+                    results.push(FileResult {
+                        filename,
+                        ok: false,
+                        output_name: String::new(),
+                        error: error.to_string(),
+                        gain: None,
+                    });
+                    errors.push(error);
+                }
+            }
+        }
+
+        Self {
+            outputs,
+            results,
+            errors,
+        }
+    }
+
+    pub fn has_failures(&self) -> bool {
+        self.results.iter().any(|r| !r.ok)
+    }
+
+    /// Contents of `errors.txt`: one `filename: error` line per failed file.
+    pub fn error_report(&self) -> String {
+        self.results
+            .iter()
+            .filter(|r| !r.ok)
+            .map(|r| format!("{}: {}\n", r.filename, r.error))
+            .collect()
+    }
+
+    /// Error for a batch in which no file succeeded. A single file keeps its
+    /// own error; several keep the code only if they all agree on it.
+    pub fn failure(mut self) -> Status {
+        if self.errors.len() == 1 {
+            return self.errors.remove(0).into();
+        }
+        let code = self.errors[0].code();
+        let code = if self.errors.iter().all(|e| e.code() == code) {
+            code
+        } else {
+            Code::Internal
+        };
+        Status::new(
+            code,
+            format!(
+                "all {} files failed: {}",
+                self.errors.len(),
+                self.error_report().trim_end().replace('\n', "; ")
+            ),
+        )
+    }
+}
+
+impl<T, R> BatchOutput<(T, R)> {
+    /// Move the per-file report carried next to each output into that file's
+    /// `FileResult`, leaving plain outputs for `respond`/`send_outputs`.
+    pub fn with_reports(self, attach: impl Fn(&mut FileResult, R)) -> BatchOutput<T> {
+        let Self {
+            outputs,
+            mut results,
+            errors,
+        } = self;
+        let mut ok = results.iter_mut().filter(|r| r.ok);
+        let outputs = outputs
+            .into_iter()
+            .map(|(name, (data, report))| {
+                if let Some(result) = ok.next() {
+                    attach(result, report);
+                }
+                (name, data)
+            })
+            .collect();
+
+        BatchOutput {
+            outputs,
+            results,
+            errors,
+        }
+    }
+}
+
+/// Build the unary response of a batch: the file itself when there is exactly
+/// one output and nothing failed, otherwise a zip of every output plus
+/// `errors.txt` when some files failed. Fails only when every file failed.
+pub async fn respond(
+    batch: BatchOutput<Vec<u8>>,
+    format: Option<String>,
+) -> Result<Response<AudioResponse>, Status> {
+    if batch.outputs.is_empty() && batch.has_failures() {
+        return Err(batch.failure());
+    }
+
+    if batch.outputs.len() == 1 && !batch.has_failures() {
+        let BatchOutput {
+            outputs, results, ..
+        } = batch;
+        let (filename, bytes) = outputs.into_iter().next().unwrap();
+        let format =
+            format.unwrap_or_else(|| filename.split('.').next_back().unwrap_or("mp3").to_string());
+        return Ok(Response::new(AudioResponse {
+            file_data: bytes,
+            format,
+            filename,
+            results,
+        }));
+    }
+
+    let report = batch.has_failures().then(|| batch.error_report());
+    let BatchOutput {
+        mut outputs,
+        results,
+        ..
+    } = batch;
+    if let Some(report) = report {
+        outputs.push((ERRORS_FILE.to_string(), report.into_bytes()));
+    }
+
+    println!("Building zip with {} files", outputs.len());
+    match pool().run(move || make_zip(outputs)).await? {
+        Ok(zip_bytes) => Ok(Response::new(AudioResponse {
+            file_data: zip_bytes,
+            format: "zip".to_string(),
+            filename: "sonic-tools.zip".to_string(),
+            results,
+        })),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn failed_file_does_not_abort_batch() {
+        let outcomes = run_batch(vec![0, 1, 2], |_, n| {
+            if n == 1 {
+                Err(pool_error(Status::resource_exhausted("server is busy")))
+            } else {
+                Ok((n.to_string(), n))
+            }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(outcomes.len(), 3);
+        assert!(matches!(outcomes[1], Err(AudioError::Busy(_))));
+        assert_eq!(outcomes[2].as_ref().unwrap().1, 2);
+    }
+
+    #[tokio::test]
+    async fn cancelled_file_aborts_batch() {
+        let status = run_batch(vec![0, 1], |_, n| {
+            if n == 0 {
+                Err(AudioError::Cancelled)
+            } else {
+                Ok((n.to_string(), n))
+            }
+        })
+        .await
+        .unwrap_err();
+
+        assert_eq!(status.code(), Code::Cancelled);
+    }
+}
@@ -1,107 +1,499 @@
+use crate::utils::error::{AudioError, AudioResult};
+use crate::utils::ffmpeg_job::FfmpegJob;
+use crate::utils::limiter::Limiter;
+use crate::utils::loudness::{Loudness, LoudnessMeter, ReplayGain, meter_path};
+use crate::utils::native::{Decoder, PcmWriter};
 use std::fs;
-use std::process::Command;
+use std::path::Path;
 use tempfile::NamedTempFile;
 
-struct EncodePlan {
-    muxer: &'static str,
-    pre_f_args: &'static [&'static str], // placed before -f (codec/flags)
-}
-
-fn plan_for(fmt: &str) -> Result<EncodePlan, String> {
-    match fmt.to_ascii_lowercase().as_str() {
-        // Lossy
-        "mp3" => Ok(EncodePlan {
-            muxer: "mp3",
-            pre_f_args: &["-c:a", "libmp3lame"],
-        }),
-        "ogg" => Ok(EncodePlan {
-            muxer: "ogg",
-            pre_f_args: &["-c:a", "libvorbis"],
-        }),
-        "opus" => Ok(EncodePlan {
-            muxer: "ogg",
-            pre_f_args: &["-c:a", "libopus"],
-        }),
-        "aac" => Ok(EncodePlan {
-            muxer: "adts",
-            pre_f_args: &["-c:a", "aac"],
-        }),
-        "m4a" => Ok(EncodePlan {
-            muxer: "mp4",
-            pre_f_args: &["-c:a", "aac", "-movflags", "+faststart"],
-        }),
-        "wma" => Ok(EncodePlan {
-            muxer: "asf",
-            pre_f_args: &["-c:a", "wmav2", "-ar", "44100", "-ac", "2"],
-        }),
-
-        // Lossless / PCM
-        "wav" => Ok(EncodePlan {
-            muxer: "wav",
-            pre_f_args: &["-c:a", "pcm_s16le"],
-        }),
-        "flac" => Ok(EncodePlan {
-            muxer: "flac",
-            pre_f_args: &["-c:a", "flac"],
-        }),
-        "aiff" | "aif" => Ok(EncodePlan {
-            muxer: "aiff",
-            pre_f_args: &["-c:a", "pcm_s16be"],
-        }),
-
-        other => Err(format!("Unsupported output format: {}", other)),
+pub(crate) fn gain_filter(gain: i32) -> String {
+    // Positive gain boosts, negative attenuates (e.g., -3 dB)
+    format!("volume={}dB", gain)
+}
+
+/// Loudness a normalization aims for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LoudnessTarget {
+    /// Integrated loudness, LUFS.
+    pub integrated: f64,
+    /// Maximum true peak, dBTP.
+    pub true_peak: f64,
+    /// Maximum loudness range, LU.
+    pub range: f64,
+}
+
+impl Default for LoudnessTarget {
+    fn default() -> Self {
+        Self {
+            integrated: -16.0,
+            true_peak: -1.5,
+            range: 11.0,
+        }
+    }
+}
+
+impl LoudnessTarget {
+    /// Named platform preset with optional per-value overrides; `custom`
+    /// needs at least `integrated`.
+    pub fn resolve(
+        name: &str,
+        integrated: Option<f64>,
+        true_peak: Option<f64>,
+        range: Option<f64>,
+    ) -> AudioResult<Self> {
+        let preset = |integrated, true_peak| Self {
+            integrated,
+            true_peak,
+            range: 11.0,
+        };
+        let base = match name.trim().to_ascii_lowercase().as_str() {
+            "" => Self::default(),
+            "spotify" | "youtube" => preset(-14.0, -1.0),
+            "apple" => preset(-16.0, -1.0),
+            "ebu" => preset(-23.0, -1.0),
+            "atsc" => preset(-24.0, -2.0),
+            "custom" if integrated.is_some() => Self::default(),
+            "custom" => {
+                return Err(AudioError::InvalidArgument(
+                    "custom preset requires target_i".into(),
+                ));
+            }
+            other => {
+                return Err(AudioError::InvalidArgument(format!(
+                    "Invalid preset '{}' (must be spotify, apple, youtube, ebu, atsc or custom)",
+                    other
+                )));
+            }
+        };
+        let target = Self {
+            integrated: integrated.unwrap_or(base.integrated),
+            true_peak: true_peak.unwrap_or(base.true_peak),
+            range: range.unwrap_or(base.range),
+        };
+        target.validate()?;
+        Ok(target)
+    }
+
+    /// Ranges loudnorm accepts, so the dynamic fallback cannot reject them.
+    fn validate(&self) -> AudioResult<()> {
+        let check = |name: &str, value: f64, min: f64, max: f64| {
+            if (min..=max).contains(&value) {
+                Ok(())
+            } else {
+                Err(AudioError::InvalidArgument(format!(
+                    "{} must be between {} and {}",
+                    name, min, max
+                )))
+            }
+        };
+        check("target_i", self.integrated, -70.0, -5.0)?;
+        check("target_tp", self.true_peak, -9.0, 0.0)?;
+        check("target_lra", self.range, 1.0, 50.0)
+    }
+}
+
+/// What `normalize_path` brings the file to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NormalizeMode {
+    Loudness(LoudnessTarget),
+    /// Sample peak, dBFS.
+    Peak(f64),
+    /// Unweighted RMS level, dBFS.
+    Rms(f64),
+    /// Largest gain that keeps the true peak at or below 0 dBTP.
+    Maximize,
+}
+
+impl NormalizeMode {
+    /// `target_db` applies to the peak and RMS modes; `target` is only used
+    /// in loudness mode.
+    pub fn parse(mode: &str, target: LoudnessTarget, target_db: Option<f64>) -> AudioResult<Self> {
+        let level = |default: f64| {
+            let db = target_db.unwrap_or(default);
+            if (-60.0..=0.0).contains(&db) {
+                Ok(db)
+            } else {
+                Err(AudioError::InvalidArgument(
+                    "target_db must be between -60 and 0".into(),
+                ))
+            }
+        };
+        match mode.trim().to_ascii_lowercase().as_str() {
+            "" | "loudness" | "lufs" => Ok(Self::Loudness(target)),
+            "peak" => level(-1.0).map(Self::Peak),
+            "rms" => level(-20.0).map(Self::Rms),
+            "maximize" | "max" => Ok(Self::Maximize),
+            other => Err(AudioError::InvalidArgument(format!(
+                "Invalid normalize mode '{}' (must be loudness, peak, rms or maximize)",
+                other
+            ))),
+        }
+    }
+}
+
+/// How a gain ended up being applied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GainMode {
+    /// Plain gain.
+    Linear,
+    /// Gain followed by the true-peak limiter, which had to reduce peaks.
+    Limited,
+    /// loudnorm had to compress dynamics to meet the target.
+    Dynamic,
+}
+
+impl GainMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            GainMode::Linear => "linear",
+            GainMode::Limited => "limited",
+            GainMode::Dynamic => "dynamic",
+        }
     }
 }
 
-fn run_ffmpeg_filter(
+/// How a file's level was changed, with loudness measured before and after.
+#[derive(Debug, Clone, Copy)]
+pub struct GainReport {
+    pub applied_gain: f64,
+    pub before: Loudness,
+    pub after: Loudness,
+    pub mode: GainMode,
+    /// Output samples beyond full scale (clipped when stored as integers).
+    pub clipped: u64,
+    /// ReplayGain of the output; album values only in album mode.
+    pub replaygain: ReplayGain,
+}
+
+impl GainReport {
+    /// User-facing warning when the output clips.
+    pub fn warning(&self) -> Option<String> {
+        (self.clipped > 0).then(|| {
+            format!(
+                "{} samples clipped (peak {:+.1} dBFS)",
+                self.clipped, self.after.sample_peak
+            )
+        })
+    }
+}
+
+/// Default limiter ceiling for BoostManual, dBTP.
+pub const DEFAULT_CEILING: f64 = -1.0;
+
+pub fn boost_file(
     input_bytes: Vec<u8>,
     output_format: &str,
-    afilter: &str,
-) -> Result<Vec<u8>, String> {
-    // temp in
-    let tmp_in = NamedTempFile::new().map_err(|e| format!("tmpfile in: {}", e))?;
-    fs::write(tmp_in.path(), &input_bytes).map_err(|e| format!("write tmp in: {}", e))?;
+    gain: i32,
+    ceiling: Option<f64>,
+) -> AudioResult<(Vec<u8>, GainReport)> {
+    let tmp_in = NamedTempFile::new().map_err(AudioError::io("tmpfile in"))?;
+    fs::write(tmp_in.path(), &input_bytes).map_err(AudioError::io("write tmp in"))?;
     let in_path = tmp_in.into_temp_path();
 
-    // temp out
-    let tmp_out = NamedTempFile::new().map_err(|e| format!("tmpfile out: {}", e))?;
+    let tmp_out = NamedTempFile::new().map_err(AudioError::io("tmpfile out"))?;
     let out_path = tmp_out.into_temp_path();
 
-    // plan
-    let plan = plan_for(output_format)?;
+    let report = boost_path(&in_path, &out_path, output_format, gain, ceiling)?;
+
+    let bytes = fs::read(&out_path).map_err(AudioError::io("read tmp out"))?;
+    Ok((bytes, report))
+}
+
+/// Path-based core of `boost_file`. The gain is applied to the decoded samples
+/// in-process; only non-WAV output is handed to ffmpeg for encoding. With a
+/// `ceiling` (dBTP) the result goes through the look-ahead limiter.
+pub fn boost_path(
+    in_path: &Path,
+    out_path: &Path,
+    output_format: &str,
+    gain: i32,
+    ceiling: Option<f64>,
+) -> AudioResult<GainReport> {
+    if let Some(ceiling) = ceiling
+        && !(-20.0..=0.0).contains(&ceiling)
+    {
+        return Err(AudioError::InvalidArgument(
+            "ceiling_db must be between -20 and 0".into(),
+        ));
+    }
+    apply_gain(in_path, out_path, output_format, gain as f64, ceiling, None)
+}
+
+/// Scale the decoded samples by `gain_db` (then limit them to `ceiling` if
+/// given) and encode to `output_format`. The input is metered on the way
+/// unless its loudness is already known.
+fn apply_gain(
+    in_path: &Path,
+    out_path: &Path,
+    output_format: &str,
+    gain_db: f64,
+    ceiling: Option<f64>,
+    before: Option<Loudness>,
+) -> AudioResult<GainReport> {
+    let factor = 10f64.powf(gain_db / 20.0) as f32;
+
+    let mut dec = Decoder::open(in_path)?;
+    let spec = dec.spec();
+    let mut out = PcmWriter::create(out_path, spec, output_format, 0)?.tags_from(in_path);
+    let mut input_meter = before.is_none().then(|| LoudnessMeter::new(spec));
+    let mut output_meter = LoudnessMeter::new(spec);
+    let mut limiter = ceiling.map(|c| Limiter::new(spec, c));
+    let mut clipped = 0;
+    let mut scaled = Vec::new();
+    let mut limited = Vec::new();
+    let mut emit = |samples: &[f32]| {
+        clipped += samples.iter().filter(|s| s.abs() > 1.0).count() as u64;
+        output_meter.push(samples);
+        out.write(samples)
+    };
 
-    // build
-    let mut cmd = Command::new("ffmpeg");
-    cmd.args(["-y", "-hide_banner", "-loglevel", "error"]);
-    cmd.args(["-i", in_path.to_str().ok_or("bad in_path")?]);
-    cmd.args(["-af", afilter]);
-    if !plan.pre_f_args.is_empty() {
-        cmd.args(plan.pre_f_args);
+    while let Some(block) = dec.next_block()? {
+        if let Some(meter) = &mut input_meter {
+            meter.push(block);
+        }
+        scaled.clear();
+        scaled.extend(block.iter().map(|s| s * factor));
+        match &mut limiter {
+            Some(limiter) => {
+                limited.clear();
+                limiter.process(&scaled, &mut limited);
+                emit(&limited)?;
+            }
+            None => emit(&scaled)?,
+        }
     }
-    cmd.args(["-f", plan.muxer, out_path.to_str().ok_or("bad out_path")?]);
-
-    // run
-    let output = cmd.output().map_err(|e| format!("ffmpeg exec: {}", e))?;
-    if !output.status.success() {
-        return Err(format!(
-            "ffmpeg failed: {}",
-            String::from_utf8_lossy(&output.stderr)
+    if let Some(limiter) = &mut limiter {
+        limited.clear();
+        limiter.finish(&mut limited);
+        emit(&limited)?;
+    }
+    out.finish()?;
+
+    let after = output_meter.finish();
+    let before = match (before, input_meter) {
+        (Some(before), _) => before,
+        (None, Some(meter)) => meter.finish(),
+        (None, None) => unreachable!("input is metered when its loudness is unknown"),
+    };
+    Ok(GainReport {
+        applied_gain: gain_db,
+        before,
+        after,
+        mode: if limiter.is_some_and(|l| l.engaged()) {
+            GainMode::Limited
+        } else {
+            GainMode::Linear
+        },
+        clipped,
+        replaygain: ReplayGain::new(&after, None),
+    })
+}
+
+pub fn normalize_file(
+    input_bytes: Vec<u8>,
+    output_format: &str,
+    mode: NormalizeMode,
+) -> AudioResult<(Vec<u8>, GainReport)> {
+    let tmp_in = NamedTempFile::new().map_err(AudioError::io("tmpfile in"))?;
+    fs::write(tmp_in.path(), &input_bytes).map_err(AudioError::io("write tmp in"))?;
+    let in_path = tmp_in.into_temp_path();
+
+    let tmp_out = NamedTempFile::new().map_err(AudioError::io("tmpfile out"))?;
+    let out_path = tmp_out.into_temp_path();
+
+    let report = normalize_path(&in_path, &out_path, output_format, mode)?;
+
+    let bytes = fs::read(&out_path).map_err(AudioError::io("read tmp out"))?;
+    Ok((bytes, report))
+}
+
+/// Two-pass normalization. The first pass measures the input. Peak, RMS and
+/// maximize modes then apply the gain that reaches their target. In loudness
+/// mode the gain is applied as is if it keeps the true peak under its limit
+/// (and the loudness range is already within target); otherwise loudnorm is
+/// run with the measured values so it only compresses as much as needed. The
+/// output is measured too, for the report.
+pub fn normalize_path(
+    in_path: &Path,
+    out_path: &Path,
+    output_format: &str,
+    mode: NormalizeMode,
+) -> AudioResult<GainReport> {
+    let meter = meter_path(in_path)?;
+    let before = meter.finish();
+    let gain = target_gain(mode, &before)?;
+
+    if let NormalizeMode::Loudness(target) = mode
+        && (before.true_peak + gain > target.true_peak || before.range > target.range)
+    {
+        loudnorm_path(
+            in_path,
+            out_path,
+            output_format,
+            &before,
+            meter.spec().sample_rate,
+            target,
+        )?;
+        let (after, clipped) = measure_output(out_path)?;
+        return Ok(GainReport {
+            applied_gain: after.integrated - before.integrated,
+            before,
+            after,
+            mode: GainMode::Dynamic,
+            clipped,
+            replaygain: ReplayGain::new(&after, None),
+        });
+    }
+
+    apply_gain(in_path, out_path, output_format, gain, None, Some(before))
+}
+
+/// Loudness of an encoded output and the number of its samples at or beyond
+/// full scale. The samples come back from the decoder already stored, so a
+/// clipped integer sample reads as the largest code rather than above 1.0.
+fn measure_output(out_path: &Path) -> AudioResult<(Loudness, u64)> {
+    const FULL_SCALE: f32 = i16::MAX as f32 / 32768.0;
+
+    let mut dec = Decoder::open(out_path)?;
+    let mut meter = LoudnessMeter::new(dec.spec());
+    let mut clipped = 0;
+    while let Some(block) = dec.next_block()? {
+        clipped += block.iter().filter(|s| s.abs() >= FULL_SCALE).count() as u64;
+        meter.push(block);
+    }
+    Ok((meter.finish(), clipped))
+}
+
+/// Gain that brings `measured` to the target of `mode`.
+fn target_gain(mode: NormalizeMode, measured: &Loudness) -> AudioResult<f64> {
+    let silent = match mode {
+        NormalizeMode::Loudness(_) => !measured.integrated.is_finite(),
+        _ => !measured.sample_peak.is_finite(),
+    };
+    if silent {
+        return Err(AudioError::InvalidArgument(
+            "audio is silent, cannot normalize".into(),
         ));
     }
+    Ok(match mode {
+        NormalizeMode::Loudness(target) => target.integrated - measured.integrated,
+        NormalizeMode::Peak(target) => target - measured.sample_peak,
+        NormalizeMode::Rms(target) => target - measured.rms,
+        NormalizeMode::Maximize => -measured.true_peak,
+    })
+}
 
-    // read
-    fs::read(&out_path).map_err(|e| format!("read tmp out: {}", e))
+/// One gain for every track of an album, worked out from all of them.
+#[derive(Debug, Clone, Copy)]
+pub struct AlbumPlan {
+    pub gain: f64,
+    /// Limiter ceiling (dBTP) when the common gain would push the loudest
+    /// track over the true-peak target.
+    pub ceiling: Option<f64>,
+    /// Expected loudness of the album after the gain.
+    pub album: Loudness,
 }
 
-pub fn boost_file(input_bytes: Vec<u8>, output_format: &str, gain: i32) -> Result<Vec<u8>, String> {
-    // Positive gain boosts, negative attenuates (e.g., -3 dB)
-    let afilter = format!("volume={}dB", gain);
-    run_ffmpeg_filter(input_bytes, output_format, &afilter)
+/// Measure the tracks as one programme and derive the common gain. Loudness
+/// mode never uses loudnorm here, as that would change tracks differently;
+/// the limiter keeps the true peak in check instead.
+pub fn album_plan(meters: &[&LoudnessMeter], mode: NormalizeMode) -> AudioResult<AlbumPlan> {
+    let album = LoudnessMeter::combine(meters);
+    let gain = target_gain(mode, &album)?;
+    let ceiling = match mode {
+        NormalizeMode::Loudness(target) if album.true_peak + gain > target.true_peak => {
+            Some(target.true_peak)
+        }
+        _ => None,
+    };
+    let peak = |db: f64| ceiling.map_or(db + gain, |c| (db + gain).min(c));
+    Ok(AlbumPlan {
+        gain,
+        ceiling,
+        album: Loudness {
+            integrated: album.integrated + gain,
+            short_term_max: album.short_term_max + gain,
+            momentary_max: album.momentary_max + gain,
+            range: album.range,
+            sample_peak: peak(album.sample_peak),
+            true_peak: peak(album.true_peak),
+            rms: album.rms + gain,
+            threshold: album.threshold + gain,
+        },
+    })
 }
 
-pub fn normalize_file(input_bytes: Vec<u8>, output_format: &str) -> Result<Vec<u8>, String> {
-    // One-pass EBU R128; good defaults for music/podcasts.
-    // If you ever want the more precise two-pass, we can add it later.
-    let afilter = "loudnorm=I=-16:TP=-1.5:LRA=11";
-    run_ffmpeg_filter(input_bytes, output_format, afilter)
+/// Apply an album's common gain to one of its tracks.
+pub fn album_path(
+    in_path: &Path,
+    out_path: &Path,
+    output_format: &str,
+    plan: &AlbumPlan,
+    before: Loudness,
+) -> AudioResult<GainReport> {
+    let mut report = apply_gain(
+        in_path,
+        out_path,
+        output_format,
+        plan.gain,
+        plan.ceiling,
+        Some(before),
+    )?;
+    report.replaygain = ReplayGain::new(&report.after, Some(&plan.album));
+    Ok(report)
+}
+
+/// loudnorm fed with the first pass' measurements, so it applies a plain
+/// gain when that meets the target and only compresses as much as needed.
+pub(crate) fn loudnorm_filter(target: LoudnessTarget, before: &Loudness) -> String {
+    format!(
+        "loudnorm=I={}:TP={}:LRA={}:measured_I={:.2}:measured_TP={:.2}:\
+         measured_LRA={:.2}:measured_thresh={:.2}:linear=true",
+        target.integrated,
+        target.true_peak,
+        target.range,
+        before.integrated,
+        before.true_peak.clamp(-99.0, 99.0),
+        before.range,
+        before.threshold,
+    )
+}
+
+/// Dynamic second pass: loudnorm fed with the first pass' measurements.
+fn loudnorm_path(
+    in_path: &Path,
+    out_path: &Path,
+    output_format: &str,
+    before: &Loudness,
+    sample_rate: u32,
+    target: LoudnessTarget,
+) -> AudioResult<()> {
+    // loudnorm's dynamic mode works at 192 kHz; go back to the source rate.
+    let rate = sample_rate.to_string();
+    FfmpegJob::new()
+        .input(in_path)?
+        .filter(loudnorm_filter(target, before))
+        .encode(output_format)?
+        .output_args(&["-ar", &rate])
+        .run(out_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_wav::wav_i16;
+
+    #[test]
+    fn output_counts_full_scale_samples() {
+        // Every 100th frame of the left channel is pinned to either rail.
+        let wav = wav_i16(48_000, 2, 48_000, |i, c| match (i % 100, c) {
+            (0, 0) if i % 200 == 0 => i16::MAX,
+            (0, 0) => i16::MIN,
+            _ => 1000,
+        });
+        let (after, clipped) = measure_output(&wav).unwrap();
+        assert_eq!(clipped, 480);
+        assert!(after.sample_peak > -0.01);
+    }
 }
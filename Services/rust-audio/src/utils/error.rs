@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::process::Output;
+use tonic::{Code, Status};
+use tonic_types::{ErrorDetails, StatusExt};
+
+/// Domain reported in the `ErrorInfo` detail of every error we send.
+const ERROR_DOMAIN: &str = "sonic-tools";
+
+/// Only the tail of ffmpeg's stderr is attached to a status; the details travel
+/// in a response trailer, which clients cap in size.
+const MAX_STDERR: usize = 4 * 1024;
+
+/// Every way an audio operation can fail, so clients can tell a bad upload
+/// from a misconfigured server.
+#[derive(Debug)]
+pub enum AudioError {
+    /// Requested output (or input) format we cannot produce or handle.
+    UnsupportedFormat(String),
+    /// Request parameters that make no sense (ranges, actions, missing fields).
+    InvalidArgument(String),
+    /// The upload could not be decoded (corrupt, truncated, not audio).
+    DecodeFailed { message: String, stderr: String },
+    /// ffmpeg/ffprobe or a codec it needs is not available on this server.
+    EncoderMissing(String),
+    /// An ffmpeg run took longer than the configured limit.
+    Timeout(String),
+    /// The job this work belonged to was cancelled.
+    Cancelled,
+    /// The processing pool turned the work away because its queue was full.
+    Busy(String),
+    /// Temp file handling failed.
+    Io { context: String, source: io::Error },
+    /// ffmpeg failed for any other reason.
+    ProcessingFailed { message: String, stderr: String },
+}
+
+pub type AudioResult<T> = Result<T, AudioError>;
+
+impl AudioError {
+    /// `map_err` adapter for filesystem errors: `.map_err(AudioError::io("read tmp out"))`.
+    pub fn io(context: &str) -> impl FnOnce(io::Error) -> AudioError + '_ {
+        move |source| AudioError::Io {
+            context: context.to_string(),
+            source,
+        }
+    }
+
+    /// Error for a tool (`ffmpeg`, `ffprobe`) that could not be run at all.
+    pub fn exec(tool: &str, err: io::Error) -> AudioError {
+        match err.kind() {
+            io::ErrorKind::NotFound => AudioError::EncoderMissing(format!("{} not found", tool)),
+            io::ErrorKind::Interrupted => AudioError::Cancelled,
+            io::ErrorKind::TimedOut => AudioError::Timeout(format!("{} timed out", tool)),
+            _ => AudioError::Io {
+                context: format!("{} exec", tool),
+                source: err,
+            },
+        }
+    }
+
+    /// Classify a failed ffmpeg/ffprobe run by what it printed on stderr.
+    pub fn failed(context: &str, output: &Output) -> AudioError {
+        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        let last_line = stderr.lines().last().unwrap_or("no output").trim();
+        let message = format!("{}: {}", context, last_line);
+        let has = |needle: &str| stderr.contains(needle);
+
+        if has("Unknown encoder") || has("Encoder not found") || has("Unknown decoder") {
+            AudioError::EncoderMissing(message)
+        } else if has("is not a suitable output format") || has("Unable to choose an output format")
+        {
+            AudioError::UnsupportedFormat(message)
+        } else if has("Invalid data found when processing input")
+            || has("could not find codec parameters")
+            || has("moov atom not found")
+            || has("Error while decoding")
+            || has("does not contain any stream")
+        {
+            AudioError::DecodeFailed { message, stderr }
+        } else {
+            AudioError::ProcessingFailed { message, stderr }
+        }
+    }
+
+    pub fn code(&self) -> Code {
+        match self {
+            AudioError::UnsupportedFormat(_)
+            | AudioError::InvalidArgument(_)
+            | AudioError::DecodeFailed { .. } => Code::InvalidArgument,
+            AudioError::EncoderMissing(_) => Code::FailedPrecondition,
+            AudioError::Timeout(_) => Code::DeadlineExceeded,
+            AudioError::Cancelled => Code::Cancelled,
+            AudioError::Busy(_) => Code::ResourceExhausted,
+            AudioError::Io { .. } | AudioError::ProcessingFailed { .. } => Code::Internal,
+        }
+    }
+
+    /// Machine-readable reason for the `ErrorInfo` detail.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            AudioError::UnsupportedFormat(_) => "UNSUPPORTED_FORMAT",
+            AudioError::InvalidArgument(_) => "INVALID_ARGUMENT",
+            AudioError::DecodeFailed { .. } => "DECODE_FAILED",
+            AudioError::EncoderMissing(_) => "ENCODER_MISSING",
+            AudioError::Timeout(_) => "TIMEOUT",
+            AudioError::Cancelled => "CANCELLED",
+            AudioError::Busy(_) => "BUSY",
+            AudioError::Io { .. } => "IO",
+            AudioError::ProcessingFailed { .. } => "PROCESSING_FAILED",
+        }
+    }
+
+    fn stderr(&self) -> Option<&str> {
+        match self {
+            AudioError::DecodeFailed { stderr, .. }
+            | AudioError::ProcessingFailed { stderr, .. } => Some(stderr),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for AudioError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AudioError::UnsupportedFormat(msg)
+            | AudioError::InvalidArgument(msg)
+            | AudioError::EncoderMissing(msg)
+            | AudioError::Timeout(msg)
+            | AudioError::Busy(msg) => f.write_str(msg),
+            AudioError::DecodeFailed { message, .. }
+            | AudioError::ProcessingFailed { message, .. } => f.write_str(message),
+            AudioError::Cancelled => f.write_str("job cancelled"),
+            AudioError::Io { context, source } => write!(f, "{}: {}", context, source),
+        }
+    }
+}
+
+impl std::error::Error for AudioError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AudioError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// Status with an `ErrorInfo` (reason + domain) and, for ffmpeg failures, the
+/// tail of its stderr as `DebugInfo`.
+impl From<AudioError> for Status {
+    fn from(err: AudioError) -> Status {
+        let mut details = ErrorDetails::new();
+        details.set_error_info(err.reason(), ERROR_DOMAIN, HashMap::new());
+        if let Some(stderr) = err.stderr().filter(|s| !s.is_empty()) {
+            details.set_debug_info(Vec::new(), tail(stderr, MAX_STDERR));
+        }
+        Status::with_error_details(err.code(), err.to_string(), details)
+    }
+}
+
+/// Last `max` bytes of `s`, cut at a char boundary.
+fn tail(s: &str, max: usize) -> &str {
+    if s.len() <= max {
+        return s;
+    }
+    let mut start = s.len() - max;
+    while !s.is_char_boundary(start) {
+        start += 1;
+    }
+    &s[start..]
+}
+
+/// `path` as UTF-8 for use on an ffmpeg command line.
+pub fn path_arg(path: &Path) -> AudioResult<&str> {
+    path.to_str().ok_or_else(|| AudioError::Io {
+        context: format!("non UTF-8 path {}", path.display()),
+        source: io::Error::from(io::ErrorKind::InvalidInput),
+    })
+}
@@ -0,0 +1,242 @@
+use crate::utils::error::{AudioError, AudioResult};
+use crate::utils::native::{Decoder, PcmSpec, PcmWriter};
+use std::collections::VecDeque;
+use std::fs;
+use std::path::Path;
+use tempfile::NamedTempFile;
+
+/// Length of the automatic fades at cut points, enough to avoid a click.
+pub const MICRO_FADE_MS: u32 = 5;
+
+/// Shape of a fade, named like ffmpeg's `afade` curves and computed the same
+/// way, so native and filter-based fades sound alike.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FadeCurve {
+    #[default]
+    Linear,
+    Log,
+    Exp,
+    SCurve,
+}
+
+impl FadeCurve {
+    pub fn parse(name: &str) -> AudioResult<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "" | "linear" => Ok(Self::Linear),
+            "log" => Ok(Self::Log),
+            "exp" => Ok(Self::Exp),
+            "s_curve" | "s-curve" | "scurve" => Ok(Self::SCurve),
+            other => Err(AudioError::InvalidArgument(format!(
+                "Invalid fade curve '{}' (must be 'linear', 'log', 'exp' or 's_curve')",
+                other
+            ))),
+        }
+    }
+
+    /// The matching `afade` curve name.
+    pub fn afade_name(self) -> &'static str {
+        match self {
+            Self::Linear => "tri",
+            Self::Log => "log",
+            Self::Exp => "exp",
+            Self::SCurve => "hsin",
+        }
+    }
+
+    /// Gain `x` of the way (0 to 1) through a fade-in.
+    pub fn gain(self, x: f64) -> f64 {
+        let x = x.clamp(0.0, 1.0);
+        match self {
+            Self::Linear => x,
+            Self::Log => (1.0 + 0.2 * x.log10()).clamp(0.0, 1.0),
+            Self::Exp => (-11.512925464970228 * (1.0 - x)).exp(),
+            Self::SCurve => (1.0 - (x * std::f64::consts::PI).cos()) / 2.0,
+        }
+    }
+}
+
+/// Fades applied while writing an edited output.
+#[derive(Debug, Clone, Copy)]
+pub struct Fades {
+    pub in_ms: u32,
+    pub out_ms: u32,
+    pub curve: FadeCurve,
+    /// Short fades at every cut point (on by default).
+    pub micro: bool,
+}
+
+impl Default for Fades {
+    fn default() -> Self {
+        Self {
+            in_ms: 0,
+            out_ms: 0,
+            curve: FadeCurve::Linear,
+            micro: true,
+        }
+    }
+}
+
+fn frames(ms: u32, sample_rate: u32) -> usize {
+    (ms as u64 * sample_rate as u64 / 1000) as usize
+}
+
+/// `PcmWriter` that applies fades: the requested fade-in and fade-out at the
+/// start and end of the output, and micro-fades (an S-curve of
+/// `MICRO_FADE_MS`) on both sides of every cut. Fades that overlap multiply.
+/// The last frames are held back until it is known whether a fade-out
+/// applies to them.
+pub struct FadeWriter {
+    writer: PcmWriter,
+    channels: usize,
+    fades: Fades,
+    micro: usize,
+    fade_in: usize,
+    fade_out: usize,
+    /// Frames written so far, for the fade-in.
+    done: usize,
+    /// Frames since the last cut, for its micro fade-in.
+    since_cut: usize,
+    tail: VecDeque<f32>,
+    /// Frames kept in `tail`.
+    hold: usize,
+}
+
+impl FadeWriter {
+    /// `cut_in`: the output starts at a cut rather than at the source's start.
+    pub fn new(writer: PcmWriter, spec: PcmSpec, fades: Fades, cut_in: bool) -> Self {
+        let micro = if fades.micro {
+            frames(MICRO_FADE_MS, spec.sample_rate)
+        } else {
+            0
+        };
+        let fade_out = frames(fades.out_ms, spec.sample_rate);
+        Self {
+            writer,
+            channels: spec.channels as usize,
+            fades,
+            micro,
+            fade_in: frames(fades.in_ms, spec.sample_rate),
+            fade_out,
+            done: 0,
+            since_cut: if cut_in { 0 } else { micro },
+            tail: VecDeque::new(),
+            hold: fade_out.max(micro),
+        }
+    }
+
+    /// Append interleaved samples.
+    pub fn write(&mut self, samples: &[f32]) -> AudioResult<()> {
+        for frame in samples.chunks_exact(self.channels) {
+            let mut gain = 1.0;
+            if self.done < self.fade_in {
+                gain *= self
+                    .fades
+                    .curve
+                    .gain(self.done as f64 / self.fade_in as f64);
+            }
+            if self.since_cut < self.micro {
+                gain *= FadeCurve::SCurve.gain(self.since_cut as f64 / self.micro as f64);
+                self.since_cut += 1;
+            }
+            self.done += 1;
+            if gain < 1.0 {
+                let gain = gain as f32;
+                self.tail.extend(frame.iter().map(|s| s * gain));
+            } else {
+                self.tail.extend(frame);
+            }
+        }
+
+        let keep = self.hold * self.channels;
+        if self.tail.len() > keep {
+            let ready: Vec<f32> = self.tail.drain(..self.tail.len() - keep).collect();
+            self.writer.write(&ready)?;
+        }
+        Ok(())
+    }
+
+    /// Mark a cut between what has been written and what follows: micro-fade
+    /// out of the one and into the other. The held frames stay held, so a
+    /// fade-out can still reach back across the cut.
+    pub fn cut(&mut self) -> AudioResult<()> {
+        if self.micro == 0 {
+            return Ok(());
+        }
+        self.fade_tail(self.micro, FadeCurve::SCurve);
+        self.since_cut = 0;
+        Ok(())
+    }
+
+    /// Apply the fade-out and finish the output. `cut_out`: the output ends
+    /// at a cut rather than at the source's end.
+    pub fn finish(mut self, cut_out: bool) -> AudioResult<()> {
+        if self.fade_out > 0 {
+            self.fade_tail(self.fade_out, self.fades.curve);
+        }
+        if cut_out {
+            self.fade_tail(self.micro, FadeCurve::SCurve);
+        }
+        self.flush()?;
+        self.writer.finish()
+    }
+
+    /// Fade out the last `length` frames held back.
+    fn fade_tail(&mut self, length: usize, curve: FadeCurve) {
+        if length == 0 {
+            return;
+        }
+        let held = self.tail.len() / self.channels;
+        let length = length.min(held);
+        let start = (held - length) * self.channels;
+        let tail = self.tail.make_contiguous();
+        for (i, frame) in tail[start..].chunks_exact_mut(self.channels).enumerate() {
+            let gain = curve.gain((length - 1 - i) as f64 / length as f64) as f32;
+            for s in frame {
+                *s *= gain;
+            }
+        }
+    }
+
+    fn flush(&mut self) -> AudioResult<()> {
+        let (a, b) = self.tail.as_slices();
+        self.writer.write(a)?;
+        self.writer.write(b)?;
+        self.tail.clear();
+        Ok(())
+    }
+}
+
+pub fn fade_file(input_bytes: Vec<u8>, output_format: &str, fades: Fades) -> AudioResult<Vec<u8>> {
+    let tmp_in = NamedTempFile::new().map_err(AudioError::io("tmpfile in"))?;
+    fs::write(tmp_in.path(), &input_bytes).map_err(AudioError::io("write tmp in"))?;
+    let in_path = tmp_in.into_temp_path();
+
+    let tmp_out = NamedTempFile::new().map_err(AudioError::io("tmpfile out"))?;
+    let out_path = tmp_out.into_temp_path();
+
+    fade_path(&in_path, &out_path, output_format, fades)?;
+
+    fs::read(&out_path).map_err(AudioError::io("read tmp out"))
+}
+
+/// Fade the whole of `in_path` in and/or out.
+pub fn fade_path(
+    in_path: &Path,
+    out_path: &Path,
+    output_format: &str,
+    fades: Fades,
+) -> AudioResult<()> {
+    if fades.in_ms == 0 && fades.out_ms == 0 {
+        return Err(AudioError::InvalidArgument(
+            "fade_in_ms or fade_out_ms must be set".into(),
+        ));
+    }
+
+    let mut dec = Decoder::open(in_path)?;
+    let writer = PcmWriter::create(out_path, dec.spec(), output_format, 0)?.tags_from(in_path);
+    let mut out = FadeWriter::new(writer, dec.spec(), fades, false);
+    while let Some(block) = dec.next_block()? {
+        out.write(block)?;
+    }
+    out.finish(false)
+}
@@ -1,77 +1,136 @@
+use crate::utils::error::{AudioError, AudioResult, path_arg};
+use crate::utils::probe::{ProbeInfo, probe_path, sniff_container};
+use crate::utils::process;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
 use std::process::Command;
-use tempfile::NamedTempFile;
 
-pub fn probe_bitrate(input_bytes: &[u8], _ext: &str) -> Result<i32, String> {
-    // 1) Create temp file and write bytes
-    let tmp = NamedTempFile::new().map_err(|e| format!("tmpfile: {}", e))?;
-    std::fs::write(tmp.path(), input_bytes).map_err(|e| format!("write tmp: {}", e))?;
-
-    // 2) Convert to TempPath: closes handle, keeps file on disk, will delete on drop
-    let tmp_path = tmp.into_temp_path();
+pub fn probe_duration_path(path: &Path) -> AudioResult<f32> {
+    probe_path(path).map(|info| info.duration as f32)
+}
 
-    // 3) Run ffprobe on the path
-    let output = Command::new("ffprobe")
-        .args([
-            "-v",
-            "error",
-            "-of",
-            "csv=p=0",
-            "-show_entries",
-            "format=bit_rate",
-            tmp_path.as_os_str().to_str().ok_or("tmp path utf-8")?,
-        ])
-        .output()
-        .map_err(|e| format!("ffprobe exec: {}", e))?;
+/// ffprobe fallback of `probe::probe_path` for formats symphonia cannot read
+/// (e.g. WMA). Fields ffprobe reports as "N/A" are derived or left at 0.
+pub(crate) fn ffprobe_path(path: &Path) -> AudioResult<ProbeInfo> {
+    let output = process::output(Command::new("ffprobe").args([
+        "-v",
+        "error",
+        "-select_streams",
+        "a:0",
+        "-show_entries",
+        "stream=codec_name,sample_rate,channels,bits_per_sample,bits_per_raw_sample\
+         :format=format_name,duration,bit_rate:format_tags",
+        "-of",
+        "default=noprint_wrappers=1",
+        path_arg(path)?,
+    ]))
+    .map_err(|e| AudioError::exec("ffprobe", e))?;
 
     if !output.status.success() {
-        let err = String::from_utf8_lossy(&output.stderr);
-        // Temp file auto-deletes here on drop
-        return Err(format!("ffprobe failed: {}", err));
+        return Err(AudioError::failed("ffprobe failed", &output));
     }
 
+    let mut info = ProbeInfo::default();
+    let mut format_name = String::new();
     let stdout = String::from_utf8_lossy(&output.stdout);
-    // Some files return "N/A"; handle that if needed
-    let bps: i32 = stdout
-        .trim()
-        .parse()
-        .map_err(|_| format!("Could not parse bitrate from '{}'", stdout.trim()))?;
+    for line in stdout.lines() {
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        if let Some(tag) = key.strip_prefix("TAG:") {
+            info.tags.push((tag.to_string(), value.to_string()));
+            continue;
+        }
+        if value == "N/A" {
+            continue;
+        }
+        let number = || value.parse::<f64>().unwrap_or(0.0);
+        match key {
+            "codec_name" => info.codec = value.to_string(),
+            "sample_rate" => info.sample_rate = number() as u32,
+            "channels" => info.channels = number() as u32,
+            "bits_per_sample" | "bits_per_raw_sample" => {
+                info.bit_depth = info.bit_depth.max(number() as u32)
+            }
+            "format_name" => format_name = value.to_string(),
+            "duration" => info.duration = number(),
+            "bit_rate" => info.bitrate = number() as u32,
+            _ => {}
+        }
+    }
 
-    // 4) Explicit delete now (optional). If you omit this, it deletes on drop anyway.
-    tmp_path.close().map_err(|e| format!("delete tmp: {}", e))?;
+    if info.codec.is_empty() {
+        return Err(AudioError::DecodeFailed {
+            message: "ffprobe found no audio stream".into(),
+            stderr: String::new(),
+        });
+    }
 
-    Ok(bps)
+    let mut head = Vec::with_capacity(16);
+    if let Ok(file) = File::open(path) {
+        let _ = file.take(16).read_to_end(&mut head);
+    }
+    info.container = match sniff_container(&head) {
+        Some(name) => name.to_string(),
+        None => format_name
+            .split(',')
+            .next()
+            .unwrap_or("unknown")
+            .to_string(),
+    };
+    if info.bitrate == 0 && info.duration > 0.0 {
+        let size = fs::metadata(path).map_or(0, |m| m.len());
+        info.bitrate = (size as f64 * 8.0 / info.duration) as u32;
+    }
+
+    Ok(info)
 }
 
-pub fn probe_duration(input_bytes: &[u8]) -> Result<f32, String> {
-    let tmp = NamedTempFile::new().map_err(|e| format!("tmpfile: {}", e))?;
-    std::fs::write(tmp.path(), input_bytes).map_err(|e| format!("write tmp: {}", e))?;
-    let tmp_path = tmp.into_temp_path();
+/// A chapter marker, in seconds from the start.
+#[derive(Debug, Clone, Default)]
+pub struct Chapter {
+    pub start: f64,
+    pub end: f64,
+    pub title: Option<String>,
+}
 
-    let output = Command::new("ffprobe")
-        .args([
-            "-v",
-            "error",
-            "-of",
-            "csv=p=0",
-            "-show_entries",
-            "format=duration",
-            tmp_path.as_os_str().to_str().ok_or("tmp path utf-8")?,
-        ])
-        .output()
-        .map_err(|e| format!("ffprobe exec: {}", e))?;
+/// Chapters embedded in the container (MP4 chapter tracks, ID3 CHAP frames,
+/// Matroska editions...), as reported by ffprobe, in order.
+pub fn ffprobe_chapters(path: &Path) -> AudioResult<Vec<Chapter>> {
+    let output = process::output(Command::new("ffprobe").args([
+        "-v",
+        "error",
+        "-show_entries",
+        "chapter=start_time,end_time:chapter_tags=title",
+        "-of",
+        "default",
+        path_arg(path)?,
+    ]))
+    .map_err(|e| AudioError::exec("ffprobe", e))?;
 
     if !output.status.success() {
-        let err = String::from_utf8_lossy(&output.stderr);
-        return Err(format!("ffprobe failed: {}", err));
+        return Err(AudioError::failed("ffprobe failed", &output));
     }
 
+    let mut chapters = Vec::new();
     let stdout = String::from_utf8_lossy(&output.stdout);
-    let secs: f32 = stdout
-        .trim()
-        .parse()
-        .map_err(|_| format!("Could not parse duration from '{}'", stdout.trim()))?;
-
-    tmp_path.close().map_err(|e| format!("delete tmp: {}", e))?;
-
-    Ok(secs)
+    for line in stdout.lines() {
+        if line == "[CHAPTER]" {
+            chapters.push(Chapter::default());
+            continue;
+        }
+        let (Some(chapter), Some((key, value))) = (chapters.last_mut(), line.split_once('='))
+        else {
+            continue;
+        };
+        match key {
+            "start_time" => chapter.start = value.parse().unwrap_or(0.0),
+            "end_time" => chapter.end = value.parse().unwrap_or(0.0),
+            "TAG:title" if !value.is_empty() => chapter.title = Some(value.to_string()),
+            _ => {}
+        }
+    }
+    chapters.sort_by(|a, b| a.start.total_cmp(&b.start));
+    Ok(chapters)
 }
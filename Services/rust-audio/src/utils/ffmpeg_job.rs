@@ -0,0 +1,268 @@
+use crate::utils::error::{AudioError, AudioResult, path_arg};
+use crate::utils::process::{self, ProgressHook, RunOptions};
+use std::path::Path;
+use std::process::Command;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Describes how to encode/mux for a requested "output_format" string.
+pub struct EncodePlan {
+    /// File extension to use for the output file (e.g. "m4a", "aac", "wav").
+    pub out_ext: &'static str,
+    /// ffmpeg muxer name to pass to `-f` (e.g. "mp4", "adts", "wav", "flac").
+    pub muxer: &'static str,
+    /// Codec and flags, placed before `-f`.
+    pub codec_args: &'static [&'static str],
+    /// Whether a kilobit bitrate (-b:a NNk) makes sense (e.g. not for lossless).
+    pub supports_bitrate: bool,
+}
+
+/// The single place that decides how each output format is encoded.
+pub fn plan_for(format: &str) -> AudioResult<EncodePlan> {
+    match format.to_ascii_lowercase().as_str() {
+        // Lossy
+        "mp3" => Ok(EncodePlan {
+            out_ext: "mp3",
+            muxer: "mp3",
+            codec_args: &["-c:a", "libmp3lame"],
+            supports_bitrate: true,
+        }),
+        "ogg" => Ok(EncodePlan {
+            out_ext: "ogg",
+            muxer: "ogg",
+            codec_args: &["-c:a", "libvorbis"],
+            supports_bitrate: true,
+        }),
+        "opus" => Ok(EncodePlan {
+            out_ext: "opus",
+            muxer: "ogg",
+            codec_args: &["-c:a", "libopus"],
+            supports_bitrate: true,
+        }),
+        "aac" => Ok(EncodePlan {
+            out_ext: "aac",
+            muxer: "adts",
+            codec_args: &["-c:a", "aac"],
+            supports_bitrate: true,
+        }),
+        "m4a" => Ok(EncodePlan {
+            out_ext: "m4a",
+            muxer: "mp4",
+            codec_args: &["-c:a", "aac", "-movflags", "+faststart"],
+            supports_bitrate: true,
+        }),
+        "wma" => Ok(EncodePlan {
+            out_ext: "wma",
+            muxer: "asf",
+            // Many players expect 44.1kHz stereo; set it for compatibility.
+            codec_args: &["-c:a", "wmav2", "-ar", "44100", "-ac", "2"],
+            supports_bitrate: true,
+        }),
+
+        // Lossless / PCM
+        "wav" => Ok(EncodePlan {
+            out_ext: "wav",
+            muxer: "wav",
+            codec_args: &["-c:a", "pcm_s16le"],
+            supports_bitrate: false,
+        }),
+        "flac" => Ok(EncodePlan {
+            out_ext: "flac",
+            muxer: "flac",
+            codec_args: &["-c:a", "flac"],
+            supports_bitrate: false,
+        }),
+        "aiff" | "aif" => Ok(EncodePlan {
+            out_ext: "aiff",
+            muxer: "aiff",
+            codec_args: &["-c:a", "pcm_s16be"],
+            supports_bitrate: false,
+        }),
+
+        other => Err(AudioError::UnsupportedFormat(format!(
+            "Unsupported output format: {}",
+            other
+        ))),
+    }
+}
+
+/// One ffmpeg invocation: inputs, filters, codec plan, muxer and tags, run
+/// through `process::run` so cancellation, progress and timeouts apply.
+///
+/// ```ignore
+/// FfmpegJob::new()
+///     .input(in_path)?
+///     .filter("volume=3dB")
+///     .encode("mp3")?
+///     .bitrate(192)
+///     .run(out_path)?;
+/// ```
+#[derive(Default)]
+pub struct FfmpegJob {
+    inputs: Vec<(Vec<String>, String)>,
+    maps: Vec<String>,
+    filters: Vec<String>,
+    plan: Option<EncodePlan>,
+    codec: Vec<String>,
+    bitrate: Option<i32>,
+    muxer: Option<String>,
+    output_args: Vec<String>,
+    metadata: Vec<(String, String)>,
+    label: Option<String>,
+    opts: RunOptions,
+}
+
+impl FfmpegJob {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an input file.
+    pub fn input(self, path: &Path) -> AudioResult<Self> {
+        self.input_with(&[], path)
+    }
+
+    /// Add an input file preceded by input options (e.g. `-f concat -safe 0`).
+    pub fn input_with(mut self, args: &[&str], path: &Path) -> AudioResult<Self> {
+        let args = args.iter().map(|a| a.to_string()).collect();
+        self.inputs.push((args, path_arg(path)?.to_string()));
+        Ok(self)
+    }
+
+    /// Select a stream for the output (`-map`), e.g. "0:a".
+    pub fn map(mut self, spec: &str) -> Self {
+        self.maps.push(spec.to_string());
+        self
+    }
+
+    /// Append an audio filter; all filters end up in one `-af` chain.
+    pub fn filter(mut self, filter: impl Into<String>) -> Self {
+        self.filters.push(filter.into());
+        self
+    }
+
+    /// Encode with the shared plan for `format` (codec, flags and muxer).
+    pub fn encode(mut self, format: &str) -> AudioResult<Self> {
+        self.plan = Some(plan_for(format)?);
+        Ok(self)
+    }
+
+    /// Explicit codec args instead of (or on top of) a plan, e.g. `-c copy`.
+    pub fn codec(mut self, args: &[&str]) -> Self {
+        self.codec.extend(args.iter().map(|a| a.to_string()));
+        self
+    }
+
+    /// Target bitrate in kbps; ignored for lossless plans and when <= 0.
+    pub fn bitrate(mut self, kbps: i32) -> Self {
+        self.bitrate = Some(kbps).filter(|b| *b > 0);
+        self
+    }
+
+    /// Muxer for `-f`; defaults to the plan's.
+    pub fn muxer(mut self, muxer: &str) -> Self {
+        self.muxer = Some(muxer.to_string());
+        self
+    }
+
+    /// Any other output option, e.g. `-ss 5` or `-id3v2_version 3`.
+    pub fn output_args(mut self, args: &[&str]) -> Self {
+        self.output_args.extend(args.iter().map(|a| a.to_string()));
+        self
+    }
+
+    /// Container tag (`-metadata key=value`); blank values are skipped.
+    pub fn metadata(mut self, key: &str, value: Option<&str>) -> Self {
+        if let Some(v) = value.filter(|v| !v.trim().is_empty()) {
+            self.metadata.push((key.to_string(), v.to_string()));
+        }
+        self
+    }
+
+    /// Name used in error messages ("ffmpeg <label> failed").
+    pub fn label(mut self, label: &str) -> Self {
+        self.label = Some(label.to_string());
+        self
+    }
+
+    /// Leave this run out of the job's progress (intermediate passes).
+    pub fn untracked(mut self) -> Self {
+        self.opts.untracked = true;
+        self
+    }
+
+    /// Observe this run's progress (fraction 0..=1).
+    pub fn on_progress(mut self, hook: impl Fn(f32) + Send + Sync + 'static) -> Self {
+        self.opts.hook = Some(Arc::new(hook) as ProgressHook);
+        self
+    }
+
+    /// Kill the run after `limit` instead of the global timeout.
+    pub fn timeout(mut self, limit: Duration) -> Self {
+        self.opts.timeout = Some(limit);
+        self
+    }
+
+    /// The planned output extension, if an encode plan was set.
+    pub fn out_ext(&self) -> Option<&'static str> {
+        self.plan.as_ref().map(|p| p.out_ext)
+    }
+
+    fn command(&self, out_path: &Path) -> AudioResult<Command> {
+        let mut cmd = Command::new("ffmpeg");
+        cmd.args(["-y", "-hide_banner", "-loglevel", "error"]);
+        for (args, path) in &self.inputs {
+            cmd.args(args);
+            cmd.args(["-i", path]);
+        }
+        for spec in &self.maps {
+            cmd.args(["-map", spec]);
+        }
+        if !self.filters.is_empty() {
+            cmd.args(["-af", &self.filters.join(",")]);
+        }
+        if let Some(plan) = &self.plan {
+            cmd.args(plan.codec_args);
+        }
+        cmd.args(&self.codec);
+        let bitrate_ok = self.plan.as_ref().is_none_or(|p| p.supports_bitrate);
+        if let Some(kbps) = self.bitrate.filter(|_| bitrate_ok) {
+            cmd.args(["-b:a", &format!("{}k", kbps)]);
+        }
+        cmd.args(&self.output_args);
+        for (key, value) in &self.metadata {
+            cmd.args(["-metadata", &format!("{}={}", key, value)]);
+        }
+        let muxer = self
+            .muxer
+            .as_deref()
+            .or(self.plan.as_ref().map(|p| p.muxer));
+        if let Some(muxer) = muxer {
+            cmd.args(["-f", muxer]);
+        }
+        cmd.arg(path_arg(out_path)?);
+        Ok(cmd)
+    }
+
+    /// Run ffmpeg, writing the result to `out_path`.
+    pub fn run(self, out_path: &Path) -> AudioResult<()> {
+        if self.inputs.is_empty() {
+            return Err(AudioError::InvalidArgument(
+                "ffmpeg job has no input".into(),
+            ));
+        }
+
+        let mut cmd = self.command(out_path)?;
+        let output =
+            process::run(&mut cmd, &self.opts).map_err(|e| AudioError::exec("ffmpeg", e))?;
+        if !output.status.success() {
+            let context = match &self.label {
+                Some(label) => format!("ffmpeg {} failed", label),
+                None => "ffmpeg failed".to_string(),
+            };
+            return Err(AudioError::failed(&context, &output));
+        }
+
+        Ok(())
+    }
+}
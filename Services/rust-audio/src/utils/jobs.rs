@@ -0,0 +1,200 @@
+use crate::audio::{AudioResponse, JobState, JobStatus, ProgressEvent};
+use crate::utils::process::{CancelToken, JobContext, with_job};
+use crate::utils::progress::ProgressTracker;
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::{BuildHasher, RandomState};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, watch};
+use tonic::Status;
+
+pub type JobFuture = Pin<Box<dyn Future<Output = Result<AudioResponse, Status>> + Send>>;
+
+/// Finished jobs whose result was never fetched are dropped after this long.
+const RESULT_TTL: Duration = Duration::from_secs(60 * 60);
+
+struct Job {
+    state: JobState,
+    error: String,
+    result: Option<AudioResponse>,
+    ctx: JobContext,
+    finished_at: Option<Instant>,
+}
+
+impl Job {
+    fn set_state(&mut self, state: JobState) {
+        self.state = state;
+        self.ctx.progress.set_state(state);
+    }
+
+    fn finish(&mut self, state: JobState) {
+        self.set_state(state);
+        self.finished_at = Some(Instant::now());
+    }
+
+    fn status(&self, id: &str) -> JobStatus {
+        JobStatus {
+            job_id: id.to_string(),
+            state: self.state as i32,
+            error: self.error.clone(),
+        }
+    }
+}
+
+type Jobs = Arc<Mutex<HashMap<String, Job>>>;
+
+/// In-process FIFO of submitted jobs, drained by a fixed number of workers.
+#[derive(Clone)]
+pub struct JobQueue {
+    jobs: Jobs,
+    tx: mpsc::UnboundedSender<(String, JobFuture)>,
+}
+
+impl JobQueue {
+    /// Start `workers` worker tasks on the current tokio runtime.
+    pub fn new(workers: usize) -> Self {
+        let jobs: Jobs = Arc::default();
+        let (tx, rx) = mpsc::unbounded_channel::<(String, JobFuture)>();
+        let rx = Arc::new(tokio::sync::Mutex::new(rx));
+
+        for _ in 0..workers.max(1) {
+            let jobs = jobs.clone();
+            let rx = rx.clone();
+            tokio::spawn(async move {
+                loop {
+                    let next = rx.lock().await.recv().await;
+                    let Some((id, work)) = next else { break };
+                    run_job(&jobs, id, work).await;
+                }
+            });
+        }
+
+        Self { jobs, tx }
+    }
+
+    pub fn submit(&self, work: JobFuture) -> Result<JobStatus, Status> {
+        let id = new_job_id();
+        let job = Job {
+            state: JobState::Queued,
+            error: String::new(),
+            result: None,
+            ctx: JobContext {
+                cancel: CancelToken::default(),
+                progress: Arc::new(ProgressTracker::new(&id)),
+                file: None,
+            },
+            finished_at: None,
+        };
+
+        let status = {
+            let mut jobs = self.jobs.lock().unwrap();
+            jobs.retain(|_, j| j.finished_at.is_none_or(|t| t.elapsed() < RESULT_TTL));
+            let status = job.status(&id);
+            jobs.insert(id.clone(), job);
+            status
+        };
+
+        self.tx
+            .send((id, work))
+            .map_err(|_| Status::unavailable("job queue is shut down"))?;
+        Ok(status)
+    }
+
+    pub fn status(&self, id: &str) -> Result<JobStatus, Status> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.get(id)
+            .map(|j| j.status(id))
+            .ok_or_else(|| unknown_job(id))
+    }
+
+    /// Mark the job cancelled and kill its ffmpeg child if it is running.
+    /// Cancelling a finished job is a no-op.
+    pub fn cancel(&self, id: &str) -> Result<JobStatus, Status> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = jobs.get_mut(id).ok_or_else(|| unknown_job(id))?;
+        if matches!(job.state, JobState::Queued | JobState::Running) {
+            job.ctx.cancel.cancel();
+            job.finish(JobState::Cancelled);
+        }
+        Ok(job.status(id))
+    }
+
+    /// Subscribe to progress updates of a job.
+    pub fn watch(&self, id: &str) -> Result<watch::Receiver<ProgressEvent>, Status> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.get(id)
+            .map(|j| j.ctx.progress.subscribe())
+            .ok_or_else(|| unknown_job(id))
+    }
+
+    /// Hand out the result of a successful job; the job is forgotten afterwards.
+    pub fn take_result(&self, id: &str) -> Result<AudioResponse, Status> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = jobs.get(id).ok_or_else(|| unknown_job(id))?;
+        match job.state {
+            JobState::Succeeded => {}
+            JobState::Failed => {
+                return Err(Status::failed_precondition(format!(
+                    "job failed: {}",
+                    job.error
+                )));
+            }
+            JobState::Cancelled => return Err(Status::failed_precondition("job was cancelled")),
+            _ => return Err(Status::failed_precondition("job has not finished yet")),
+        }
+
+        jobs.remove(id)
+            .and_then(|j| j.result)
+            .ok_or_else(|| Status::internal("job result missing"))
+    }
+}
+
+async fn run_job(jobs: &Jobs, id: String, work: JobFuture) {
+    let ctx = {
+        let mut jobs = jobs.lock().unwrap();
+        match jobs.get_mut(&id) {
+            Some(job) if job.state == JobState::Queued => {
+                job.set_state(JobState::Running);
+                job.ctx.clone()
+            }
+            // cancelled (or expired) while it was waiting in the queue
+            _ => return,
+        }
+    };
+
+    println!("Job {} started", id);
+    let result = with_job(ctx, work).await;
+
+    let mut jobs = jobs.lock().unwrap();
+    let Some(job) = jobs.get_mut(&id) else {
+        return;
+    };
+    if job.state == JobState::Cancelled {
+        println!("Job {} cancelled", id);
+        return;
+    }
+    match result {
+        Ok(resp) => {
+            job.result = Some(resp);
+            job.finish(JobState::Succeeded);
+        }
+        Err(status) => {
+            job.error = status.message().to_string();
+            job.finish(JobState::Failed);
+        }
+    }
+    println!("Job {} finished", id);
+}
+
+fn unknown_job(id: &str) -> Status {
+    Status::not_found(format!("unknown job id: {}", id))
+}
+
+/// Random, unguessable job id (two randomly seeded SipHash outputs).
+fn new_job_id() -> String {
+    let a = RandomState::new().hash_one(Instant::now());
+    let b = RandomState::new().hash_one(a);
+    format!("{:016x}{:016x}", a, b)
+}
@@ -0,0 +1,179 @@
+use crate::utils::loudness::{TRUE_PEAK_TAPS, TruePeak};
+use crate::utils::native::PcmSpec;
+use std::collections::VecDeque;
+
+/// How far ahead the limiter looks, and so how long its gain ramps take.
+const LOOKAHEAD_MS: u32 = 5;
+/// Time constant for the gain to recover after a peak.
+const RELEASE_MS: f64 = 50.0;
+
+/// Look-ahead true-peak limiter over interleaved f32 frames.
+///
+/// Each frame's required gain (ceiling / true peak) goes through a sliding
+/// minimum, an exponential release and a boxcar average as long as the
+/// look-ahead. Because the minimum window covers the whole average plus the
+/// interpolator's lag, the averaged gain never exceeds what the frame it is
+/// applied to needs, while still ramping smoothly into every peak.
+pub struct Limiter {
+    channels: usize,
+    ceiling: f64,
+    lookahead: usize,
+    release: f64,
+    detector: TruePeak,
+    /// (frame index, required gain), increasing gains: a monotonic queue for
+    /// the sliding minimum.
+    minimum: VecDeque<(u64, f64)>,
+    window: u64,
+    held: f64,
+    average: VecDeque<f64>,
+    sum: f64,
+    delay: VecDeque<f32>,
+    frames: u64,
+    /// Lowest gain applied, as a linear factor.
+    min_gain: f64,
+}
+
+impl Limiter {
+    /// `ceiling_db` is the highest true peak the output may reach, in dBTP.
+    pub fn new(spec: PcmSpec, ceiling_db: f64) -> Self {
+        let lookahead = (spec.sample_rate * LOOKAHEAD_MS / 1000).max(1) as usize;
+        let release_frames = RELEASE_MS / 1000.0 * spec.sample_rate as f64;
+        Self {
+            channels: spec.channels as usize,
+            ceiling: 10f64.powf(ceiling_db / 20.0),
+            lookahead,
+            release: 1.0 - (-1.0 / release_frames).exp(),
+            detector: TruePeak::new(spec.sample_rate, spec.channels as usize),
+            minimum: VecDeque::new(),
+            window: (lookahead + TRUE_PEAK_TAPS) as u64,
+            held: 1.0,
+            average: VecDeque::from(vec![1.0; lookahead]),
+            sum: lookahead as f64,
+            delay: VecDeque::new(),
+            frames: 0,
+            min_gain: 1.0,
+        }
+    }
+
+    /// Frames held back before the first output frame.
+    fn latency(&self) -> usize {
+        self.lookahead - 1 + TRUE_PEAK_TAPS
+    }
+
+    /// Limit `samples`, appending whatever frames are ready to `out`.
+    pub fn process(&mut self, samples: &[f32], out: &mut Vec<f32>) {
+        for frame in samples.chunks_exact(self.channels) {
+            let sample_peak = frame.iter().fold(0.0f64, |m, s| m.max(s.abs() as f64));
+            let peak = self.detector.push(frame).max(sample_peak);
+            self.step(peak, frame, out);
+        }
+    }
+
+    /// Flush the frames still in the look-ahead buffer.
+    pub fn finish(&mut self, out: &mut Vec<f32>) {
+        let silence = vec![0.0; self.channels];
+        for _ in 0..self.latency() {
+            let peak = self.detector.push(&silence);
+            self.step(peak, &silence, out);
+        }
+    }
+
+    /// Whether any frame had to be turned down.
+    pub fn engaged(&self) -> bool {
+        self.min_gain < 1.0 - 1e-9
+    }
+
+    /// Advance by one frame whose (true) peak is `peak`.
+    fn step(&mut self, peak: f64, frame: &[f32], out: &mut Vec<f32>) {
+        let required = if peak > self.ceiling {
+            self.ceiling / peak
+        } else {
+            1.0
+        };
+        let n = self.frames;
+        self.frames += 1;
+
+        while self.minimum.back().is_some_and(|(_, g)| *g >= required) {
+            self.minimum.pop_back();
+        }
+        self.minimum.push_back((n, required));
+        while self
+            .minimum
+            .front()
+            .is_some_and(|(i, _)| i + self.window <= n)
+        {
+            self.minimum.pop_front();
+        }
+        let target = self.minimum.front().map_or(1.0, |(_, g)| *g);
+
+        self.held = target.min(self.held + (1.0 - self.held) * self.release);
+        self.sum += self.held - self.average.pop_front().unwrap_or(1.0);
+        self.average.push_back(self.held);
+        let gain = (self.sum / self.lookahead as f64).min(1.0);
+
+        self.delay.extend(frame);
+        if self.delay.len() > self.latency() * self.channels {
+            self.min_gain = self.min_gain.min(gain);
+            out.extend(
+                self.delay
+                    .drain(..self.channels)
+                    .map(|s| (s as f64 * gain) as f32),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::loudness::LoudnessMeter;
+    use crate::utils::test_wav::sine;
+
+    const SPEC: PcmSpec = PcmSpec {
+        sample_rate: 48_000,
+        channels: 2,
+    };
+
+    fn limit(input: &[f32], ceiling_db: f64) -> (Vec<f32>, bool) {
+        let mut limiter = Limiter::new(SPEC, ceiling_db);
+        let mut out = Vec::new();
+        // Odd block sizes so frames straddle calls.
+        for block in input.chunks(2 * 317) {
+            limiter.process(block, &mut out);
+        }
+        limiter.finish(&mut out);
+        (out, limiter.engaged())
+    }
+
+    fn tone(hz: f64, amplitude: f64) -> Vec<f32> {
+        let wave = sine(hz, amplitude, SPEC.sample_rate);
+        (0..SPEC.sample_rate)
+            .flat_map(|i| [wave(i, 0), wave(i, 1)])
+            .collect()
+    }
+
+    #[test]
+    fn output_stays_under_ceiling() {
+        // A 997 Hz tone and a near-Nyquist one, whose peaks fall between samples.
+        for hz in [997.0, 11_025.0] {
+            let input = tone(hz, 2.0);
+            let (out, engaged) = limit(&input, -1.0);
+            assert!(engaged);
+            assert_eq!(out.len(), input.len());
+
+            let mut meter = LoudnessMeter::new(SPEC);
+            meter.push(&out);
+            let l = meter.finish();
+            assert!(l.true_peak <= -1.0 + 0.01, "{} Hz: {}", hz, l.true_peak);
+            assert!(l.sample_peak <= -1.0 + 0.01, "{} Hz: {}", hz, l.sample_peak);
+        }
+    }
+
+    #[test]
+    fn quiet_input_passes_unchanged() {
+        let input = tone(440.0, 0.5);
+        let (out, engaged) = limit(&input, -1.0);
+        assert!(!engaged);
+        assert_eq!(out, input);
+    }
+}
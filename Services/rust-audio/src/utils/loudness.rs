@@ -0,0 +1,471 @@
+use crate::utils::error::{AudioError, AudioResult};
+use crate::utils::native::{Decoder, PcmSpec};
+use std::f64::consts::PI;
+use std::fs;
+use std::path::Path;
+use tempfile::Builder;
+
+/// BS.1770 blocks are built from 100 ms steps.
+const STEPS_PER_SECOND: u32 = 10;
+/// Momentary window (400 ms) and short-term window (3 s), in steps.
+const MOMENTARY_STEPS: usize = 4;
+const SHORT_TERM_STEPS: usize = 30;
+/// Absolute gate shared by integrated loudness and LRA.
+const ABSOLUTE_GATE: f64 = -70.0;
+const INTEGRATED_RELATIVE_GATE: f64 = -10.0;
+const RANGE_RELATIVE_GATE: f64 = -20.0;
+/// Taps per polyphase branch of the true-peak interpolator.
+pub(crate) const TRUE_PEAK_TAPS: usize = 12;
+
+/// EBU R128 measurement of one file (or of several files measured together).
+/// Loudness values are in LUFS, peaks in dBFS / dBTP; silence yields `-inf`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Loudness {
+    pub integrated: f64,
+    pub short_term_max: f64,
+    pub momentary_max: f64,
+    /// Loudness range in LU.
+    pub range: f64,
+    pub sample_peak: f64,
+    pub true_peak: f64,
+    /// Unweighted RMS level over all channels, dBFS.
+    pub rms: f64,
+    /// Relative gate the integrated loudness was computed above (LUFS).
+    pub threshold: f64,
+}
+
+/// ReplayGain 2.0 reference loudness.
+pub const REPLAYGAIN_REFERENCE: f64 = -18.0;
+
+/// ReplayGain 2.0 values: gains in dB towards -18 LUFS, peaks as linear
+/// sample peak (1.0 = full scale).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReplayGain {
+    pub track_gain: f64,
+    pub track_peak: f64,
+    pub album_gain: Option<f64>,
+    pub album_peak: Option<f64>,
+}
+
+impl ReplayGain {
+    pub fn new(track: &Loudness, album: Option<&Loudness>) -> Self {
+        let gain = |l: &Loudness| REPLAYGAIN_REFERENCE - l.integrated;
+        let peak = |l: &Loudness| 10f64.powf(l.sample_peak / 20.0);
+        Self {
+            track_gain: gain(track),
+            track_peak: peak(track),
+            album_gain: album.map(gain),
+            album_peak: album.map(peak),
+        }
+    }
+}
+
+fn to_lufs(energy: f64) -> f64 {
+    -0.691 + 10.0 * energy.log10()
+}
+
+fn to_db(linear: f64) -> f64 {
+    20.0 * linear.log10()
+}
+
+/// Transposed direct form II biquad.
+#[derive(Clone)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    z: [f64; 2],
+}
+
+impl Biquad {
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.z[0];
+        self.z[0] = self.b[1] * x - self.a[0] * y + self.z[1];
+        self.z[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
+
+/// K-weighting (high shelf + RLB high-pass) for any sample rate, using the
+/// analog prototype parameters from BS.1770 rather than the 48 kHz table.
+fn k_weighting(sample_rate: u32) -> [Biquad; 2] {
+    let fs = sample_rate as f64;
+
+    let f0 = 1681.974450955533;
+    let gain = 3.999843853973347;
+    let q = 0.7071752369554196;
+    let k = (PI * f0 / fs).tan();
+    let vh = 10f64.powf(gain / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad {
+        b: [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        z: [0.0; 2],
+    };
+
+    let f0 = 38.13547087602444;
+    let q = 0.5003270373238773;
+    let k = (PI * f0 / fs).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let highpass = Biquad {
+        b: [1.0, -2.0, 1.0],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        z: [0.0; 2],
+    };
+
+    [shelf, highpass]
+}
+
+/// BS.1770 channel weights: surrounds count +1.5 dB, LFE is ignored.
+fn channel_weights(channels: u16) -> Vec<f64> {
+    match channels {
+        5 => vec![1.0, 1.0, 1.0, 1.41, 1.41],
+        6 => vec![1.0, 1.0, 1.0, 0.0, 1.41, 1.41],
+        n => vec![1.0; n as usize],
+    }
+}
+
+/// Oversampling interpolator for true-peak detection (BS.1770 Annex 2):
+/// 4x below 96 kHz, 2x below 192 kHz, none above.
+pub(crate) struct TruePeak {
+    factor: usize,
+    /// `phases[p][k]` weights input sample `n - k` for output phase `p`.
+    phases: Vec<Vec<f64>>,
+    history: Vec<Vec<f64>>,
+    pos: usize,
+    peak: f64,
+}
+
+impl TruePeak {
+    pub(crate) fn new(sample_rate: u32, channels: usize) -> Self {
+        let factor = match sample_rate {
+            0..96_000 => 4,
+            96_000..192_000 => 2,
+            _ => 1,
+        };
+        let len = TRUE_PEAK_TAPS * factor;
+        let center = (len - 1) as f64 / 2.0;
+        let mut taps: Vec<f64> = (0..len)
+            .map(|n| {
+                let t = (n as f64 - center) / factor as f64;
+                let sinc = if t == 0.0 {
+                    1.0
+                } else {
+                    (PI * t).sin() / (PI * t)
+                };
+                let x = 2.0 * PI * n as f64 / (len - 1) as f64;
+                let blackman = 0.42 - 0.5 * x.cos() + 0.08 * (2.0 * x).cos();
+                sinc * blackman
+            })
+            .collect();
+        let sum: f64 = taps.iter().sum();
+        taps.iter_mut().for_each(|t| *t *= factor as f64 / sum);
+
+        let phases = (0..factor)
+            .map(|p| (0..TRUE_PEAK_TAPS).map(|k| taps[p + k * factor]).collect())
+            .collect();
+        Self {
+            factor,
+            phases,
+            history: vec![vec![0.0; TRUE_PEAK_TAPS]; channels],
+            pos: 0,
+            peak: 0.0,
+        }
+    }
+
+    /// Feed one frame; returns the highest interpolated magnitude around it,
+    /// which lags the input by up to `TRUE_PEAK_TAPS` frames.
+    pub(crate) fn push(&mut self, frame: &[f32]) -> f64 {
+        let mut frame_peak: f64 = 0.0;
+        if self.factor == 1 {
+            for s in frame {
+                frame_peak = frame_peak.max(s.abs() as f64);
+            }
+            self.peak = self.peak.max(frame_peak);
+            return frame_peak;
+        }
+        self.pos = (self.pos + TRUE_PEAK_TAPS - 1) % TRUE_PEAK_TAPS;
+        for (c, s) in frame.iter().enumerate() {
+            let history = &mut self.history[c];
+            history[self.pos] = *s as f64;
+            for phase in &self.phases {
+                let y: f64 = phase
+                    .iter()
+                    .enumerate()
+                    .map(|(k, h)| h * history[(self.pos + k) % TRUE_PEAK_TAPS])
+                    .sum();
+                frame_peak = frame_peak.max(y.abs());
+            }
+        }
+        self.peak = self.peak.max(frame_peak);
+        frame_peak
+    }
+}
+
+/// Incremental EBU R128 meter over interleaved f32 blocks.
+pub struct LoudnessMeter {
+    spec: PcmSpec,
+    channels: usize,
+    weights: Vec<f64>,
+    filters: Vec<[Biquad; 2]>,
+    step_len: usize,
+    step_fill: usize,
+    step_sums: Vec<f64>,
+    /// Weighted mean-square energy of every completed 100 ms step.
+    steps: Vec<f64>,
+    sample_peak: f64,
+    square_sum: f64,
+    samples: u64,
+    true_peak: TruePeak,
+}
+
+impl LoudnessMeter {
+    pub fn new(spec: PcmSpec) -> Self {
+        let channels = spec.channels as usize;
+        Self {
+            spec,
+            channels,
+            weights: channel_weights(spec.channels),
+            filters: vec![k_weighting(spec.sample_rate); channels],
+            step_len: (spec.sample_rate / STEPS_PER_SECOND).max(1) as usize,
+            step_fill: 0,
+            step_sums: vec![0.0; channels],
+            steps: Vec::new(),
+            sample_peak: 0.0,
+            square_sum: 0.0,
+            samples: 0,
+            true_peak: TruePeak::new(spec.sample_rate, channels),
+        }
+    }
+
+    pub fn spec(&self) -> PcmSpec {
+        self.spec
+    }
+
+    pub fn push(&mut self, samples: &[f32]) {
+        for frame in samples.chunks_exact(self.channels) {
+            for (c, s) in frame.iter().enumerate() {
+                self.sample_peak = self.sample_peak.max(s.abs() as f64);
+                self.square_sum += (*s as f64) * (*s as f64);
+                let [shelf, highpass] = &mut self.filters[c];
+                let y = highpass.process(shelf.process(*s as f64));
+                self.step_sums[c] += y * y;
+            }
+            self.true_peak.push(frame);
+            self.samples += frame.len() as u64;
+
+            self.step_fill += 1;
+            if self.step_fill == self.step_len {
+                let energy: f64 = self
+                    .step_sums
+                    .iter()
+                    .zip(&self.weights)
+                    .map(|(sum, w)| w * sum / self.step_len as f64)
+                    .sum();
+                self.steps.push(energy);
+                self.step_sums.iter_mut().for_each(|s| *s = 0.0);
+                self.step_fill = 0;
+            }
+        }
+    }
+
+    /// Sliding-window energies, one per step once the window is full. Audio
+    /// shorter than one window still yields a single (zero-padded) block.
+    fn windows(&self, len: usize) -> Vec<f64> {
+        if self.steps.is_empty() {
+            return Vec::new();
+        }
+        if self.steps.len() < len {
+            return vec![self.steps.iter().sum::<f64>() / len as f64];
+        }
+        self.steps
+            .windows(len)
+            .map(|w| w.iter().sum::<f64>() / len as f64)
+            .collect()
+    }
+
+    pub fn finish(&self) -> Loudness {
+        Self::combine(&[self])
+    }
+
+    /// Measure several meters as one programme (e.g. an album): blocks are
+    /// gated together, peaks and RMS are taken over all of them.
+    pub fn combine(meters: &[&LoudnessMeter]) -> Loudness {
+        let momentary: Vec<f64> = meters
+            .iter()
+            .flat_map(|m| m.windows(MOMENTARY_STEPS))
+            .collect();
+        let short_term: Vec<f64> = meters
+            .iter()
+            .flat_map(|m| m.windows(SHORT_TERM_STEPS))
+            .collect();
+        let max = |blocks: &[f64]| to_lufs(blocks.iter().copied().fold(0.0, f64::max));
+        let sample_peak = meters.iter().map(|m| m.sample_peak).fold(0.0, f64::max);
+        let true_peak = meters.iter().map(|m| m.true_peak.peak).fold(0.0, f64::max);
+        let square_sum: f64 = meters.iter().map(|m| m.square_sum).sum();
+        let samples: u64 = meters.iter().map(|m| m.samples).sum();
+
+        let (integrated, threshold) = integrated(&momentary);
+        Loudness {
+            integrated,
+            short_term_max: max(&short_term),
+            momentary_max: max(&momentary),
+            range: loudness_range(&short_term),
+            sample_peak: to_db(sample_peak),
+            true_peak: to_db(true_peak.max(sample_peak)),
+            rms: 10.0 * (square_sum / samples.max(1) as f64).log10(),
+            threshold,
+        }
+    }
+}
+
+/// Relative threshold (`relative` LU below the mean of the blocks above the
+/// absolute gate) and the blocks above it; empty when nothing passes.
+fn gate(blocks: &[f64], relative: f64) -> (f64, Vec<f64>) {
+    let above: Vec<f64> = blocks
+        .iter()
+        .copied()
+        .filter(|e| to_lufs(*e) > ABSOLUTE_GATE)
+        .collect();
+    if above.is_empty() {
+        return (ABSOLUTE_GATE, above);
+    }
+    let threshold = to_lufs(above.iter().sum::<f64>() / above.len() as f64) + relative;
+    let gated = above
+        .into_iter()
+        .filter(|e| to_lufs(*e) > threshold)
+        .collect();
+    (threshold, gated)
+}
+
+/// Gated integrated loudness of 400 ms block energies, with its relative
+/// threshold.
+fn integrated(blocks: &[f64]) -> (f64, f64) {
+    let (threshold, gated) = gate(blocks, INTEGRATED_RELATIVE_GATE);
+    if gated.is_empty() {
+        return (f64::NEG_INFINITY, threshold);
+    }
+    (
+        to_lufs(gated.iter().sum::<f64>() / gated.len() as f64),
+        threshold,
+    )
+}
+
+/// EBU Tech 3342 loudness range: 10th to 95th percentile of the gated
+/// short-term loudness distribution.
+fn loudness_range(short_term: &[f64]) -> f64 {
+    let mut gated: Vec<f64> = gate(short_term, RANGE_RELATIVE_GATE)
+        .1
+        .into_iter()
+        .map(to_lufs)
+        .collect();
+    if gated.len() < 2 {
+        return 0.0;
+    }
+    gated.sort_by(f64::total_cmp);
+    let at = |p: f64| gated[((gated.len() - 1) as f64 * p).round() as usize];
+    at(0.95) - at(0.10)
+}
+
+/// Feed the whole of `in_path` through a fresh meter.
+pub fn meter_path(in_path: &Path) -> AudioResult<LoudnessMeter> {
+    let mut dec = Decoder::open(in_path)?;
+    let mut meter = LoudnessMeter::new(dec.spec());
+    while let Some(block) = dec.next_block()? {
+        meter.push(block);
+    }
+    Ok(meter)
+}
+
+pub fn loudness_path(in_path: &Path) -> AudioResult<Loudness> {
+    meter_path(in_path).map(|meter| meter.finish())
+}
+
+/// In-memory variant of `loudness_path`; `ext` helps the decoder sniff.
+pub fn loudness_file(input_bytes: Vec<u8>, ext: &str) -> AudioResult<Loudness> {
+    let tmp_in = Builder::new()
+        .suffix(&format!(".{}", ext))
+        .tempfile()
+        .map_err(AudioError::io("tmpfile in"))?;
+    fs::write(tmp_in.path(), &input_bytes).map_err(AudioError::io("write tmp in"))?;
+    let in_path = tmp_in.into_temp_path();
+
+    loudness_path(&in_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_wav::sine;
+
+    const RATE: u32 = 48_000;
+
+    /// Meter fed `segments` of (seconds, peak dBFS) of a 1 kHz sine.
+    fn sine_meter(channels: u16, segments: &[(u32, f64)]) -> Loudness {
+        let mut meter = LoudnessMeter::new(PcmSpec {
+            sample_rate: RATE,
+            channels,
+        });
+        let mut offset = 0;
+        for &(seconds, level) in segments {
+            let wave = sine(1000.0, 10f64.powf(level / 20.0), RATE);
+            let frames = seconds * RATE;
+            let block: Vec<f32> = (offset..offset + frames)
+                .flat_map(|i| (0..channels).map(move |c| (i, c)))
+                .map(|(i, c)| wave(i, c))
+                .collect();
+            meter.push(&block);
+            offset += frames;
+        }
+        meter.finish()
+    }
+
+    #[test]
+    fn integrated_matches_tech_3341() {
+        // Cases 1 and 2: stereo 1 kHz sine at -23 and -33 dBFS. Durations
+        // are shortened to keep debug test runs quick.
+        let l = sine_meter(2, &[(10, -23.0)]);
+        assert!((l.integrated + 23.0).abs() <= 0.1, "{}", l.integrated);
+        let l = sine_meter(2, &[(10, -33.0)]);
+        assert!((l.integrated + 33.0).abs() <= 0.1, "{}", l.integrated);
+
+        // Case 3: quiet lead-in and tail fall below the relative gate.
+        let l = sine_meter(2, &[(5, -36.0), (20, -23.0), (5, -36.0)]);
+        assert!((l.integrated + 23.0).abs() <= 0.1, "{}", l.integrated);
+        assert!((l.momentary_max + 23.0).abs() <= 0.1);
+    }
+
+    #[test]
+    fn range_matches_tech_3342() {
+        // Case 1: -20 dBFS followed by -30 dBFS (10 s each instead of 20 s).
+        let l = sine_meter(2, &[(10, -20.0), (10, -30.0)]);
+        assert!((l.range - 10.0).abs() <= 1.0, "{}", l.range);
+    }
+
+    #[test]
+    fn true_peak_finds_inter_sample_peaks() {
+        // fs/4 sine at 45 degrees: every sample lands 3 dB below the peak.
+        let mut meter = LoudnessMeter::new(PcmSpec {
+            sample_rate: RATE,
+            channels: 1,
+        });
+        let block: Vec<f32> = (0..RATE)
+            .map(|i| (0.5 * (PI / 2.0 * i as f64 + PI / 4.0).sin()) as f32)
+            .collect();
+        meter.push(&block);
+        let l = meter.finish();
+
+        let expected = 20.0 * 0.5f64.log10();
+        assert!((l.sample_peak - (expected - 3.01)).abs() < 0.05);
+        // Tech 3341 tolerance for true peak: +0.2 / -0.4 dB.
+        assert!(
+            (expected - 0.4..=expected + 0.2).contains(&l.true_peak),
+            "{}",
+            l.true_peak
+        );
+    }
+}
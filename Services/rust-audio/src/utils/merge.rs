@@ -1,88 +1,409 @@
-use crate::utils::conversion::convert_file;
-use std::{fs, io::Write, path::Path, process::Command};
-use tempfile::NamedTempFile;
+use crate::utils::boost::DEFAULT_CEILING;
+use crate::utils::error::{AudioError, AudioResult};
+use crate::utils::fade::FadeCurve;
+use crate::utils::limiter::Limiter;
+use crate::utils::loudness::meter_path;
+use crate::utils::native::{Decoder, PcmWriter};
+use crate::utils::progress;
+use std::collections::VecDeque;
+use std::{fs, path::Path};
+use tempfile::{Builder, NamedTempFile};
+
+/// How one input leads into the next.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Join {
+    /// Overlap of the two inputs; shortened when either is shorter.
+    pub crossfade_ms: u32,
+    pub curve: FadeCurve,
+    /// Silence inserted between the two; excludes a crossfade.
+    pub gap_ms: u32,
+}
+
+/// Per-join options and loudness matching of a merge.
+#[derive(Debug, Clone, Default)]
+pub struct MergeOptions {
+    /// `joins[i]` sits between input `i` and `i + 1`; a single entry applies
+    /// to every join and none means back to back.
+    pub joins: Vec<Join>,
+    /// Bring every input to the same integrated loudness first.
+    pub match_loudness: bool,
+    /// Loudness to match to, in LUFS; the quietest input's when unset.
+    pub target_lufs: Option<f64>,
+}
+
+impl MergeOptions {
+    fn validate(&self, inputs: usize) -> AudioResult<()> {
+        let joins = inputs.saturating_sub(1);
+        if self.joins.len() > 1 && self.joins.len() != joins {
+            return Err(AudioError::InvalidArgument(format!(
+                "expected 1 or {} joins for {} inputs, got {}",
+                joins,
+                inputs,
+                self.joins.len()
+            )));
+        }
+        if let Some(i) = self
+            .joins
+            .iter()
+            .position(|j| j.crossfade_ms > 0 && j.gap_ms > 0)
+        {
+            return Err(AudioError::InvalidArgument(format!(
+                "join {}: set either crossfade_ms or gap_ms, not both",
+                i
+            )));
+        }
+        if let Some(target) = self.target_lufs
+            && !(-70.0..=-5.0).contains(&target)
+        {
+            return Err(AudioError::InvalidArgument(
+                "target_lufs must be between -70 and -5".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// The join after input `i`.
+    fn join(&self, i: usize) -> Join {
+        match self.joins.as_slice() {
+            [one] => *one,
+            joins => joins.get(i).copied().unwrap_or_default(),
+        }
+    }
+}
 
 fn ext_of(name: &str) -> Option<&str> {
     Path::new(name).extension().and_then(|e| e.to_str())
 }
 
 /// Merge sequentially and encode to the requested `output_format`.
-/// Supports AAC (.aac), M4A (AAC), and ALAC (.m4a) via convert_file's plan.
-/// Bitrate is fixed to 0 here (meaning "default"), but you can add a parameter if needed.
+/// Inputs are decoded in-process and appended sample by sample; WAV output is
+/// written directly, any other format is encoded once by ffmpeg.
 pub fn merge_sequential(
     inputs: Vec<(String, Vec<u8>)>,
     output_format: &str,
-) -> Result<Vec<u8>, String> {
+    opts: &MergeOptions,
+) -> AudioResult<Vec<u8>> {
     if inputs.is_empty() {
-        return Err("no inputs".into());
+        return Err(AudioError::InvalidArgument("no inputs".into()));
     }
 
-    // 1) Convert all inputs to WAV using the shared convert_file (pass original ext for sniffing)
-    let mut wav_paths: Vec<tempfile::TempPath> = Vec::new();
+    // 1) Spool inputs to disk, keeping the original ext so decoders can sniff it
+    let mut in_paths: Vec<tempfile::TempPath> = Vec::new();
     for (name, data) in inputs {
-        let in_ext = ext_of(&name);
-        let wav_bytes = convert_file(data, "wav", 0, in_ext)?; // 0 bitrate = default
-        let tmp = NamedTempFile::new().map_err(|e| format!("tmpfile out: {}", e))?;
-        fs::write(tmp.path(), &wav_bytes).map_err(|e| format!("write tmp out: {}", e))?;
-        wav_paths.push(tmp.into_temp_path());
+        let tmp = match ext_of(&name) {
+            Some(ext) => Builder::new().suffix(&format!(".{}", ext)).tempfile(),
+            None => NamedTempFile::new(),
+        }
+        .map_err(AudioError::io("tmpfile in"))?;
+        fs::write(tmp.path(), &data).map_err(AudioError::io("write tmp in"))?;
+        in_paths.push(tmp.into_temp_path());
     }
 
-    // 2) Build concat list file
-    let list_file = NamedTempFile::new().map_err(|e| format!("concat list tmp: {}", e))?;
+    let out = NamedTempFile::new().map_err(AudioError::io("tmpfile out"))?;
+    let out_path = out.into_temp_path();
+    merge_paths(&in_paths, &out_path, output_format, opts)?;
+
+    fs::read(&out_path).map_err(AudioError::io("read merged output"))
+}
+
+/// Merged output: the encoder, behind a limiter when loudness matching
+/// boosts an input.
+struct Output {
+    writer: PcmWriter,
+    limiter: Option<Limiter>,
+    buf: Vec<f32>,
+}
+
+impl Output {
+    fn write(&mut self, samples: &[f32]) -> AudioResult<()> {
+        match &mut self.limiter {
+            Some(limiter) => {
+                self.buf.clear();
+                limiter.process(samples, &mut self.buf);
+                self.writer.write(&self.buf)
+            }
+            None => self.writer.write(samples),
+        }
+    }
+
+    fn finish(mut self) -> AudioResult<()> {
+        if let Some(limiter) = &mut self.limiter {
+            self.buf.clear();
+            limiter.finish(&mut self.buf);
+            self.writer.write(&self.buf)?;
+        }
+        self.writer.finish()
+    }
+}
+
+/// Gain in dB that brings each input to the common loudness. Silent inputs
+/// are left alone.
+fn matching_gains<P: AsRef<Path>>(
+    inputs: &[P],
+    target: Option<f64>,
+    steps: usize,
+) -> AudioResult<Vec<f64>> {
+    let mut loudness = Vec::with_capacity(inputs.len());
+    for (i, input) in inputs.iter().enumerate() {
+        progress::begin_file(i, steps);
+        loudness.push(meter_path(input.as_ref())?.finish().integrated);
+    }
+    let target = target.unwrap_or_else(|| {
+        loudness
+            .iter()
+            .copied()
+            .filter(|l| l.is_finite())
+            .fold(f64::INFINITY, f64::min)
+    });
+    Ok(loudness
+        .into_iter()
+        .map(|l| {
+            if l.is_finite() && target.is_finite() {
+                target - l
+            } else {
+                0.0
+            }
+        })
+        .collect())
+}
+
+/// Read frames of `dec` (scaled by `gain`) until `frames` are buffered or
+/// the input ends.
+fn read_head(
+    dec: &mut Decoder,
+    gain: f32,
+    frames: usize,
+    channels: usize,
+) -> AudioResult<Vec<f32>> {
+    let mut head = Vec::new();
+    while head.len() < frames * channels {
+        let Some(block) = dec.next_block()? else {
+            break;
+        };
+        head.extend(block.iter().map(|s| s * gain));
+    }
+    Ok(head)
+}
+
+/// Mix the end of one input into the start of the next: `tail` fades out
+/// while `head` fades in over as many frames as both have. Returns the part
+/// of `head` past the overlap.
+fn crossfade(
+    out: &mut Output,
+    tail: &[f32],
+    mut head: Vec<f32>,
+    curve: FadeCurve,
+    channels: usize,
+) -> AudioResult<Vec<f32>> {
+    let overlap = tail.len().min(head.len()) / channels * channels;
+    let (plain, fading) = tail.split_at(tail.len() - overlap);
+    out.write(plain)?;
+
+    let frames = overlap / channels;
+    let mut mixed = Vec::with_capacity(overlap);
+    for (i, (a, b)) in fading
+        .chunks_exact(channels)
+        .zip(head.chunks_exact(channels))
+        .enumerate()
     {
-        let mut f = list_file
-            .as_file()
-            .try_clone()
-            .map_err(|e| format!("concat list open: {}", e))?;
-        for p in &wav_paths {
-            let path = p.to_str().ok_or("bad temp path")?;
-            // Escape single quotes for concat demuxer line format
-            writeln!(f, "file '{}'", path.replace('\'', "'\\''"))
-                .map_err(|e| format!("write concat list: {}", e))?;
+        let x = (i as f64 + 0.5) / frames as f64;
+        let fade_in = curve.gain(x) as f32;
+        let fade_out = curve.gain(1.0 - x) as f32;
+        mixed.extend(a.iter().zip(b).map(|(a, b)| a * fade_out + b * fade_in));
+    }
+    out.write(&mixed)?;
+    Ok(head.split_off(overlap))
+}
+
+/// Path-based variant of `merge_sequential`: inputs are already on disk.
+pub fn merge_paths<P: AsRef<Path>>(
+    inputs: &[P],
+    out_path: &Path,
+    output_format: &str,
+    opts: &MergeOptions,
+) -> AudioResult<()> {
+    if inputs.is_empty() {
+        return Err(AudioError::InvalidArgument("no inputs".into()));
+    }
+    opts.validate(inputs.len())?;
+
+    // Each input counts as one step of the job (two when its loudness is
+    // measured first), the final encode as the last one.
+    let measured = if opts.match_loudness { inputs.len() } else { 0 };
+    let steps = measured + inputs.len() + 1;
+    let gains = if opts.match_loudness {
+        matching_gains(inputs, opts.target_lufs, steps)?
+    } else {
+        vec![0.0; inputs.len()]
+    };
+
+    // The first input decides the output layout; others are converted to it.
+    progress::begin_file(measured, steps);
+    let mut dec = Decoder::open(inputs[0].as_ref())?;
+    let spec = dec.spec();
+    let channels = spec.channels as usize;
+    let frames = |ms: u32| (ms as u64 * spec.sample_rate as u64 / 1000) as usize;
+    let mut out = Output {
+        writer: PcmWriter::create(out_path, spec, output_format, 0)?,
+        limiter: gains
+            .iter()
+            .any(|g| *g > 0.0)
+            .then(|| Limiter::new(spec, DEFAULT_CEILING)),
+        buf: Vec::new(),
+    };
+
+    // End of the previous input, held back for a crossfade into this one
+    let mut tail: Vec<f32> = Vec::new();
+    for (i, input) in inputs.iter().enumerate() {
+        if i > 0 {
+            progress::begin_file(measured + i, steps);
+            dec = Decoder::open_as(input.as_ref(), spec)?;
         }
+        let gain = 10f32.powf(gains[i] as f32 / 20.0);
+
+        let mut head = Vec::new();
+        if i > 0 {
+            let join = opts.join(i - 1);
+            if tail.is_empty() {
+                out.write(&vec![0.0; frames(join.gap_ms) * channels])?;
+            } else {
+                let wanted = tail.len() / channels;
+                head = read_head(&mut dec, gain, wanted, channels)?;
+                head = crossfade(&mut out, &tail, head, join.curve, channels)?;
+            }
+        }
+
+        // Stream the rest, keeping back the frames the next join overlaps
+        let hold = if i + 1 < inputs.len() {
+            frames(opts.join(i).crossfade_ms) * channels
+        } else {
+            0
+        };
+        let mut pending: VecDeque<f32> = head.into();
+        loop {
+            if pending.len() > hold {
+                let ready: Vec<f32> = pending.drain(..pending.len() - hold).collect();
+                out.write(&ready)?;
+            }
+            let Some(block) = dec.next_block()? else {
+                break;
+            };
+            pending.extend(block.iter().map(|s| s * gain));
+        }
+        tail = pending.into();
+    }
+    out.write(&tail)?;
+
+    progress::begin_file(steps - 1, steps);
+    out.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::loudness::LoudnessMeter;
+    use crate::utils::native::PcmSpec;
+    use crate::utils::test_wav::{read_i16, sine, wav_f32, wav_i16};
+
+    const RATE: u32 = 8000;
+
+    fn merge(inputs: &[&Path], opts: MergeOptions) -> Vec<i16> {
+        let out = NamedTempFile::with_suffix(".wav").unwrap().into_temp_path();
+        merge_paths(inputs, &out, "wav", &opts).unwrap();
+        read_i16(&out)
+    }
+
+    fn joined(join: Join) -> MergeOptions {
+        MergeOptions {
+            joins: vec![join],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn crossfade_overlaps_inputs() {
+        let a = wav_i16(RATE, 1, RATE, |_, _| 1000);
+        let b = wav_i16(RATE, 1, RATE, |_, _| 3000);
+        let out = merge(
+            &[&a, &b],
+            joined(Join {
+                crossfade_ms: 100,
+                ..Default::default()
+            }),
+        );
+
+        // 800 frames of overlap are shared by both inputs.
+        assert_eq!(out.len(), 2 * RATE as usize - 800);
+        assert!(out[..7200].iter().all(|&s| s == 1000));
+        assert!(out[8000..].iter().all(|&s| s == 3000));
+        let fade = &out[7200..8000];
+        assert!(fade.windows(2).all(|w| w[0] <= w[1]));
+        assert!((1990..=2010).contains(&fade[400]));
+    }
+
+    #[test]
+    fn crossfade_is_shortened_to_the_shorter_input() {
+        let a = wav_i16(RATE, 1, RATE, |_, _| 1000);
+        let b = wav_i16(RATE, 1, 400, |_, _| 3000);
+        let out = merge(
+            &[&a, &b],
+            joined(Join {
+                crossfade_ms: 500,
+                ..Default::default()
+            }),
+        );
+        assert_eq!(out.len(), RATE as usize);
+    }
+
+    #[test]
+    fn gap_inserts_silence() {
+        let a = wav_i16(RATE, 2, RATE, |_, _| 1000);
+        let b = wav_i16(RATE, 2, RATE, |_, _| 3000);
+        let out = merge(
+            &[&a, &b],
+            joined(Join {
+                gap_ms: 250,
+                ..Default::default()
+            }),
+        );
+
+        let (frames, gap) = (RATE as usize, 2000);
+        assert_eq!(out.len(), 2 * (2 * frames + gap));
+        assert!(out[..2 * frames].iter().all(|&s| s == 1000));
+        assert!(out[2 * frames..2 * (frames + gap)].iter().all(|&s| s == 0));
+        assert!(out[2 * (frames + gap)..].iter().all(|&s| s == 3000));
+    }
+
+    #[test]
+    fn match_loudness_levels_inputs() {
+        let rate = 48_000;
+        let loud = wav_f32(rate, 1, 3 * rate, sine(1000.0, 0.5, rate));
+        let quiet = wav_f32(rate, 1, 3 * rate, sine(1000.0, 0.05, rate));
+        let out = merge(
+            &[&loud, &quiet],
+            MergeOptions {
+                match_loudness: true,
+                ..Default::default()
+            },
+        );
+
+        let measure = |samples: &[i16]| {
+            let mut meter = LoudnessMeter::new(PcmSpec {
+                sample_rate: rate,
+                channels: 1,
+            });
+            let block: Vec<f32> = samples.iter().map(|&s| s as f32 / 32768.0).collect();
+            meter.push(&block);
+            meter.finish().integrated
+        };
+        let (first, second) = out.split_at(out.len() / 2);
+        let (first, second) = (measure(first), measure(second));
+        // Both end up at the quieter input's level, 20 dB below the louder.
+        assert!((first - second).abs() < 0.2, "{} vs {}", first, second);
+        assert!(
+            (second - (20.0 * 0.05f64.log10() - 3.01)).abs() < 0.2,
+            "{}",
+            second
+        );
     }
-    let list_path = list_file.into_temp_path();
-
-    // 3) Concat WAVs -> single WAV
-    let merged_wav = NamedTempFile::new().map_err(|e| format!("tmpfile out: {}", e))?;
-    let merged_wav_path = merged_wav.into_temp_path();
-
-    // Safer to re-encode to a standard WAV (pcm_s16le) than try `-c copy`
-    let output = Command::new("ffmpeg")
-        .args([
-            "-y",
-            "-hide_banner",
-            "-loglevel",
-            "error",
-            "-f",
-            "concat",
-            "-safe",
-            "0",
-            "-i",
-            list_path.to_str().ok_or("list path utf-8")?,
-            "-c:a",
-            "pcm_s16le",
-            "-f",
-            "wav",
-            merged_wav_path.to_str().ok_or("bad merged_wav_path")?,
-        ])
-        .output()
-        .map_err(|e| format!("ffmpeg exec: {}", e))?;
-
-    if !output.status.success() {
-        return Err(format!(
-            "ffmpeg failed (concat): {}",
-            String::from_utf8_lossy(&output.stderr)
-        ));
-    }
-
-    // 4) Re-encode merged WAV to the requested output format using the shared plan
-    let merged_wav_bytes =
-        fs::read(&merged_wav_path).map_err(|e| format!("read merged wav: {}", e))?;
-
-    // Reuse convert_file so AAC/M4A/ALAC mapping works consistently.
-    // Pass Some("wav") so ffmpeg knows input type.
-    let final_bytes = convert_file(merged_wav_bytes, output_format, 0, Some("wav"))?;
-
-    Ok(final_bytes)
 }
@@ -1,20 +1,24 @@
+use crate::utils::error::{AudioError, AudioResult};
+use crate::utils::ffmpeg_job::FfmpegJob;
+use crate::utils::loudness::ReplayGain;
+use crate::utils::mp4_tags::set_freeform_tags;
 use std::fs;
-use std::process::Command;
+use std::path::Path;
 use tempfile::{Builder, NamedTempFile};
 
-enum CoverMode {
+pub(crate) enum CoverMode {
     None,
     Mp3Attached,  // ID3v2 attached picture
     Mp4CoverAtom, // MP4/M4A cover
 }
 
-struct MetaPlan {
-    muxer: &'static str,
-    extra_args: &'static [&'static str],
-    cover_mode: CoverMode,
+pub(crate) struct MetaPlan {
+    pub(crate) muxer: &'static str,
+    pub(crate) extra_args: &'static [&'static str],
+    pub(crate) cover_mode: CoverMode,
 }
 
-fn plan_for_meta(ext: &str) -> Result<MetaPlan, String> {
+pub(crate) fn plan_for_meta(ext: &str) -> AudioResult<MetaPlan> {
     match ext.to_ascii_lowercase().as_str() {
         "m4a" => Ok(MetaPlan {
             muxer: "mp4",
@@ -61,7 +65,9 @@ fn plan_for_meta(ext: &str) -> Result<MetaPlan, String> {
             extra_args: &[],
             cover_mode: CoverMode::None,
         }),
-        other => Err(format!("Unsupported extension for metadata: {other}")),
+        other => Err(AudioError::UnsupportedFormat(format!(
+            "Unsupported extension for metadata: {other}"
+        ))),
     }
 }
 
@@ -72,24 +78,13 @@ fn any_metadata_requested(
     year: &Option<String>,
     cover: &Option<Vec<u8>>,
 ) -> bool {
-    title.as_deref().map_or(false, |s| !s.trim().is_empty())
-        || artist.as_deref().map_or(false, |s| !s.trim().is_empty())
-        || album.as_deref().map_or(false, |s| !s.trim().is_empty())
-        || year.as_deref().map_or(false, |s| !s.trim().is_empty())
+    title.as_deref().is_some_and(|s| !s.trim().is_empty())
+        || artist.as_deref().is_some_and(|s| !s.trim().is_empty())
+        || album.as_deref().is_some_and(|s| !s.trim().is_empty())
+        || year.as_deref().is_some_and(|s| !s.trim().is_empty())
         || cover.is_some()
 }
 
-// helpers to build Vec<String> safely
-fn push(args: &mut Vec<String>, items: &[&str]) {
-    for s in items {
-        args.push((*s).to_string());
-    }
-}
-fn push_kv(args: &mut Vec<String>, k: &str, v: &str) {
-    args.push(k.to_string());
-    args.push(v.to_string());
-}
-
 pub fn write_metadata(
     input_bytes: Vec<u8>,
     ext: &str,
@@ -98,127 +93,199 @@ pub fn write_metadata(
     album: Option<String>,
     year: Option<String>,
     cover_art: Option<Vec<u8>>,
-) -> Result<Vec<u8>, String> {
-    // ADTS AAC cannot carry tags/cover
-    if ext.eq_ignore_ascii_case("aac")
-        && any_metadata_requested(&title, &artist, &album, &year, &cover_art)
-    {
-        return Err(
-            "Raw AAC (.aac/ADTS) does not support embedded metadata. Use .m4a instead.".into(),
-        );
-    }
-
-    let plan = plan_for_meta(ext)?;
-
+) -> AudioResult<Vec<u8>> {
     // 1) temp input
-    let tmp_in = NamedTempFile::new().map_err(|e| format!("tmpfile: {e}"))?;
-    fs::write(tmp_in.path(), &input_bytes).map_err(|e| format!("write tmp in: {e}"))?;
+    let tmp_in = NamedTempFile::new().map_err(AudioError::io("tmpfile"))?;
+    fs::write(tmp_in.path(), &input_bytes).map_err(AudioError::io("write tmp in"))?;
     let in_path = tmp_in.into_temp_path();
 
     // 2) temp output
     let tmp_out = Builder::new()
         .suffix(&format!(".{}", ext))
         .tempfile()
-        .map_err(|e| format!("tmpfile: {e}"))?;
+        .map_err(AudioError::io("tmpfile"))?;
     let out_path = tmp_out.into_temp_path();
 
-    // 3) build args
-    let mut args: Vec<String> = Vec::with_capacity(32);
-    push(
-        &mut args,
-        &["-y", "-hide_banner", "-loglevel", "error", "-i"],
-    );
-    args.push(in_path.to_str().ok_or("bad in_path")?.to_string());
+    write_metadata_path(
+        &in_path, &out_path, ext, title, artist, album, year, cover_art,
+    )?;
+
+    // 5) read result
+    let bytes = fs::read(&out_path).map_err(AudioError::io("read tmp out"))?;
+    Ok(bytes)
+}
+
+/// Path-based core of `write_metadata`.
+#[allow(clippy::too_many_arguments)]
+pub fn write_metadata_path(
+    in_path: &Path,
+    out_path: &Path,
+    ext: &str,
+    title: Option<String>,
+    artist: Option<String>,
+    album: Option<String>,
+    year: Option<String>,
+    cover_art: Option<Vec<u8>>,
+) -> AudioResult<()> {
+    // ADTS AAC cannot carry tags/cover
+    if ext.eq_ignore_ascii_case("aac")
+        && any_metadata_requested(&title, &artist, &album, &year, &cover_art)
+    {
+        return Err(AudioError::UnsupportedFormat(
+            "Raw AAC (.aac/ADTS) does not support embedded metadata. Use .m4a instead.".into(),
+        ));
+    }
+
+    let plan = plan_for_meta(ext)?;
+
+    // 3) build the job
+    let mut job = FfmpegJob::new().input(in_path)?;
 
     // Keep cover temp alive until after ffmpeg runs
     let mut cover_tmp: Option<NamedTempFile> = None;
-    let mut used_cover = false;
-
-    match (&plan.cover_mode, &cover_art) {
-        (CoverMode::Mp3Attached, Some(bytes)) => {
-            let ctmp = NamedTempFile::new().map_err(|e| format!("tmpfile cover: {e}"))?;
-            fs::write(ctmp.path(), bytes).map_err(|e| format!("write cover: {e}"))?;
-            push(&mut args, &["-i"]);
-            args.push(ctmp.path().to_str().ok_or("bad cover path")?.to_string());
-            push(
-                &mut args,
-                &[
-                    "-map",
-                    "0:a",
-                    "-map",
-                    "1:v",
-                    "-c:a",
-                    "copy",
-                    "-c:v",
-                    "mjpeg",
-                    "-disposition:v",
-                    "attached_pic",
-                ],
-            );
-            cover_tmp = Some(ctmp);
-            used_cover = true;
-        }
-        (CoverMode::Mp4CoverAtom, Some(bytes)) => {
-            let ctmp = NamedTempFile::new().map_err(|e| format!("tmpfile cover: {e}"))?;
-            fs::write(ctmp.path(), bytes).map_err(|e| format!("write cover: {e}"))?;
-            push(&mut args, &["-i"]);
-            args.push(ctmp.path().to_str().ok_or("bad cover path")?.to_string());
-            push(
-                &mut args,
-                &["-map", "0:a", "-map", "1:v", "-c:a", "copy", "-c:v", "copy"],
-            );
+    let cover_args: Option<&[&str]> = match plan.cover_mode {
+        CoverMode::Mp3Attached => Some(&["-c:v", "mjpeg", "-disposition:v", "attached_pic"]),
+        CoverMode::Mp4CoverAtom => Some(&["-c:v", "copy"]),
+        CoverMode::None => None,
+    };
+
+    match (cover_args, &cover_art) {
+        (Some(args), Some(bytes)) => {
+            let ctmp = NamedTempFile::new().map_err(AudioError::io("tmpfile cover"))?;
+            fs::write(ctmp.path(), bytes).map_err(AudioError::io("write cover"))?;
+            job = job
+                .input(ctmp.path())?
+                .map("0:a")
+                .map("1:v")
+                .codec(&["-c:a", "copy"])
+                .codec(args);
             cover_tmp = Some(ctmp);
-            used_cover = true;
         }
         _ => {
             // no cover or unsupported: copy audio-only to drop any video/subs
-            push(&mut args, &["-map", "0:a", "-c", "copy"]);
+            job = job.map("0:a").codec(&["-c", "copy"]);
         }
     }
+    let used_cover = cover_tmp.is_some();
 
-    // per-format extra flags
-    for s in plan.extra_args {
-        args.push(s.to_string());
-    }
+    // per-format extra flags, text metadata, muxer
+    let result = job
+        .output_args(plan.extra_args)
+        .metadata("title", title.as_deref())
+        .metadata("artist", artist.as_deref())
+        .metadata("album", album.as_deref())
+        .metadata("date", year.as_deref())
+        .muxer(plan.muxer)
+        .run(out_path);
 
-    // text metadata
-    if let Some(t) = title.filter(|s| !s.trim().is_empty()) {
-        push_kv(&mut args, "-metadata", &format!("title={}", t));
-    }
-    if let Some(a) = artist.filter(|s| !s.trim().is_empty()) {
-        push_kv(&mut args, "-metadata", &format!("artist={}", a));
-    }
-    if let Some(al) = album.filter(|s| !s.trim().is_empty()) {
-        push_kv(&mut args, "-metadata", &format!("album={}", al));
-    }
-    if let Some(y) = year.filter(|s| !s.trim().is_empty()) {
-        push_kv(&mut args, "-metadata", &format!("date={}", y));
+    drop(cover_tmp); // explicit, though it drops anyway here
+
+    match result {
+        Err(AudioError::ProcessingFailed { stderr, .. })
+            if used_cover
+                && ext.eq_ignore_ascii_case("m4a")
+                && stderr.contains("could not find tag for codec") =>
+        {
+            Err(AudioError::UnsupportedFormat(
+                "cover art format not allowed in MP4. Try JPEG or PNG.".into(),
+            ))
+        }
+        other => other,
     }
+}
 
-    // muxer + output
-    push(&mut args, &["-f", plan.muxer]);
-    args.push(out_path.to_str().ok_or("bad out_path")?.to_string());
+/// Opus gains (RFC 7845) are relative to -23 LUFS, ReplayGain's to -18.
+const R128_OFFSET_DB: f64 = -5.0;
 
-    // 4) run ffmpeg
-    let output = Command::new("ffmpeg")
-        .args(&args)
-        .output()
-        .map_err(|e| format!("Failed to run ffmpeg: {e}"))?;
+/// `REPLAYGAIN_*` tags in the usual text form ("-6.52 dB", "0.988553").
+fn replaygain_tags(rg: &ReplayGain) -> Vec<(&'static str, String)> {
+    let mut tags = vec![
+        ("REPLAYGAIN_TRACK_GAIN", format!("{:.2} dB", rg.track_gain)),
+        ("REPLAYGAIN_TRACK_PEAK", format!("{:.6}", rg.track_peak)),
+    ];
+    if let (Some(gain), Some(peak)) = (rg.album_gain, rg.album_peak) {
+        tags.push(("REPLAYGAIN_ALBUM_GAIN", format!("{:.2} dB", gain)));
+        tags.push(("REPLAYGAIN_ALBUM_PEAK", format!("{:.6}", peak)));
+    }
+    tags
+}
 
-    drop(cover_tmp); // explicit, though it drops anyway here
+/// Opus `R128_*_GAIN` tags: Q7.8 fixed point dB.
+fn r128_tags(rg: &ReplayGain) -> Vec<(&'static str, String)> {
+    let q78 = |gain: f64| {
+        ((gain + R128_OFFSET_DB) * 256.0)
+            .round()
+            .clamp(i16::MIN as f64, i16::MAX as f64)
+            .to_string()
+    };
+    let mut tags = vec![("R128_TRACK_GAIN", q78(rg.track_gain))];
+    if let Some(gain) = rg.album_gain {
+        tags.push(("R128_ALBUM_GAIN", q78(gain)));
+    }
+    tags
+}
 
-    if !output.status.success() {
-        let mut err = String::from_utf8_lossy(&output.stderr).to_string();
-        if used_cover
-            && ext.eq_ignore_ascii_case("m4a")
-            && err.contains("could not find tag for codec")
-        {
-            err.push_str("\nHint: some cover formats are not allowed in MP4. Try JPEG or PNG.");
-        }
-        return Err(format!("ffmpeg failed: {}", err));
+/// Store ReplayGain as tags, copying the audio stream untouched: ID3v2 TXXX
+/// frames for MP3, Vorbis comments for FLAC/Ogg, `R128_*` for Opus and
+/// iTunes freeform atoms for M4A.
+pub fn write_replaygain_path(
+    in_path: &Path,
+    out_path: &Path,
+    ext: &str,
+    rg: &ReplayGain,
+) -> AudioResult<()> {
+    let ext = ext.to_ascii_lowercase();
+    if ext == "m4a" {
+        // iTunes convention: lower-case names, e.g. replaygain_track_gain.
+        let tags: Vec<(String, String)> = replaygain_tags(rg)
+            .into_iter()
+            .map(|(key, value)| (key.to_ascii_lowercase(), value))
+            .collect();
+        return set_freeform_tags(in_path, out_path, &tags);
     }
 
-    // 5) read result
-    let bytes = fs::read(&out_path).map_err(|e| format!("read tmp out: {e}"))?;
-    Ok(bytes)
+    let plan = match ext.as_str() {
+        "mp3" | "flac" | "ogg" | "opus" => plan_for_meta(&ext)?,
+        other => {
+            return Err(AudioError::UnsupportedFormat(format!(
+                "ReplayGain tags are not supported for .{} (use mp3, flac, ogg, opus or m4a)",
+                other
+            )));
+        }
+    };
+
+    let job = FfmpegJob::new()
+        .input(in_path)?
+        .codec(&["-c", "copy"])
+        .output_args(plan.extra_args)
+        .muxer(plan.muxer)
+        .label("replaygain");
+    let job = match ext.as_str() {
+        // Cover art streams are copied along.
+        "mp3" | "flac" => replaygain_tags(rg)
+            .into_iter()
+            .fold(job.map("0"), |job, (key, value)| {
+                job.metadata(key, Some(&value))
+            }),
+        // Ogg keeps comments on the stream; Opus wants R128 gains instead of
+        // (not alongside) ReplayGain, so stale ones are cleared.
+        _ => {
+            let tags: Vec<_> = if ext == "opus" {
+                r128_tags(rg)
+                    .into_iter()
+                    .chain(
+                        replaygain_tags(rg)
+                            .into_iter()
+                            .map(|(key, _)| (key, String::new())),
+                    )
+                    .collect()
+            } else {
+                replaygain_tags(rg)
+            };
+            tags.into_iter().fold(job.map("0:a"), |job, (key, value)| {
+                job.output_args(&["-metadata:s:a:0", &format!("{}={}", key, value)])
+            })
+        }
+    };
+    job.run(out_path)
 }
@@ -1,9 +1,30 @@
+pub mod batch;
 pub mod boost;
 pub mod compress;
 pub mod conversion;
+pub mod error;
+pub mod fade;
 pub mod ffmpeg;
+pub mod ffmpeg_job;
+pub mod jobs;
+pub mod limiter;
+pub mod loudness;
 pub mod merge;
 pub mod metadata;
+pub mod mp4_tags;
+pub mod native;
+pub mod pipeline;
+pub mod pool;
+pub mod probe;
+pub mod process;
+pub mod progress;
+pub mod silence;
+pub mod spectrogram;
+pub mod split;
+pub mod stream;
 pub mod temp;
+#[cfg(test)]
+pub mod test_wav;
 pub mod trim;
+pub mod waveform;
 pub mod zip;
@@ -0,0 +1,379 @@
+use crate::utils::error::{AudioError, AudioResult};
+use std::fs;
+use std::path::Path;
+
+/// Boxes we descend into on the way to `ilst` and to the chunk offset tables.
+const CONTAINERS: &[&[u8; 4]] = &[
+    b"moov", b"trak", b"mdia", b"minf", b"stbl", b"udta", b"meta", b"ilst",
+];
+/// Namespace of iTunes-style freeform (`----`) atoms.
+const FREEFORM_MEAN: &str = "com.apple.iTunes";
+
+enum Body {
+    Leaf(Vec<u8>),
+    /// `prefix` holds the version/flags of full-box containers (`meta`).
+    Container {
+        prefix: Vec<u8>,
+        children: Vec<Atom>,
+    },
+}
+
+struct Atom {
+    kind: [u8; 4],
+    body: Body,
+}
+
+fn malformed(what: &str) -> AudioError {
+    AudioError::DecodeFailed {
+        message: format!("malformed MP4: {}", what),
+        stderr: String::new(),
+    }
+}
+
+/// Type, header length and total size of the box starting at `pos`.
+fn box_header(data: &[u8], pos: usize) -> AudioResult<([u8; 4], usize, usize)> {
+    let head = data
+        .get(pos..pos + 8)
+        .ok_or_else(|| malformed("truncated box"))?;
+    let size = u32::from_be_bytes(head[0..4].try_into().unwrap()) as usize;
+    let kind: [u8; 4] = head[4..8].try_into().unwrap();
+    let (header, size) = match size {
+        0 => (8, data.len() - pos),
+        1 => {
+            let large = data
+                .get(pos + 8..pos + 16)
+                .ok_or_else(|| malformed("truncated box"))?;
+            let large = u64::from_be_bytes(large.try_into().unwrap());
+            (16, usize::try_from(large).unwrap_or(usize::MAX))
+        }
+        n => (8, n),
+    };
+    // The size is untrusted: it must not run past the data
+    if size < header || pos.checked_add(size).is_none_or(|end| end > data.len()) {
+        return Err(malformed("box size out of range"));
+    }
+    Ok((kind, header, size))
+}
+
+fn parse(data: &[u8]) -> AudioResult<Vec<Atom>> {
+    let mut atoms = Vec::new();
+    let mut pos = 0;
+    while pos + 8 <= data.len() {
+        let (kind, header, size) = box_header(data, pos)?;
+        let body = &data[pos + header..pos + size];
+        atoms.push(parse_atom(kind, body)?);
+        pos += size;
+    }
+    Ok(atoms)
+}
+
+fn parse_atom(kind: [u8; 4], body: &[u8]) -> AudioResult<Atom> {
+    if !CONTAINERS.contains(&&kind) {
+        return Ok(Atom {
+            kind,
+            body: Body::Leaf(body.to_vec()),
+        });
+    }
+    // ISO `meta` is a full box; QuickTime's starts straight with its children.
+    let prefix_len = if &kind == b"meta" && body.get(0..4) == Some(&[0; 4]) {
+        4
+    } else {
+        0
+    };
+    Ok(Atom {
+        kind,
+        body: Body::Container {
+            prefix: body[..prefix_len].to_vec(),
+            children: parse(&body[prefix_len..])?,
+        },
+    })
+}
+
+impl Atom {
+    fn leaf(kind: &[u8; 4], body: Vec<u8>) -> Self {
+        Self {
+            kind: *kind,
+            body: Body::Leaf(body),
+        }
+    }
+
+    fn container(kind: &[u8; 4], prefix: Vec<u8>, children: Vec<Atom>) -> Self {
+        Self {
+            kind: *kind,
+            body: Body::Container { prefix, children },
+        }
+    }
+
+    fn size(&self) -> usize {
+        let body = match &self.body {
+            Body::Leaf(data) => data.len(),
+            Body::Container { prefix, children } => {
+                prefix.len() + children.iter().map(Atom::size).sum::<usize>()
+            }
+        };
+        if body + 8 > u32::MAX as usize {
+            body + 16
+        } else {
+            body + 8
+        }
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        let size = self.size();
+        if size > u32::MAX as usize {
+            out.extend_from_slice(&1u32.to_be_bytes());
+            out.extend_from_slice(&self.kind);
+            out.extend_from_slice(&(size as u64).to_be_bytes());
+        } else {
+            out.extend_from_slice(&(size as u32).to_be_bytes());
+            out.extend_from_slice(&self.kind);
+        }
+        match &self.body {
+            Body::Leaf(data) => out.extend_from_slice(data),
+            Body::Container { prefix, children } => {
+                out.extend_from_slice(prefix);
+                for child in children {
+                    child.write(out);
+                }
+            }
+        }
+    }
+
+    fn children_mut(&mut self) -> Option<&mut Vec<Atom>> {
+        match &mut self.body {
+            Body::Container { children, .. } => Some(children),
+            Body::Leaf(_) => None,
+        }
+    }
+
+    /// Child container `kind`, created by `make` when missing.
+    fn child_or_insert(&mut self, kind: &[u8; 4], make: impl FnOnce() -> Atom) -> &mut Atom {
+        let children = self.children_mut().expect("container");
+        let index = match children.iter().position(|a| &a.kind == kind) {
+            Some(i) => i,
+            None => {
+                children.push(make());
+                children.len() - 1
+            }
+        };
+        &mut children[index]
+    }
+
+    /// Name of a `----` atom (the payload of its `name` child).
+    fn freeform_name(&self) -> Option<String> {
+        let Body::Leaf(data) = &self.body else {
+            return None;
+        };
+        if &self.kind != b"----" {
+            return None;
+        }
+        let mut pos = 0;
+        while let Ok((kind, header, size)) = box_header(data, pos) {
+            if &kind == b"name" {
+                let name = data.get(pos + header + 4..pos + size)?;
+                return Some(String::from_utf8_lossy(name).into_owned());
+            }
+            pos += size;
+        }
+        None
+    }
+
+    /// Add `delta` to every chunk offset that points at or past `from`.
+    fn shift_chunk_offsets(&mut self, from: u64, delta: i64) -> AudioResult<()> {
+        match &mut self.body {
+            Body::Container { children, .. } => {
+                for child in children {
+                    child.shift_chunk_offsets(from, delta)?;
+                }
+            }
+            Body::Leaf(data) if &self.kind == b"stco" || &self.kind == b"co64" => {
+                let width = if &self.kind == b"stco" { 4 } else { 8 };
+                let count = data
+                    .get(4..8)
+                    .map(|c| u32::from_be_bytes(c.try_into().unwrap()) as usize)
+                    .ok_or_else(|| malformed("truncated chunk offset table"))?;
+                if data.len() < 8 + count * width {
+                    return Err(malformed("truncated chunk offset table"));
+                }
+                for entry in data[8..8 + count * width].chunks_exact_mut(width) {
+                    let offset = if width == 4 {
+                        u32::from_be_bytes(entry.try_into().unwrap()) as u64
+                    } else {
+                        u64::from_be_bytes(entry.try_into().unwrap())
+                    };
+                    if offset < from {
+                        continue;
+                    }
+                    let shifted = offset
+                        .checked_add_signed(delta)
+                        .ok_or_else(|| malformed("chunk offset out of range"))?;
+                    if width == 4 {
+                        let shifted = u32::try_from(shifted).map_err(|_| {
+                            AudioError::UnsupportedFormat(
+                                "MP4 chunk offsets overflow 32 bits after tagging".into(),
+                            )
+                        })?;
+                        entry.copy_from_slice(&shifted.to_be_bytes());
+                    } else {
+                        entry.copy_from_slice(&shifted.to_be_bytes());
+                    }
+                }
+            }
+            Body::Leaf(_) => {}
+        }
+        Ok(())
+    }
+}
+
+fn full_box(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(12 + payload.len());
+    out.extend_from_slice(&(12 + payload.len() as u32).to_be_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(&[0; 4]);
+    out.extend_from_slice(payload);
+    out
+}
+
+/// `----` atom holding one UTF-8 value under the iTunes namespace.
+fn freeform(name: &str, value: &str) -> Atom {
+    let mut body = full_box(b"mean", FREEFORM_MEAN.as_bytes());
+    body.extend(full_box(b"name", name.as_bytes()));
+    // data: type 1 (UTF-8), locale 0
+    body.extend_from_slice(&(16 + value.len() as u32).to_be_bytes());
+    body.extend_from_slice(b"data");
+    body.extend_from_slice(&1u32.to_be_bytes());
+    body.extend_from_slice(&[0; 4]);
+    body.extend_from_slice(value.as_bytes());
+    Atom::leaf(b"----", body)
+}
+
+/// `hdlr` announcing an iTunes metadata list, required inside `meta`.
+fn metadata_handler() -> Atom {
+    let mut body = vec![0; 8];
+    body.extend_from_slice(b"mdirappl");
+    body.extend_from_slice(&[0; 9]);
+    Atom::leaf(b"hdlr", body)
+}
+
+/// Copy `in_path` to `out_path` with `tags` stored as freeform atoms in
+/// `moov/udta/meta/ilst`, replacing atoms of the same name. The media data
+/// is copied byte for byte; only chunk offsets move if `moov` grows in front
+/// of it.
+pub fn set_freeform_tags(
+    in_path: &Path,
+    out_path: &Path,
+    tags: &[(String, String)],
+) -> AudioResult<()> {
+    let data = fs::read(in_path).map_err(AudioError::io("read mp4"))?;
+
+    let mut pos = 0;
+    let mut moov = None;
+    while pos + 8 <= data.len() {
+        let (kind, header, size) = box_header(&data, pos)?;
+        if &kind == b"moov" {
+            moov = Some((pos, header, size));
+            break;
+        }
+        pos += size;
+    }
+    let (moov_start, header, old_size) = moov.ok_or_else(|| malformed("no moov box"))?;
+    let mut root = parse_atom(*b"moov", &data[moov_start + header..moov_start + old_size])?;
+
+    let ilst = root
+        .child_or_insert(b"udta", || Atom::container(b"udta", Vec::new(), Vec::new()))
+        .child_or_insert(b"meta", || {
+            Atom::container(b"meta", vec![0; 4], vec![metadata_handler()])
+        })
+        .child_or_insert(b"ilst", || Atom::container(b"ilst", Vec::new(), Vec::new()))
+        .children_mut()
+        .expect("ilst is a container");
+    ilst.retain(|atom| {
+        atom.freeform_name()
+            .is_none_or(|name| !tags.iter().any(|(key, _)| key.eq_ignore_ascii_case(&name)))
+    });
+    for (key, value) in tags {
+        ilst.push(freeform(key, value));
+    }
+
+    let delta = root.size() as i64 - old_size as i64;
+    root.shift_chunk_offsets((moov_start + old_size) as u64, delta)?;
+
+    let mut out = Vec::with_capacity((data.len() as i64 + delta) as usize);
+    out.extend_from_slice(&data[..moov_start]);
+    root.write(&mut out);
+    out.extend_from_slice(&data[moov_start + old_size..]);
+    fs::write(out_path, out).map_err(AudioError::io("write mp4"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    fn boxed(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut out = (8 + body.len() as u32).to_be_bytes().to_vec();
+        out.extend_from_slice(kind);
+        out.extend_from_slice(body);
+        out
+    }
+
+    /// `ftyp`, a `moov` whose `stco` points at the media, then `mdat`.
+    fn mp4(media: &[u8]) -> Vec<u8> {
+        let ftyp = boxed(b"ftyp", b"M4A \0\0\0\0");
+        let stco = |offset: u32| {
+            let mut body = vec![0; 4];
+            body.extend_from_slice(&1u32.to_be_bytes());
+            body.extend_from_slice(&offset.to_be_bytes());
+            boxed(b"stco", &body)
+        };
+        let moov = |offset| {
+            let stbl = boxed(b"stbl", &stco(offset));
+            let minf = boxed(b"minf", &stbl);
+            let mdia = boxed(b"mdia", &minf);
+            boxed(b"moov", &boxed(b"trak", &mdia))
+        };
+        let offset = (ftyp.len() + moov(0).len() + 8) as u32;
+        [ftyp, moov(offset), boxed(b"mdat", media)].concat()
+    }
+
+    fn tag(data: &[u8]) -> AudioResult<Vec<u8>> {
+        let input = NamedTempFile::new().unwrap().into_temp_path();
+        let output = NamedTempFile::new().unwrap().into_temp_path();
+        fs::write(&input, data).unwrap();
+        let tags = [("REPLAYGAIN_TRACK_GAIN".to_string(), "-3.50 dB".to_string())];
+        set_freeform_tags(&input, &output, &tags)?;
+        Ok(fs::read(&output).unwrap())
+    }
+
+    #[test]
+    fn shifts_chunk_offsets_past_the_grown_moov() {
+        let media = b"media bytes";
+        let out = tag(&mp4(media)).unwrap();
+
+        let at = |needle: &[u8]| out.windows(needle.len()).position(|w| w == needle).unwrap();
+        let stco = at(b"stco");
+        let offset = u32::from_be_bytes(out[stco + 12..stco + 16].try_into().unwrap()) as usize;
+        assert_eq!(&out[offset..offset + media.len()], media);
+        assert!(at(b"REPLAYGAIN_TRACK_GAIN") < at(b"mdat"));
+        assert!(at(b"-3.50 dB") < at(b"mdat"));
+    }
+
+    #[test]
+    fn rejects_out_of_range_box_sizes() {
+        let mut data = mp4(b"media");
+        let moov = data.windows(4).position(|w| w == b"moov").unwrap() - 4;
+
+        // Truncated: moov claims more than the file holds
+        let mut truncated = data.clone();
+        truncated[moov..moov + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(matches!(
+            tag(&truncated),
+            Err(AudioError::DecodeFailed { .. })
+        ));
+
+        // Oversized 64-bit size that would overflow `pos + size`
+        data[moov..moov + 4].copy_from_slice(&1u32.to_be_bytes());
+        data.splice(moov + 8..moov + 8, u64::MAX.to_be_bytes());
+        assert!(matches!(tag(&data), Err(AudioError::DecodeFailed { .. })));
+    }
+}
@@ -0,0 +1,437 @@
+use crate::utils::error::{AudioError, AudioResult};
+use crate::utils::fade::FadeWriter;
+use crate::utils::ffmpeg_job::{FfmpegJob, plan_for};
+use crate::utils::process;
+use hound::{SampleFormat, WavSpec, WavWriter};
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{CODEC_TYPE_NULL, Decoder as CodecDecoder, DecoderOptions};
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::{FormatOptions, FormatReader};
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+use tempfile::{NamedTempFile, TempPath};
+
+/// How many decoded blocks pass between two cancellation checks.
+const CANCEL_CHECK_BLOCKS: usize = 64;
+
+/// Layout of decoded audio; samples are always interleaved f32.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PcmSpec {
+    pub sample_rate: u32,
+    pub channels: u16,
+}
+
+/// Streaming in-process decoder. Formats symphonia cannot read are decoded
+/// by ffmpeg into a temporary float WAV first, so callers always get PCM.
+pub struct Decoder {
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn CodecDecoder>,
+    track_id: u32,
+    spec: PcmSpec,
+    buf: Vec<f32>,
+    /// First block, decoded by `open` to learn the spec.
+    pending: bool,
+    blocks: usize,
+    /// Corrupt packets skipped, logged once when the decoder is dropped.
+    skipped: usize,
+    // Keeps the ffmpeg fallback output alive while we read it.
+    _fallback: Option<TempPath>,
+}
+
+impl Decoder {
+    /// Open `path`, natively when possible and through ffmpeg otherwise.
+    pub fn open(path: &Path) -> AudioResult<Self> {
+        match Self::open_native(path) {
+            Ok(dec) => Ok(dec),
+            Err(AudioError::UnsupportedFormat(_) | AudioError::DecodeFailed { .. }) => {
+                Self::open_ffmpeg(path, None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Open `path` resampled/remixed to `spec` (ffmpeg does the conversion
+    /// when the source does not already match).
+    pub fn open_as(path: &Path, spec: PcmSpec) -> AudioResult<Self> {
+        match Self::open(path) {
+            Ok(dec) if dec.spec == spec => Ok(dec),
+            Ok(_) => Self::open_ffmpeg(path, Some(spec)),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Decode with symphonia only.
+    pub fn open_native(path: &Path) -> AudioResult<Self> {
+        let file = File::open(path).map_err(AudioError::io("open input"))?;
+        let mss = MediaSourceStream::new(Box::new(file), Default::default());
+
+        let mut hint = Hint::new();
+        if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
+            hint.with_extension(ext);
+        }
+
+        // Gapless trims encoder delay/padding, so sample positions match the source.
+        let format_opts = FormatOptions {
+            enable_gapless: true,
+            ..Default::default()
+        };
+        let probed = symphonia::default::get_probe()
+            .format(&hint, mss, &format_opts, &MetadataOptions::default())
+            .map_err(decode_error)?;
+        let format = probed.format;
+
+        let track = format
+            .tracks()
+            .iter()
+            .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+            .ok_or_else(|| AudioError::DecodeFailed {
+                message: "no audio track found".into(),
+                stderr: String::new(),
+            })?;
+        let track_id = track.id;
+        let decoder = symphonia::default::get_codecs()
+            .make(&track.codec_params, &DecoderOptions::default())
+            .map_err(decode_error)?;
+
+        let mut dec = Self {
+            format,
+            decoder,
+            track_id,
+            spec: PcmSpec {
+                sample_rate: 0,
+                channels: 0,
+            },
+            buf: Vec::new(),
+            pending: false,
+            blocks: 0,
+            skipped: 0,
+            _fallback: None,
+        };
+
+        // The spec is only reliable once a packet has been decoded.
+        if dec.decode_next()? {
+            dec.pending = true;
+            Ok(dec)
+        } else {
+            Err(AudioError::DecodeFailed {
+                message: "no audio decoded".into(),
+                stderr: String::new(),
+            })
+        }
+    }
+
+    /// Let ffmpeg decode (and optionally convert) to float WAV, then read that.
+    fn open_ffmpeg(path: &Path, spec: Option<PcmSpec>) -> AudioResult<Self> {
+        let tmp = NamedTempFile::with_suffix(".wav").map_err(AudioError::io("tmpfile decode"))?;
+        let wav_path = tmp.into_temp_path();
+
+        let mut job = FfmpegJob::new()
+            .input(path)?
+            .map("0:a:0")
+            .codec(&["-c:a", "pcm_f32le"]);
+        if let Some(spec) = spec {
+            job = job.output_args(&[
+                "-ar",
+                &spec.sample_rate.to_string(),
+                "-ac",
+                &spec.channels.to_string(),
+            ]);
+        }
+        job.muxer("wav").label("decode").run(&wav_path)?;
+
+        let mut dec = Self::open_native(&wav_path)?;
+        dec._fallback = Some(wav_path);
+        Ok(dec)
+    }
+
+    pub fn spec(&self) -> PcmSpec {
+        self.spec
+    }
+
+    /// Next block of interleaved samples, `None` at the end of the stream.
+    pub fn next_block(&mut self) -> AudioResult<Option<&[f32]>> {
+        if self.pending {
+            self.pending = false;
+        } else if !self.decode_next()? {
+            return Ok(None);
+        }
+        Ok(Some(&self.buf))
+    }
+
+    /// Whether the stream has no more frames. Decodes ahead by one block,
+    /// which the next `next_block` returns.
+    pub fn at_end(&mut self) -> AudioResult<bool> {
+        if !self.pending {
+            self.pending = self.decode_next()?;
+        }
+        Ok(!self.pending)
+    }
+
+    /// Decode the next non-empty packet into `buf`; false at end of stream.
+    fn decode_next(&mut self) -> AudioResult<bool> {
+        loop {
+            self.blocks += 1;
+            if self.blocks.is_multiple_of(CANCEL_CHECK_BLOCKS) && process::is_cancelled() {
+                return Err(AudioError::Cancelled);
+            }
+
+            let packet = match self.format.next_packet() {
+                Ok(packet) => packet,
+                Err(SymphoniaError::IoError(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    return Ok(false);
+                }
+                // Chained streams (e.g. concatenated Ogg) end the first track here.
+                Err(SymphoniaError::ResetRequired) => return Ok(false),
+                Err(e) => return Err(decode_error(e)),
+            };
+            if packet.track_id() != self.track_id {
+                continue;
+            }
+
+            let decoded = match self.decoder.decode(&packet) {
+                Ok(decoded) => decoded,
+                // A corrupt frame is skipped, like ffmpeg does.
+                Err(SymphoniaError::DecodeError(_)) => {
+                    self.skipped += 1;
+                    continue;
+                }
+                Err(e) => return Err(decode_error(e)),
+            };
+            if decoded.frames() == 0 {
+                continue;
+            }
+
+            let spec = *decoded.spec();
+            let pcm = PcmSpec {
+                sample_rate: spec.rate,
+                channels: spec.channels.count() as u16,
+            };
+            // Callers size their output by the first spec; interleaving a
+            // different layout into it would garble the audio.
+            if self.spec.channels != 0 && pcm != self.spec {
+                return Err(AudioError::DecodeFailed {
+                    message: format!(
+                        "native decoder: stream changes from {} Hz/{} ch to {} Hz/{} ch",
+                        self.spec.sample_rate, self.spec.channels, pcm.sample_rate, pcm.channels
+                    ),
+                    stderr: String::new(),
+                });
+            }
+            let mut samples = SampleBuffer::<f32>::new(decoded.capacity() as u64, spec);
+            samples.copy_interleaved_ref(decoded);
+            self.buf.clear();
+            self.buf.extend_from_slice(samples.samples());
+            self.spec = pcm;
+            return Ok(true);
+        }
+    }
+}
+
+impl Drop for Decoder {
+    fn drop(&mut self) {
+        if self.skipped > 0 {
+            println!("Skipped {} undecodable packets", self.skipped);
+        }
+    }
+}
+
+fn decode_error(err: SymphoniaError) -> AudioError {
+    match err {
+        SymphoniaError::Unsupported(what) => {
+            AudioError::UnsupportedFormat(format!("native decoder: unsupported {}", what))
+        }
+        other => AudioError::DecodeFailed {
+            message: format!("native decoder: {}", other),
+            stderr: String::new(),
+        },
+    }
+}
+
+/// Sink for decoded audio. WAV output is written directly with hound; any
+/// other format goes through a float WAV that ffmpeg encodes on `finish`.
+pub struct PcmWriter {
+    writer: WavWriter<BufWriter<File>>,
+    int16: bool,
+    encode: Option<Encode>,
+}
+
+/// Deferred ffmpeg encode of the intermediate WAV.
+struct Encode {
+    wav_path: TempPath,
+    out_path: PathBuf,
+    output_format: String,
+    bitrate: i32,
+    tags_from: Option<PathBuf>,
+}
+
+impl PcmWriter {
+    pub fn create(
+        out_path: &Path,
+        spec: PcmSpec,
+        output_format: &str,
+        bitrate: i32,
+    ) -> AudioResult<Self> {
+        let plan = plan_for(output_format)?;
+        if plan.out_ext == "wav" {
+            let writer = WavWriter::create(out_path, wav_spec(spec, true)).map_err(wav_error)?;
+            return Ok(Self {
+                writer,
+                int16: true,
+                encode: None,
+            });
+        }
+
+        let tmp = NamedTempFile::with_suffix(".wav").map_err(AudioError::io("tmpfile wav"))?;
+        let wav_path = tmp.into_temp_path();
+        let writer = WavWriter::create(&wav_path, wav_spec(spec, false)).map_err(wav_error)?;
+        Ok(Self {
+            writer,
+            int16: false,
+            encode: Some(Encode {
+                wav_path,
+                out_path: out_path.to_path_buf(),
+                output_format: output_format.to_string(),
+                bitrate,
+                tags_from: None,
+            }),
+        })
+    }
+
+    /// Copy the container tags of `source` into the encoded output (ignored
+    /// for WAV, which hound writes without tags).
+    pub fn tags_from(mut self, source: &Path) -> Self {
+        if let Some(encode) = &mut self.encode {
+            encode.tags_from = Some(source.to_path_buf());
+        }
+        self
+    }
+
+    /// Append interleaved samples.
+    pub fn write(&mut self, samples: &[f32]) -> AudioResult<()> {
+        if self.int16 {
+            for s in samples {
+                let v = (s.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16;
+                self.writer.write_sample(v).map_err(wav_error)?;
+            }
+        } else {
+            for s in samples {
+                self.writer.write_sample(*s).map_err(wav_error)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Finalize the WAV and, for other formats, encode it into the output.
+    pub fn finish(self) -> AudioResult<()> {
+        self.writer.finalize().map_err(wav_error)?;
+
+        let Some(encode) = self.encode else {
+            return Ok(());
+        };
+        let mut job = FfmpegJob::new().input(&encode.wav_path)?;
+        if let Some(source) = &encode.tags_from {
+            job = job
+                .input(source)?
+                .map("0:a")
+                .output_args(&["-map_metadata", "1"]);
+        }
+        job.encode(&encode.output_format)?
+            .bitrate(encode.bitrate)
+            .label("encode")
+            .run(&encode.out_path)
+    }
+}
+
+fn wav_spec(spec: PcmSpec, int16: bool) -> WavSpec {
+    WavSpec {
+        channels: spec.channels,
+        sample_rate: spec.sample_rate,
+        bits_per_sample: if int16 { 16 } else { 32 },
+        sample_format: if int16 {
+            SampleFormat::Int
+        } else {
+            SampleFormat::Float
+        },
+    }
+}
+
+fn wav_error(err: hound::Error) -> AudioError {
+    match err {
+        hound::Error::IoError(source) => AudioError::Io {
+            context: "write wav".into(),
+            source,
+        },
+        other => AudioError::ProcessingFailed {
+            message: format!("write wav: {}", other),
+            stderr: String::new(),
+        },
+    }
+}
+
+/// What `copy_ranges` did.
+#[derive(Debug, Clone, Copy)]
+pub struct Copied {
+    pub written: u64,
+    /// Frames decoded: the whole input unless `cut_out`.
+    pub read: u64,
+    /// The source went on past the last range, i.e. the output ends at a cut.
+    pub cut_out: bool,
+}
+
+/// Stream the frames of `dec` that fall in `ranges` (half-open, in frames,
+/// sorted and non-overlapping) into `out`, marking a cut wherever two ranges
+/// do not touch.
+pub fn copy_ranges(
+    dec: &mut Decoder,
+    out: &mut FadeWriter,
+    ranges: &[(u64, u64)],
+) -> AudioResult<Copied> {
+    let channels = dec.spec().channels as usize;
+    let mut pos: u64 = 0;
+    let mut written: u64 = 0;
+    let mut last: Option<usize> = None;
+
+    while let Some(block) = dec.next_block()? {
+        let frames = (block.len() / channels) as u64;
+        let block_end = pos + frames;
+        for (i, &(start, end)) in ranges.iter().enumerate() {
+            let from = start.max(pos);
+            let to = end.min(block_end);
+            if from < to {
+                if let Some(j) = last
+                    && j != i
+                    && ranges[j].1 != start
+                {
+                    out.cut()?;
+                }
+                last = Some(i);
+                let a = (from - pos) as usize * channels;
+                let b = (to - pos) as usize * channels;
+                out.write(&block[a..b])?;
+                written += to - from;
+            }
+        }
+        pos = block_end;
+        if let Some(&(_, end)) = ranges.last()
+            && pos >= end
+        {
+            // A range ending exactly at the end of the stream is no cut
+            let cut_out = pos > end || !dec.at_end()?;
+            return Ok(Copied {
+                written,
+                read: pos,
+                cut_out,
+            });
+        }
+    }
+
+    Ok(Copied {
+        written,
+        read: pos,
+        cut_out: false,
+    })
+}
@@ -0,0 +1,407 @@
+use crate::utils::boost::{LoudnessTarget, gain_filter, loudnorm_filter};
+use crate::utils::error::{AudioError, AudioResult};
+use crate::utils::fade::FadeCurve;
+use crate::utils::ffmpeg_job::FfmpegJob;
+use crate::utils::loudness::meter_path;
+use crate::utils::metadata::{CoverMode, plan_for_meta};
+use crate::utils::native::Decoder;
+use crate::utils::trim::{Range, frame_ranges};
+use std::fs;
+use std::path::Path;
+use tempfile::{NamedTempFile, TempPath};
+
+/// Tags written by a metadata step; later steps override earlier ones field by field.
+#[derive(Debug, Default, Clone)]
+pub struct Tags {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub year: Option<String>,
+    pub cover_art: Option<Vec<u8>>,
+}
+
+/// One operation of a pipeline, applied to the decoded audio in order.
+#[derive(Debug, Clone)]
+pub enum Step {
+    Trim {
+        ranges: Vec<Range>,
+        action: String, // "keep" or "remove"
+    },
+    Gain(i32),
+    Normalize(LoudnessTarget),
+    Convert {
+        output_format: String,
+        bitrate: i32,
+    },
+    Metadata(Tags),
+    Fade {
+        in_ms: u32,
+        out_ms: u32,
+        curve: FadeCurve,
+    },
+}
+
+/// Format the pipeline encodes to: the last convert step, else the input's.
+pub fn output_format(input_ext: &str, steps: &[Step]) -> String {
+    steps
+        .iter()
+        .rev()
+        .find_map(|s| match s {
+            Step::Convert { output_format, .. } => Some(output_format.to_ascii_lowercase()),
+            _ => None,
+        })
+        .unwrap_or_else(|| input_ext.to_ascii_lowercase())
+}
+
+pub fn pipeline_file(
+    input_bytes: Vec<u8>,
+    input_ext: &str,
+    steps: &[Step],
+) -> AudioResult<Vec<u8>> {
+    let tmp_in = NamedTempFile::new().map_err(AudioError::io("tmpfile in"))?;
+    fs::write(tmp_in.path(), &input_bytes).map_err(AudioError::io("write tmp in"))?;
+    let in_path = tmp_in.into_temp_path();
+
+    let tmp_out = NamedTempFile::new().map_err(AudioError::io("tmpfile out"))?;
+    let out_path = tmp_out.into_temp_path();
+
+    pipeline_path(&in_path, &out_path, input_ext, steps)?;
+
+    fs::read(&out_path).map_err(AudioError::io("read tmp out"))
+}
+
+/// Path-based core of `pipeline_file`: one ffmpeg run that decodes `in_path`,
+/// applies every step as an audio filter and encodes once into `out_path`.
+/// A normalize step needs the audio measured as it is at that step, so the
+/// steps before it are first rendered to a float WAV.
+pub fn pipeline_path(
+    in_path: &Path,
+    out_path: &Path,
+    input_ext: &str,
+    steps: &[Step],
+) -> AudioResult<()> {
+    if steps.is_empty() {
+        return Err(AudioError::InvalidArgument("pipeline has no steps".into()));
+    }
+
+    // Trims resolve positions to frames and fade-outs are placed relative to
+    // the end, so both need the exact length. Only decoding gives it; a probed
+    // duration is an estimate for VBR files. Zero when no step needs it.
+    let needs_length = steps.iter().any(|s| match s {
+        Step::Trim { .. } => true,
+        Step::Fade { out_ms, .. } => *out_ms > 0,
+        _ => false,
+    });
+    let (sample_rate, mut frames) = if needs_length {
+        decoded_length(in_path)?
+    } else {
+        (0, 0)
+    };
+
+    let mut filters: Vec<String> = Vec::new();
+    let mut rendered: Option<TempPath> = None;
+    let mut bitrate = 0;
+    let mut tags: Option<Tags> = None;
+
+    for (i, step) in steps.iter().enumerate() {
+        match step {
+            Step::Trim { ranges, action } => {
+                // No step changes the source's rate
+                trim_filters(ranges, action, sample_rate, i, &mut filters, &mut frames)?;
+            }
+            Step::Gain(gain) => filters.push(gain_filter(*gain)),
+            Step::Normalize(target) => {
+                if !filters.is_empty() {
+                    let source = rendered.as_deref().unwrap_or(in_path);
+                    rendered = Some(render(source, &filters)?);
+                    filters.clear();
+                }
+                let source = rendered.as_deref().unwrap_or(in_path);
+                filters.extend(normalize_filters(source, *target)?);
+            }
+            Step::Convert { bitrate: b, .. } => bitrate = *b,
+            Step::Metadata(t) => {
+                let merged = tags.get_or_insert_with(Tags::default);
+                merge_tags(merged, t);
+            }
+            Step::Fade {
+                in_ms,
+                out_ms,
+                curve,
+            } => {
+                let curve = curve.afade_name();
+                if *in_ms > 0 {
+                    filters.push(format!(
+                        "afade=t=in:st=0:d={}:curve={}",
+                        *in_ms as f64 / 1000.0,
+                        curve
+                    ));
+                }
+                if *out_ms > 0 {
+                    let length = (*out_ms as u64 * sample_rate as u64 / 1000).min(frames);
+                    filters.push(format!(
+                        "afade=t=out:ss={}:ns={}:curve={}",
+                        frames - length,
+                        length,
+                        curve
+                    ));
+                }
+            }
+        }
+    }
+
+    let output_format = output_format(input_ext, steps);
+    let mut job = FfmpegJob::new()
+        .input(rendered.as_deref().unwrap_or(in_path))?
+        .encode(&output_format)?
+        .bitrate(bitrate);
+    let out_ext = job.out_ext().unwrap_or_default();
+
+    // A rendered WAV has no tags; take them from the original instead.
+    let mut next_input = 1;
+    if rendered.is_some() {
+        job = job.input(in_path)?.output_args(&["-map_metadata", "1"]);
+        next_input += 1;
+    }
+    if rendered.is_some() || tags.is_some() {
+        job = job.map("0:a");
+    }
+
+    // Keep the cover temp alive until ffmpeg has run.
+    let mut _cover_tmp: Option<NamedTempFile> = None;
+    if let Some(tags) = &tags {
+        let meta = plan_for_meta(out_ext)?;
+        if out_ext == "aac" {
+            return Err(AudioError::UnsupportedFormat(
+                "Raw AAC (.aac/ADTS) does not support embedded metadata. Use .m4a instead.".into(),
+            ));
+        }
+
+        let cover_args: Option<&[&str]> = match meta.cover_mode {
+            CoverMode::Mp3Attached => Some(&["-c:v", "mjpeg", "-disposition:v", "attached_pic"]),
+            CoverMode::Mp4CoverAtom => Some(&["-c:v", "copy"]),
+            CoverMode::None => None,
+        };
+        if let (Some(args), Some(bytes)) = (cover_args, &tags.cover_art) {
+            let ctmp = NamedTempFile::new().map_err(AudioError::io("tmpfile cover"))?;
+            fs::write(ctmp.path(), bytes).map_err(AudioError::io("write cover"))?;
+            job = job
+                .input(ctmp.path())?
+                .map(&format!("{}:v", next_input))
+                .codec(args);
+            _cover_tmp = Some(ctmp);
+        }
+
+        job = job
+            .output_args(meta.extra_args)
+            .metadata("title", tags.title.as_deref())
+            .metadata("artist", tags.artist.as_deref())
+            .metadata("album", tags.album.as_deref())
+            .metadata("date", tags.year.as_deref());
+    }
+
+    for filter in filters {
+        job = job.filter(filter);
+    }
+    job.label("pipeline").run(out_path)
+}
+
+/// Sample rate and exact length in frames of `path`, by decoding it.
+fn decoded_length(path: &Path) -> AudioResult<(u32, u64)> {
+    let mut dec = Decoder::open(path)?;
+    let spec = dec.spec();
+    let mut frames = 0;
+    while let Some(block) = dec.next_block()? {
+        frames += (block.len() / spec.channels as usize) as u64;
+    }
+    Ok((spec.sample_rate, frames))
+}
+
+/// Apply `filters` to `source` and write the result as a float WAV.
+fn render(source: &Path, filters: &[String]) -> AudioResult<TempPath> {
+    let wav_path = NamedTempFile::with_suffix(".wav")
+        .map_err(AudioError::io("tmpfile render"))?
+        .into_temp_path();
+    let mut job = FfmpegJob::new()
+        .input(source)?
+        .map("0:a:0")
+        .codec(&["-c:a", "pcm_f32le"]);
+    for filter in filters {
+        job = job.filter(filter.as_str());
+    }
+    job.muxer("wav")
+        .label("pipeline render")
+        .untracked()
+        .run(&wav_path)?;
+    Ok(wav_path)
+}
+
+/// Filters that bring `source` to `target`: loudnorm with the measured
+/// values, then back to the source rate (loudnorm outputs 192 kHz).
+fn normalize_filters(source: &Path, target: LoudnessTarget) -> AudioResult<[String; 2]> {
+    let meter = meter_path(source)?;
+    let before = meter.finish();
+    if !before.integrated.is_finite() {
+        return Err(AudioError::InvalidArgument(
+            "audio is silent, cannot normalize".into(),
+        ));
+    }
+    Ok([
+        loudnorm_filter(target, &before),
+        format!("aresample={}", meter.spec().sample_rate),
+    ])
+}
+
+/// Filters for trim step `step`, with the same rules as `trim::trim_path`:
+/// the ranges are resolved to frames at `sample_rate` and cut with `atrim`
+/// on sample counts. Several kept ranges are split off and concatenated.
+/// `frames` is the length of the audio at this step; it is updated to what
+/// is left of it.
+fn trim_filters(
+    ranges: &[Range],
+    action: &str,
+    sample_rate: u32,
+    step: usize,
+    filters: &mut Vec<String>,
+    frames: &mut u64,
+) -> AudioResult<()> {
+    if action != "keep" && action != "remove" {
+        return Err(AudioError::InvalidArgument(
+            "Invalid action (must be 'keep' or 'remove')".into(),
+        ));
+    }
+    let kept = frame_ranges(ranges, sample_rate, action)?;
+
+    if action == "keep"
+        && let Some(i) = kept.iter().position(|&(start, _)| start >= *frames)
+    {
+        return Err(AudioError::InvalidArgument(format!(
+            "range {} starts after end of input",
+            i
+        )));
+    }
+    let total = *frames;
+    *frames = kept
+        .iter()
+        .map(|&(start, end)| end.min(total) - start.min(total))
+        .sum();
+
+    let segment = |&(start, end): &(u64, u64)| {
+        let mut bounds = Vec::new();
+        if start > 0 {
+            bounds.push(format!("start_sample={}", start));
+        }
+        if end < u64::MAX {
+            bounds.push(format!("end_sample={}", end));
+        }
+        format!("atrim={},asetpts=PTS-STARTPTS", bounds.join(":"))
+    };
+    match kept.as_slice() {
+        // Keeps everything
+        [(0, u64::MAX)] => {}
+        [range] => filters.push(segment(range)),
+        _ => {
+            let label = |kind: &str, i: usize| format!("[{}{}_{}]", kind, step, i);
+            let mut graph = format!("asplit={}", kept.len());
+            for i in 0..kept.len() {
+                graph.push_str(&label("in", i));
+            }
+            for (i, range) in kept.iter().enumerate() {
+                graph.push_str(&format!(
+                    ";{}{}{}",
+                    label("in", i),
+                    segment(range),
+                    label("cut", i)
+                ));
+            }
+            graph.push(';');
+            for i in 0..kept.len() {
+                graph.push_str(&label("cut", i));
+            }
+            graph.push_str(&format!("concat=n={}:v=0:a=1", kept.len()));
+            filters.push(graph);
+        }
+    }
+
+    Ok(())
+}
+
+fn merge_tags(into: &mut Tags, from: &Tags) {
+    let from = from.clone();
+    into.title = from.title.or(into.title.take());
+    into.artist = from.artist.or(into.artist.take());
+    into.album = from.album.or(into.album.take());
+    into.year = from.year.or(into.year.take());
+    into.cover_art = from.cover_art.or(into.cover_art.take());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_wav::{sine, wav_f32};
+    use crate::utils::trim::Position;
+    use hound::WavReader;
+
+    /// One second of a 440 Hz tone at -20 dBFS, mono.
+    fn tone_wav(sample_rate: u32) -> TempPath {
+        wav_f32(sample_rate, 1, sample_rate, sine(440.0, 0.1, sample_rate))
+    }
+
+    #[test]
+    fn normalize_uses_measured_values_and_source_rate() {
+        let input = tone_wav(44_100);
+        let [loudnorm, resample] = normalize_filters(&input, LoudnessTarget::default()).unwrap();
+
+        assert!(loudnorm.starts_with("loudnorm=I=-16:TP=-1.5:LRA=11:measured_I="));
+        assert!(loudnorm.ends_with(":linear=true"));
+        assert_eq!(resample, "aresample=44100");
+    }
+
+    #[test]
+    fn trims_on_sample_positions() {
+        let range = |start, end| Range {
+            start: Some(start),
+            end: Some(end),
+        };
+        let mut filters = Vec::new();
+        let mut frames = 480_000;
+
+        let keep = range(Position::millis(1234).unwrap(), Position::Frame(96_000));
+        trim_filters(&[keep], "keep", 48_000, 0, &mut filters, &mut frames).unwrap();
+        assert_eq!(
+            filters,
+            ["atrim=start_sample=59232:end_sample=96000,asetpts=PTS-STARTPTS"]
+        );
+        assert_eq!(frames, 36_768);
+
+        filters.clear();
+        let cut = range(Position::Frame(100), Position::timecode("0.5").unwrap());
+        trim_filters(&[cut], "remove", 48_000, 3, &mut filters, &mut frames).unwrap();
+        assert_eq!(
+            filters,
+            ["asplit=2[in3_0][in3_1];\
+                 [in3_0]atrim=end_sample=100,asetpts=PTS-STARTPTS[cut3_0];\
+                 [in3_1]atrim=start_sample=24000,asetpts=PTS-STARTPTS[cut3_1];\
+                 [cut3_0][cut3_1]concat=n=2:v=0:a=1"]
+        );
+        assert_eq!(frames, 12_868);
+
+        let past_end = Range {
+            start: Some(Position::Frame(12_868)),
+            end: None,
+        };
+        let err = trim_filters(&[past_end], "keep", 48_000, 4, &mut filters, &mut frames);
+        assert!(matches!(err, Err(AudioError::InvalidArgument(_))));
+    }
+
+    #[test]
+    #[ignore = "needs ffmpeg"]
+    fn normalize_keeps_output_sample_rate() {
+        let input = tone_wav(44_100);
+        let out = NamedTempFile::with_suffix(".wav").unwrap().into_temp_path();
+        let steps = [Step::Gain(-3), Step::Normalize(LoudnessTarget::default())];
+        pipeline_path(&input, &out, "wav", &steps).unwrap();
+
+        assert_eq!(WavReader::open(&out).unwrap().spec().sample_rate, 44_100);
+    }
+}
@@ -0,0 +1,87 @@
+use crate::utils::process;
+use std::sync::Arc;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::Semaphore;
+use tonic::Status;
+
+/// Shared pool that every blocking ffmpeg/filesystem step runs on, so the
+/// tokio worker threads stay free to serve other requests.
+pub struct ProcessingPool {
+    permits: Arc<Semaphore>,
+    waiting: AtomicUsize,
+    queue_depth: usize,
+}
+
+static POOL: OnceLock<ProcessingPool> = OnceLock::new();
+
+/// Configure the shared pool. Must be called before the first `pool()` call
+/// to take effect; later calls are ignored.
+pub fn init(workers: usize, queue_depth: usize) {
+    let _ = POOL.set(ProcessingPool::new(workers, queue_depth));
+}
+
+/// The shared pool; defaults to one worker per CPU and a queue four times that.
+pub fn pool() -> &'static ProcessingPool {
+    POOL.get_or_init(|| {
+        let workers = std::thread::available_parallelism().map_or(2, |n| n.get());
+        ProcessingPool::new(workers, workers * 4)
+    })
+}
+
+/// Decrements the waiting counter even if the request is dropped mid-wait.
+struct WaitGuard<'a>(&'a AtomicUsize);
+
+impl Drop for WaitGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl ProcessingPool {
+    pub fn new(workers: usize, queue_depth: usize) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(workers.max(1))),
+            waiting: AtomicUsize::new(0),
+            queue_depth,
+        }
+    }
+
+    /// Run `f` on a blocking thread once a worker slot is free. Fails with
+    /// RESOURCE_EXHAUSTED instead of waiting when the queue is already full.
+    pub async fn run<F, T>(&self, f: F) -> Result<T, Status>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let permit = match self.permits.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                if self.waiting.fetch_add(1, Ordering::SeqCst) >= self.queue_depth {
+                    self.waiting.fetch_sub(1, Ordering::SeqCst);
+                    return Err(Status::resource_exhausted(
+                        "server is busy, try again later",
+                    ));
+                }
+                let _guard = WaitGuard(&self.waiting);
+                self.permits
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .map_err(|_| Status::unavailable("processing pool closed"))?
+            }
+        };
+
+        // Carry the job context (cancellation, progress) over to the blocking thread.
+        let ctx = process::current();
+        tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            match ctx {
+                Some(ctx) => process::enter_job(ctx, f),
+                None => f(),
+            }
+        })
+        .await
+        .map_err(|e| Status::internal(format!("worker panicked: {}", e)))
+    }
+}
@@ -0,0 +1,253 @@
+use crate::utils::error::{AudioError, AudioResult};
+use crate::utils::ffmpeg::ffprobe_path;
+use std::fs::{self, File};
+use std::io::{Cursor, Read, Seek};
+use std::path::Path;
+use symphonia::core::codecs::{CODEC_TYPE_NULL, DecoderOptions};
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::{FormatOptions, FormatReader};
+use symphonia::core::io::{MediaSource, MediaSourceStream};
+use symphonia::core::meta::{MetadataOptions, MetadataRevision, Value};
+use symphonia::core::probe::Hint;
+use tempfile::NamedTempFile;
+
+/// What we know about an audio file without decoding all of it.
+#[derive(Debug, Clone, Default)]
+pub struct ProbeInfo {
+    /// Seconds.
+    pub duration: f64,
+    pub sample_rate: u32,
+    pub channels: u32,
+    /// Codec short name, e.g. "mp3", "flac", "pcm_s16le".
+    pub codec: String,
+    /// Container, e.g. "mp3", "wav", "ogg", "mp4".
+    pub container: String,
+    /// Bits per second; computed from size and duration when the stream does not say.
+    pub bitrate: u32,
+    /// Bits per sample; 0 for lossy codecs.
+    pub bit_depth: u32,
+    pub tags: Vec<(String, String)>,
+}
+
+/// Probe an in-memory upload. Formats symphonia cannot read are spooled to a
+/// temp file and handed to ffprobe.
+pub fn probe_bytes(data: &[u8], ext: Option<&str>) -> AudioResult<ProbeInfo> {
+    let source = Box::new(Cursor::new(data.to_vec()));
+    match probe_native(source, data.len() as u64, sniff_container(data), ext) {
+        Err(AudioError::UnsupportedFormat(_)) => {
+            let tmp = NamedTempFile::new().map_err(AudioError::io("tmpfile"))?;
+            fs::write(tmp.path(), data).map_err(AudioError::io("write tmp"))?;
+            ffprobe_path(tmp.path())
+        }
+        other => other,
+    }
+}
+
+/// Probe a file on disk, natively when possible and with ffprobe otherwise.
+pub fn probe_path(path: &Path) -> AudioResult<ProbeInfo> {
+    let mut file = File::open(path).map_err(AudioError::io("open input"))?;
+    let size = file.metadata().map_err(AudioError::io("stat input"))?.len();
+
+    let mut head = Vec::with_capacity(16);
+    (&mut file)
+        .take(16)
+        .read_to_end(&mut head)
+        .map_err(AudioError::io("read input"))?;
+    file.rewind().map_err(AudioError::io("read input"))?;
+
+    let ext = path.extension().and_then(|e| e.to_str());
+    match probe_native(Box::new(file), size, sniff_container(&head), ext) {
+        Err(AudioError::UnsupportedFormat(_)) => ffprobe_path(path),
+        other => other,
+    }
+}
+
+fn probe_native(
+    source: Box<dyn MediaSource>,
+    size: u64,
+    container: Option<&str>,
+    ext: Option<&str>,
+) -> AudioResult<ProbeInfo> {
+    let mss = MediaSourceStream::new(source, Default::default());
+    let mut hint = Hint::new();
+    if let Some(ext) = ext {
+        hint.with_extension(ext);
+    }
+
+    let mut probed = symphonia::default::get_probe()
+        .format(
+            &hint,
+            mss,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .map_err(probe_error)?;
+
+    // Tags may sit in front of the stream (ID3) or inside the container.
+    let mut tags = Vec::new();
+    if let Some(rev) = probed.metadata.get().as_ref().and_then(|m| m.current()) {
+        collect_tags(rev, &mut tags);
+    }
+    if let Some(rev) = probed.format.metadata().current() {
+        collect_tags(rev, &mut tags);
+    }
+
+    let mut format = probed.format;
+    let track = format
+        .tracks()
+        .iter()
+        .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+        .ok_or_else(|| AudioError::DecodeFailed {
+            message: "no audio track found".into(),
+            stderr: String::new(),
+        })?;
+    let track_id = track.id;
+    let params = track.codec_params.clone();
+
+    let codec = symphonia::default::get_codecs()
+        .get_codec(params.codec)
+        .map(|d| d.short_name.to_string())
+        .unwrap_or_else(|| "unknown".into());
+
+    let mut sample_rate = params.sample_rate.unwrap_or(0);
+    let mut channels = params.channels.map_or(0, |c| c.count() as u32);
+    if sample_rate == 0 || channels == 0 {
+        // Some streams only reveal their layout once a packet is decoded.
+        let (rate, count) = first_packet_spec(format.as_mut(), track_id, &params)?;
+        sample_rate = rate;
+        channels = count;
+    }
+
+    let duration = match (params.n_frames, params.time_base) {
+        (Some(frames), Some(tb)) => seconds(tb.calc_time(frames)),
+        (Some(frames), None) if sample_rate > 0 => frames as f64 / sample_rate as f64,
+        // No frame count in the header (e.g. mp3 without a Xing frame): walk
+        // the packets, which is cheap since nothing is decoded.
+        _ => scan_duration(format.as_mut(), track_id, params.time_base, sample_rate)?,
+    };
+
+    let bit_depth = params.bits_per_sample.unwrap_or(0);
+    let bitrate = if codec.starts_with("pcm_") && bit_depth > 0 {
+        sample_rate * channels * bit_depth
+    } else if duration > 0.0 {
+        (size as f64 * 8.0 / duration) as u32
+    } else {
+        0
+    };
+
+    Ok(ProbeInfo {
+        duration,
+        sample_rate,
+        channels,
+        codec,
+        container: container.unwrap_or("unknown").to_string(),
+        bitrate,
+        bit_depth,
+        tags,
+    })
+}
+
+fn first_packet_spec(
+    format: &mut dyn FormatReader,
+    track_id: u32,
+    params: &symphonia::core::codecs::CodecParameters,
+) -> AudioResult<(u32, u32)> {
+    let mut decoder = symphonia::default::get_codecs()
+        .make(params, &DecoderOptions::default())
+        .map_err(probe_error)?;
+    loop {
+        let packet = format.next_packet().map_err(probe_error)?;
+        if packet.track_id() != track_id {
+            continue;
+        }
+        match decoder.decode(&packet) {
+            Ok(decoded) => {
+                let spec = decoded.spec();
+                return Ok((spec.rate, spec.channels.count() as u32));
+            }
+            Err(SymphoniaError::DecodeError(_)) => continue,
+            Err(e) => return Err(probe_error(e)),
+        }
+    }
+}
+
+fn scan_duration(
+    format: &mut dyn FormatReader,
+    track_id: u32,
+    time_base: Option<symphonia::core::units::TimeBase>,
+    sample_rate: u32,
+) -> AudioResult<f64> {
+    let mut end = 0;
+    loop {
+        match format.next_packet() {
+            Ok(packet) if packet.track_id() == track_id => end = end.max(packet.ts + packet.dur),
+            Ok(_) => {}
+            Err(SymphoniaError::IoError(_)) | Err(SymphoniaError::ResetRequired) => break,
+            Err(e) => return Err(probe_error(e)),
+        }
+    }
+
+    match time_base {
+        Some(tb) => Ok(seconds(tb.calc_time(end))),
+        None if sample_rate > 0 => Ok(end as f64 / sample_rate as f64),
+        None => Ok(0.0),
+    }
+}
+
+fn seconds(time: symphonia::core::units::Time) -> f64 {
+    time.seconds as f64 + time.frac
+}
+
+fn collect_tags(rev: &MetadataRevision, tags: &mut Vec<(String, String)>) {
+    for tag in rev.tags() {
+        // Embedded pictures and other blobs are not useful as text.
+        if matches!(tag.value, Value::Binary(_)) {
+            continue;
+        }
+        tags.push((tag.key.clone(), tag.value.to_string()));
+    }
+}
+
+fn probe_error(err: SymphoniaError) -> AudioError {
+    match err {
+        SymphoniaError::Unsupported(what) => {
+            AudioError::UnsupportedFormat(format!("probe: unsupported {}", what))
+        }
+        other => AudioError::DecodeFailed {
+            message: format!("probe: {}", other),
+            stderr: String::new(),
+        },
+    }
+}
+
+/// Container name from the first bytes of a file.
+pub(crate) fn sniff_container(head: &[u8]) -> Option<&'static str> {
+    let at = |offset: usize, magic: &[u8]| head.get(offset..offset + magic.len()) == Some(magic);
+
+    if at(0, b"RIFF") && at(8, b"WAVE") {
+        Some("wav")
+    } else if at(0, b"FORM") && (at(8, b"AIFF") || at(8, b"AIFC")) {
+        Some("aiff")
+    } else if at(0, b"fLaC") {
+        Some("flac")
+    } else if at(0, b"OggS") {
+        Some("ogg")
+    } else if at(4, b"ftyp") {
+        Some("mp4")
+    } else if at(0, &[0x1A, 0x45, 0xDF, 0xA3]) {
+        Some("matroska")
+    } else if at(0, &[0x30, 0x26, 0xB2, 0x75]) {
+        Some("asf")
+    } else if at(0, b"caff") {
+        Some("caf")
+    } else if at(0, b"ID3") {
+        Some("mp3")
+    } else if head.len() >= 2 && head[0] == 0xFF && head[1] & 0xF6 == 0xF0 {
+        // ADTS sync word (layer bits 00)
+        Some("adts")
+    } else if head.len() >= 2 && head[0] == 0xFF && head[1] & 0xE0 == 0xE0 {
+        Some("mp3")
+    } else {
+        None
+    }
+}
@@ -0,0 +1,242 @@
+use crate::utils::ffmpeg::probe_duration_path;
+use crate::utils::progress::{self, ProgressLine, ProgressTracker};
+use std::future::Future;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often a running child is checked for cancellation.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+static TIMEOUT: OnceLock<Duration> = OnceLock::new();
+
+/// Kill any single child that runs longer than `limit` (reported as
+/// `io::ErrorKind::TimedOut`). Unlimited unless set; later calls are ignored.
+pub fn set_timeout(limit: Duration) {
+    let _ = TIMEOUT.set(limit);
+}
+
+/// Shared flag that asks every ffmpeg/ffprobe child started under it to stop.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Everything a background job hands down to the ffmpeg runs it triggers.
+#[derive(Clone)]
+pub struct JobContext {
+    pub cancel: CancelToken,
+    pub progress: Arc<ProgressTracker>,
+    /// Batch item this context reports progress for; `None` means whatever
+    /// file was last announced through `progress::begin_file`.
+    pub file: Option<usize>,
+}
+
+impl JobContext {
+    /// Same job, with progress attributed to batch item `index`.
+    pub fn for_file(&self, index: usize) -> Self {
+        Self {
+            file: Some(index),
+            ..self.clone()
+        }
+    }
+}
+
+tokio::task_local! {
+    static CURRENT: JobContext;
+}
+
+/// Run `fut` with `ctx` as the job context seen by `output` calls made while
+/// it is being polled.
+pub async fn with_job<F: Future>(ctx: JobContext, fut: F) -> F::Output {
+    CURRENT.scope(ctx, fut).await
+}
+
+/// Blocking-thread counterpart of `with_job`, used when work is moved off the
+/// async task (see `pool`).
+pub fn enter_job<F: FnOnce() -> T, T>(ctx: JobContext, f: F) -> T {
+    CURRENT.sync_scope(ctx, f)
+}
+
+/// Context of the job currently being processed, if any.
+pub fn current() -> Option<JobContext> {
+    CURRENT.try_with(|c| c.clone()).ok()
+}
+
+/// Whether the job this work belongs to has been cancelled; for in-process
+/// loops that have no child to kill.
+pub fn is_cancelled() -> bool {
+    current().is_some_and(|c| c.cancel.is_cancelled())
+}
+
+/// Called with the fraction (0..=1) of one ffmpeg run that is done.
+pub type ProgressHook = Arc<dyn Fn(f32) + Send + Sync>;
+
+/// Per-run knobs for `run`; the defaults are what `output` uses.
+#[derive(Clone, Default)]
+pub struct RunOptions {
+    /// Do not count this run towards the job's progress (helper passes).
+    pub untracked: bool,
+    /// Extra observer of this run's progress, called inside or outside jobs.
+    pub hook: Option<ProgressHook>,
+    /// Overrides the global `set_timeout` limit for this run.
+    pub timeout: Option<Duration>,
+}
+
+/// Drop-in replacement for `Command::output` that kills the child as soon as
+/// the surrounding job is cancelled. Inside a job, ffmpeg runs also report
+/// progress through `-progress pipe:1`.
+pub fn output(cmd: &mut Command) -> io::Result<Output> {
+    run(cmd, &RunOptions::default())
+}
+
+/// `output` with explicit options.
+pub fn run(cmd: &mut Command, opts: &RunOptions) -> io::Result<Output> {
+    let ctx = current();
+    let cancelled = || ctx.as_ref().is_some_and(|c| c.cancel.is_cancelled());
+
+    if cancelled() {
+        return Err(io::Error::new(io::ErrorKind::Interrupted, "job cancelled"));
+    }
+
+    let tracker = ctx.as_ref().filter(|_| !opts.untracked).map(|c| {
+        let file = c.file.unwrap_or_else(|| c.progress.current_file());
+        (c.progress.clone(), file)
+    });
+    let wants_progress =
+        cmd.get_program() == "ffmpeg" && (tracker.is_some() || opts.hook.is_some());
+
+    let mut progress_cmd;
+    let (cmd, sink) = if wants_progress {
+        let duration_us = input_path(cmd)
+            .and_then(|p| probe_duration_path(p).ok())
+            .map(|secs| (secs as f64 * 1_000_000.0) as i64);
+        progress_cmd = with_progress_args(cmd);
+        let sink = ProgressSink {
+            tracker,
+            hook: opts.hook.clone(),
+            duration_us,
+        };
+        (&mut progress_cmd, Some(sink))
+    } else {
+        (cmd, None)
+    };
+
+    let mut child = cmd
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Drain the pipes on their own threads so a chatty child never blocks on a full pipe.
+    let stdout = match sink {
+        Some(sink) => follow_progress(child.stdout.take(), sink),
+        None => drain(child.stdout.take()),
+    };
+    let stderr = drain(child.stderr.take());
+
+    let timeout = opts.timeout.or(TIMEOUT.get().copied());
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if cancelled() {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(io::Error::new(io::ErrorKind::Interrupted, "job cancelled"));
+        }
+        if timeout.is_some_and(|limit| started.elapsed() > limit) {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "timed out"));
+        }
+        thread::sleep(POLL_INTERVAL);
+    };
+
+    Ok(Output {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    })
+}
+
+fn drain<R: Read + Send + 'static>(pipe: Option<R>) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut buf);
+        }
+        buf
+    })
+}
+
+/// Same command with `-progress pipe:1 -nostats` prepended as global options.
+fn with_progress_args(cmd: &Command) -> Command {
+    let mut out = Command::new(cmd.get_program());
+    out.args(["-progress", "pipe:1", "-nostats"]);
+    out.args(cmd.get_args());
+    for (key, value) in cmd.get_envs() {
+        match value {
+            Some(v) => out.env(key, v),
+            None => out.env_remove(key),
+        };
+    }
+    if let Some(dir) = cmd.get_current_dir() {
+        out.current_dir(dir);
+    }
+    out
+}
+
+/// First `-i` argument of an ffmpeg command, used to probe the duration that
+/// progress percentages are computed against.
+fn input_path(cmd: &Command) -> Option<&Path> {
+    let mut args = cmd.get_args();
+    while let Some(arg) = args.next() {
+        if arg == "-i" {
+            return args.next().map(Path::new);
+        }
+    }
+    None
+}
+
+/// Where the progress of one ffmpeg run goes.
+struct ProgressSink {
+    tracker: Option<(Arc<ProgressTracker>, usize)>,
+    hook: Option<ProgressHook>,
+    duration_us: Option<i64>,
+}
+
+fn follow_progress<R: Read + Send + 'static>(
+    pipe: Option<R>,
+    sink: ProgressSink,
+) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        if let Some(pipe) = pipe {
+            for line in BufReader::new(pipe).lines().map_while(Result::ok) {
+                let Some(line) = progress::parse_line(&line, sink.duration_us) else {
+                    continue;
+                };
+                if let (Some(hook), ProgressLine::Done(fraction)) = (&sink.hook, &line) {
+                    hook(*fraction);
+                }
+                if let Some((tracker, file)) = &sink.tracker {
+                    tracker.update(*file, line);
+                }
+            }
+        }
+        Vec::new()
+    })
+}
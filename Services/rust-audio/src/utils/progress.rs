@@ -0,0 +1,141 @@
+use crate::audio::{JobState, ProgressEvent};
+use crate::utils::process;
+use std::sync::Mutex;
+use std::time::Instant;
+use tokio::sync::watch;
+
+#[derive(Default)]
+struct FileState {
+    /// Highest file index that has started so far.
+    index: u32,
+    count: u32,
+    /// Fraction done per file; never goes backwards even when one file needs
+    /// several ffmpeg passes. Batch files may progress concurrently.
+    fractions: Vec<f32>,
+    speed: f32,
+}
+
+/// Live progress of one job, published to `WatchProgress` subscribers.
+pub struct ProgressTracker {
+    tx: watch::Sender<ProgressEvent>,
+    started: Instant,
+    state: Mutex<FileState>,
+}
+
+impl ProgressTracker {
+    pub fn new(job_id: &str) -> Self {
+        let (tx, _) = watch::channel(ProgressEvent {
+            job_id: job_id.to_string(),
+            state: JobState::Queued as i32,
+            eta_seconds: -1,
+            ..Default::default()
+        });
+        Self {
+            tx,
+            started: Instant::now(),
+            state: Mutex::new(FileState {
+                count: 1,
+                fractions: vec![0.0],
+                ..Default::default()
+            }),
+        }
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<ProgressEvent> {
+        self.tx.subscribe()
+    }
+
+    pub fn set_state(&self, state: JobState) {
+        self.tx.send_modify(|ev| {
+            ev.state = state as i32;
+            if state == JobState::Succeeded {
+                ev.percent = 100.0;
+                ev.eta_seconds = 0;
+            }
+        });
+    }
+
+    pub(crate) fn begin_file(&self, index: usize, count: usize) {
+        let mut st = self.state.lock().unwrap();
+        st.count = count.max(1) as u32;
+        st.index = st.index.max(index as u32);
+        let count = st.count as usize;
+        st.fractions.resize(count, 0.0);
+        self.publish(&st);
+    }
+
+    /// File that sequential handlers (those not going through `batch`) are
+    /// currently working on.
+    pub(crate) fn current_file(&self) -> usize {
+        self.state.lock().unwrap().index as usize
+    }
+
+    /// Apply one parsed `-progress` line of an ffmpeg run working on `file`.
+    pub(crate) fn update(&self, file: usize, line: ProgressLine) {
+        let mut st = self.state.lock().unwrap();
+        match line {
+            ProgressLine::Speed(speed) => st.speed = speed, // published with the next fraction
+            ProgressLine::Done(fraction) => {
+                let Some(done) = st.fractions.get_mut(file) else {
+                    return;
+                };
+                *done = done.max(fraction);
+                self.publish(&st);
+            }
+        }
+    }
+
+    fn publish(&self, st: &FileState) {
+        let overall = st.fractions.iter().sum::<f32>() / st.count as f32;
+        let elapsed = self.started.elapsed().as_secs_f32();
+        let eta_seconds = if overall > 0.0 {
+            (elapsed * (1.0 - overall) / overall).round() as i64
+        } else {
+            -1
+        };
+
+        self.tx.send_modify(|ev| {
+            ev.percent = (overall * 100.0).min(100.0);
+            ev.file_index = st.index;
+            ev.file_count = st.count;
+            ev.speed = st.speed;
+            ev.eta_seconds = eta_seconds;
+        });
+    }
+}
+
+/// What one `key=value` line of ffmpeg's `-progress` output tells us.
+pub(crate) enum ProgressLine {
+    /// Fraction (0..=1) of the run's input that has been processed.
+    Done(f32),
+    /// Processing speed relative to real time.
+    Speed(f32),
+}
+
+/// Parse a `-progress` line of a run whose input lasts `duration_us`
+/// microseconds (if it could be probed).
+pub(crate) fn parse_line(line: &str, duration_us: Option<i64>) -> Option<ProgressLine> {
+    let (key, value) = line.trim().split_once('=')?;
+    match key {
+        "out_time_us" => {
+            let us = value.parse::<i64>().ok()?;
+            let total = duration_us.filter(|t| *t > 0)?;
+            Some(ProgressLine::Done(
+                (us as f32 / total as f32).clamp(0.0, 1.0),
+            ))
+        }
+        "speed" => Some(ProgressLine::Speed(
+            value.trim_end_matches('x').trim().parse().unwrap_or(0.0),
+        )),
+        "progress" if value == "end" => Some(ProgressLine::Done(1.0)),
+        _ => None,
+    }
+}
+
+/// Tell the current job's tracker that file `index` of `count` is starting.
+/// No-op outside a job (plain unary/streaming calls).
+pub fn begin_file(index: usize, count: usize) {
+    if let Some(ctx) = process::current() {
+        ctx.progress.begin_file(index, count);
+    }
+}
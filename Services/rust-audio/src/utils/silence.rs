@@ -0,0 +1,149 @@
+use crate::utils::error::{AudioError, AudioResult};
+use crate::utils::native::{Decoder, PcmSpec};
+use crate::utils::temp::spool;
+use std::path::Path;
+
+/// Level below which audio counts as silent, in dBFS.
+pub const DEFAULT_THRESHOLD_DB: f64 = -50.0;
+/// Shortest quiet stretch reported as silence.
+pub const DEFAULT_MIN_DURATION_MS: u32 = 500;
+
+/// What counts as silence: every channel below `threshold_db` for at least
+/// `min_duration_ms`.
+#[derive(Debug, Clone, Copy)]
+pub struct SilenceOptions {
+    pub threshold_db: f64,
+    pub min_duration_ms: u32,
+}
+
+impl Default for SilenceOptions {
+    fn default() -> Self {
+        Self {
+            threshold_db: DEFAULT_THRESHOLD_DB,
+            min_duration_ms: DEFAULT_MIN_DURATION_MS,
+        }
+    }
+}
+
+impl SilenceOptions {
+    /// Options from optional request fields, with defaults applied.
+    pub fn new(threshold_db: Option<f64>, min_duration_ms: Option<u32>) -> AudioResult<Self> {
+        let threshold_db = threshold_db.unwrap_or(DEFAULT_THRESHOLD_DB);
+        if !(-120.0..=0.0).contains(&threshold_db) {
+            return Err(AudioError::InvalidArgument(
+                "silence threshold_db must be between -120 and 0".into(),
+            ));
+        }
+        Ok(Self {
+            threshold_db,
+            min_duration_ms: min_duration_ms.unwrap_or(DEFAULT_MIN_DURATION_MS),
+        })
+    }
+}
+
+/// Silent regions of a file, as half-open frame ranges in order.
+#[derive(Debug, Clone)]
+pub struct Silence {
+    pub spec: PcmSpec,
+    /// Length of the whole file, in frames.
+    pub frames: u64,
+    pub regions: Vec<(u64, u64)>,
+    /// First and one-past-last frame above the threshold, however short the
+    /// quiet stretches around them are; both 0 for an all-silent file.
+    pub sound: (u64, u64),
+}
+
+impl Silence {
+    pub fn seconds(&self, frame: u64) -> f64 {
+        frame as f64 / self.spec.sample_rate as f64
+    }
+
+    /// Frames to keep so that leading and trailing silence is dropped and,
+    /// with `max_gap_ms`, no internal silence lasts longer than that. A
+    /// shortened gap keeps its first and last halves so the audio on both
+    /// sides decays and starts as before.
+    pub fn keep_ranges(&self, max_gap_ms: Option<u32>) -> AudioResult<Vec<(u64, u64)>> {
+        let max_gap = max_gap_ms.map(|ms| ms as u64 * self.spec.sample_rate as u64 / 1000);
+        let (start, end) = self.sound;
+        let mut cuts = Vec::new();
+        for &(from, to) in &self.regions {
+            if from <= start || to >= end {
+                continue;
+            }
+            if let Some(gap) = max_gap
+                && to - from > gap
+            {
+                let head = gap / 2;
+                cuts.push((from + head, to - (gap - head)));
+            }
+        }
+        if start >= end {
+            return Err(AudioError::InvalidArgument(
+                "audio is silent throughout; nothing left after stripping silence".into(),
+            ));
+        }
+
+        let mut ranges = Vec::with_capacity(cuts.len() + 1);
+        let mut pos = start;
+        for (from, to) in cuts {
+            ranges.push((pos, from));
+            pos = to;
+        }
+        ranges.push((pos, end));
+        Ok(ranges)
+    }
+}
+
+/// Decode `in_path` and find every stretch where all channels stay below
+/// the threshold for at least the minimum duration.
+pub fn detect_silence_path(in_path: &Path, opts: &SilenceOptions) -> AudioResult<Silence> {
+    let mut dec = Decoder::open(in_path)?;
+    let spec = dec.spec();
+    let channels = spec.channels as usize;
+    let threshold = 10f32.powf(opts.threshold_db as f32 / 20.0);
+    let min_frames = (opts.min_duration_ms as u64 * spec.sample_rate as u64 / 1000).max(1);
+
+    let mut regions = Vec::new();
+    let mut quiet_since = None;
+    let mut sound: Option<(u64, u64)> = None;
+    let mut pos: u64 = 0;
+    while let Some(block) = dec.next_block()? {
+        for frame in block.chunks_exact(channels) {
+            let quiet = frame.iter().all(|s| s.abs() < threshold);
+            match (quiet, quiet_since) {
+                (true, None) => quiet_since = Some(pos),
+                (false, _) => {
+                    sound = Some((sound.map_or(pos, |(first, _)| first), pos + 1));
+                    if let Some(since) = quiet_since.take()
+                        && pos - since >= min_frames
+                    {
+                        regions.push((since, pos));
+                    }
+                }
+                (true, Some(_)) => {}
+            }
+            pos += 1;
+        }
+    }
+    if let Some(since) = quiet_since
+        && pos - since >= min_frames
+    {
+        regions.push((since, pos));
+    }
+
+    Ok(Silence {
+        spec,
+        frames: pos,
+        regions,
+        sound: sound.unwrap_or((0, 0)),
+    })
+}
+
+pub fn detect_silence_file(
+    input_bytes: Vec<u8>,
+    ext: &str,
+    opts: &SilenceOptions,
+) -> AudioResult<Silence> {
+    let in_path = spool(&input_bytes, ext)?;
+    detect_silence_path(&in_path, opts)
+}
@@ -0,0 +1,444 @@
+use crate::utils::error::{AudioError, AudioResult};
+use crate::utils::native::Decoder;
+use realfft::RealFftPlanner;
+use std::f32::consts::PI;
+use std::fs;
+use std::path::Path;
+use tempfile::Builder;
+
+/// Lowest frequency shown on the log scale.
+const LOG_MIN_HZ: f32 = 20.0;
+const MAX_WIDTH: u32 = 8192;
+const MAX_HEIGHT: u32 = 4096;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Window {
+    Hann,
+    Hamming,
+    Blackman,
+    Rectangular,
+}
+
+impl Window {
+    pub fn parse(name: &str) -> AudioResult<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "" | "hann" | "hanning" => Ok(Window::Hann),
+            "hamming" => Ok(Window::Hamming),
+            "blackman" => Ok(Window::Blackman),
+            "rectangular" | "rect" | "none" => Ok(Window::Rectangular),
+            other => Err(AudioError::InvalidArgument(format!(
+                "Invalid window '{}' (must be hann, hamming, blackman or rectangular)",
+                other
+            ))),
+        }
+    }
+
+    fn coefficients(self, size: usize) -> Vec<f32> {
+        let n = (size - 1) as f32;
+        (0..size)
+            .map(|i| {
+                let x = 2.0 * PI * i as f32 / n;
+                match self {
+                    Window::Hann => 0.5 - 0.5 * x.cos(),
+                    Window::Hamming => 0.54 - 0.46 * x.cos(),
+                    Window::Blackman => 0.42 - 0.5 * x.cos() + 0.08 * (2.0 * x).cos(),
+                    Window::Rectangular => 1.0,
+                }
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum FreqScale {
+    Linear,
+    Log,
+    Mel,
+}
+
+impl FreqScale {
+    pub fn parse(name: &str) -> AudioResult<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "" | "linear" => Ok(FreqScale::Linear),
+            "log" => Ok(FreqScale::Log),
+            "mel" => Ok(FreqScale::Mel),
+            other => Err(AudioError::InvalidArgument(format!(
+                "Invalid scale '{}' (must be linear, log or mel)",
+                other
+            ))),
+        }
+    }
+
+    /// Frequency at `t` (0 = bottom, 1 = top of the image).
+    fn freq_at(self, t: f32, nyquist: f32) -> f32 {
+        match self {
+            FreqScale::Linear => t * nyquist,
+            FreqScale::Log => LOG_MIN_HZ * (nyquist / LOG_MIN_HZ).powf(t),
+            FreqScale::Mel => mel_to_hz(t * hz_to_mel(nyquist)),
+        }
+    }
+}
+
+fn hz_to_mel(hz: f32) -> f32 {
+    2595.0 * (1.0 + hz / 700.0).log10()
+}
+
+fn mel_to_hz(mel: f32) -> f32 {
+    700.0 * (10f32.powf(mel / 2595.0) - 1.0)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Colormap {
+    Magma,
+    Inferno,
+    Viridis,
+    Grayscale,
+}
+
+// Control points of the matplotlib colormaps, evenly spaced from 0 to 1.
+const MAGMA: &[[u8; 3]] = &[
+    [0, 0, 4],
+    [28, 16, 68],
+    [79, 18, 123],
+    [129, 37, 129],
+    [181, 54, 122],
+    [229, 80, 100],
+    [251, 135, 97],
+    [254, 194, 135],
+    [252, 253, 191],
+];
+const INFERNO: &[[u8; 3]] = &[
+    [0, 0, 4],
+    [31, 12, 72],
+    [85, 15, 109],
+    [136, 34, 106],
+    [186, 54, 85],
+    [227, 89, 51],
+    [249, 140, 10],
+    [249, 201, 50],
+    [252, 255, 164],
+];
+const VIRIDIS: &[[u8; 3]] = &[
+    [68, 1, 84],
+    [72, 40, 120],
+    [62, 74, 137],
+    [49, 104, 142],
+    [38, 130, 142],
+    [31, 158, 137],
+    [53, 183, 121],
+    [109, 205, 89],
+    [253, 231, 37],
+];
+const GRAYSCALE: &[[u8; 3]] = &[[0, 0, 0], [255, 255, 255]];
+
+impl Colormap {
+    pub fn parse(name: &str) -> AudioResult<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "" | "magma" => Ok(Colormap::Magma),
+            "inferno" => Ok(Colormap::Inferno),
+            "viridis" => Ok(Colormap::Viridis),
+            "grayscale" | "greyscale" | "gray" | "grey" => Ok(Colormap::Grayscale),
+            other => Err(AudioError::InvalidArgument(format!(
+                "Invalid colormap '{}' (must be magma, inferno, viridis or grayscale)",
+                other
+            ))),
+        }
+    }
+
+    /// Color for `t` in 0..=1.
+    fn color(self, t: f32) -> [u8; 3] {
+        let points = match self {
+            Colormap::Magma => MAGMA,
+            Colormap::Inferno => INFERNO,
+            Colormap::Viridis => VIRIDIS,
+            Colormap::Grayscale => GRAYSCALE,
+        };
+        let pos = t.clamp(0.0, 1.0) * (points.len() - 1) as f32;
+        let i = (pos.floor() as usize).min(points.len() - 2);
+        let frac = pos - i as f32;
+        let (a, b) = (points[i], points[i + 1]);
+        [0, 1, 2].map(|c| (a[c] as f32 + (b[c] as f32 - a[c] as f32) * frac).round() as u8)
+    }
+}
+
+/// Everything that shapes the image.
+#[derive(Debug, Clone, Copy)]
+pub struct SpectrogramOptions {
+    /// Power of two, 64..=16384.
+    pub fft_size: usize,
+    pub window: Window,
+    pub scale: FreqScale,
+    pub min_db: f32,
+    pub max_db: f32,
+    pub colormap: Colormap,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl SpectrogramOptions {
+    fn validate(&self) -> AudioResult<()> {
+        if !self.fft_size.is_power_of_two() || !(64..=16384).contains(&self.fft_size) {
+            return Err(AudioError::InvalidArgument(
+                "fft_size must be a power of two between 64 and 16384".into(),
+            ));
+        }
+        if self.min_db >= self.max_db {
+            return Err(AudioError::InvalidArgument(
+                "min_db must be lower than max_db".into(),
+            ));
+        }
+        if self.width == 0 || self.width > MAX_WIDTH || self.height == 0 || self.height > MAX_HEIGHT
+        {
+            return Err(AudioError::InvalidArgument(format!(
+                "image size must be between 1x1 and {}x{}",
+                MAX_WIDTH, MAX_HEIGHT
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Power spectra of consecutive FFT frames, summed in buckets of
+/// `per_bucket` frames. Once there are `limit` buckets, neighbours are merged
+/// and the bucket size doubles, so memory stays bounded however long the
+/// audio is, and columns are placed by the frames actually decoded.
+struct Buckets {
+    bins: usize,
+    limit: usize,
+    per_bucket: usize,
+    frames: usize,
+    power: Vec<f32>,
+    counts: Vec<u32>,
+}
+
+impl Buckets {
+    fn new(bins: usize, width: usize) -> Self {
+        Self {
+            bins,
+            limit: width * 2,
+            per_bucket: 1,
+            frames: 0,
+            power: Vec::new(),
+            counts: Vec::new(),
+        }
+    }
+
+    /// Power row the next frame adds to.
+    fn next(&mut self) -> &mut [f32] {
+        if self.frames == self.counts.len() * self.per_bucket {
+            if self.counts.len() == self.limit {
+                self.merge();
+            }
+            self.counts.push(0);
+            self.power.resize(self.counts.len() * self.bins, 0.0);
+        }
+        let b = self.counts.len() - 1;
+        self.counts[b] += 1;
+        self.frames += 1;
+        &mut self.power[b * self.bins..(b + 1) * self.bins]
+    }
+
+    fn merge(&mut self) {
+        let bins = self.bins;
+        let half = self.counts.len() / 2;
+        for i in 0..half {
+            for j in 0..bins {
+                self.power[i * bins + j] =
+                    self.power[2 * i * bins + j] + self.power[(2 * i + 1) * bins + j];
+            }
+            self.counts[i] = self.counts[2 * i] + self.counts[2 * i + 1];
+        }
+        self.counts.truncate(half);
+        self.power.truncate(half * bins);
+        self.per_bucket *= 2;
+    }
+
+    /// Summed power and frame count of each of `width` columns.
+    fn columns(&self, width: usize) -> (Vec<f32>, Vec<u32>) {
+        let bins = self.bins;
+        let mut power = vec![0f32; width * bins];
+        let mut counts = vec![0u32; width];
+        for (b, &count) in self.counts.iter().enumerate() {
+            let first = b * self.per_bucket;
+            let col = (first * width / self.frames.max(1)).min(width - 1);
+            let row = &self.power[b * bins..(b + 1) * bins];
+            for (p, v) in power[col * bins..(col + 1) * bins].iter_mut().zip(row) {
+                *p += v;
+            }
+            counts[col] += count;
+        }
+        (power, counts)
+    }
+}
+
+/// Render the STFT of `in_path` (channels mixed down) as a PNG. FFT frames
+/// overlap by half and are averaged into `width` columns, so long files stay
+/// cheap in memory.
+pub fn spectrogram_path(in_path: &Path, opts: &SpectrogramOptions) -> AudioResult<Vec<u8>> {
+    opts.validate()?;
+
+    let mut dec = Decoder::open(in_path)?;
+    let spec = dec.spec();
+    let channels = spec.channels as usize;
+
+    let size = opts.fft_size;
+    let step = size / 2;
+    let bins = size / 2 + 1;
+    let width = opts.width as usize;
+
+    let window = opts.window.coefficients(size);
+    // Scale so a full-scale sine reads 0 dB.
+    let norm = 2.0 / window.iter().sum::<f32>();
+    let fft = RealFftPlanner::<f32>::new().plan_fft_forward(size);
+    let mut input = fft.make_input_vec();
+    let mut output = fft.make_output_vec();
+
+    let mut buckets = Buckets::new(bins, width);
+    let mut pending: Vec<f32> = Vec::with_capacity(size * 2);
+    let mut analyzed = 0;
+
+    let mut analyze = |samples: &[f32]| -> AudioResult<()> {
+        for (i, (s, w)) in samples.iter().zip(&window).enumerate() {
+            input[i] = s * w;
+        }
+        fft.process(&mut input, &mut output)
+            .map_err(|e| AudioError::ProcessingFailed {
+                message: format!("fft: {}", e),
+                stderr: String::new(),
+            })?;
+        for (p, c) in buckets.next().iter_mut().zip(&output) {
+            *p += (c.norm() * norm).powi(2);
+        }
+        Ok(())
+    };
+
+    while let Some(block) = dec.next_block()? {
+        pending.extend(
+            block
+                .chunks_exact(channels)
+                .map(|f| f.iter().sum::<f32>() / channels as f32),
+        );
+        let mut offset = 0;
+        while pending.len() - offset >= size {
+            analyze(&pending[offset..offset + size])?;
+            analyzed += 1;
+            offset += step;
+        }
+        pending.drain(..offset);
+    }
+    // Short files (or the tail) still get one zero-padded frame.
+    if !pending.is_empty() && (analyzed == 0 || pending.len() > step) {
+        pending.resize(size, 0.0);
+        analyze(&pending)?;
+    }
+
+    let (power, counts) = buckets.columns(width);
+    render(opts, spec.sample_rate, bins, &power, &counts)
+}
+
+/// Map the averaged power columns to pixels and encode the PNG.
+fn render(
+    opts: &SpectrogramOptions,
+    sample_rate: u32,
+    bins: usize,
+    power: &[f32],
+    counts: &[u32],
+) -> AudioResult<Vec<u8>> {
+    let width = opts.width as usize;
+    let height = opts.height as usize;
+    let nyquist = sample_rate as f32 / 2.0;
+    let bin_hz = nyquist / (bins - 1) as f32;
+
+    // Bin range covered by each image row, top row first.
+    let rows: Vec<(usize, usize)> = (0..height)
+        .map(|y| {
+            let top = 1.0 - y as f32 / height as f32;
+            let bottom = 1.0 - (y + 1) as f32 / height as f32;
+            let hi = opts.scale.freq_at(top, nyquist) / bin_hz;
+            let lo = opts.scale.freq_at(bottom, nyquist) / bin_hz;
+            let lo = (lo.round() as usize).min(bins - 1);
+            let hi = (hi.round() as usize).clamp(lo + 1, bins);
+            (lo, hi)
+        })
+        .collect();
+
+    let mut pixels = vec![0u8; width * height * 3];
+    let mut last_col = None;
+    for x in 0..width {
+        // Columns no frame fell into repeat their left neighbour.
+        let col = if counts[x] > 0 {
+            last_col = Some(x);
+            x
+        } else {
+            match last_col {
+                Some(c) => c,
+                None => counts.iter().position(|c| *c > 0).unwrap_or(x),
+            }
+        };
+        let count = counts[col].max(1) as f32;
+        let column = &power[col * bins..(col + 1) * bins];
+
+        for (y, &(lo, hi)) in rows.iter().enumerate() {
+            let mean = column[lo..hi].iter().sum::<f32>() / (hi - lo) as f32 / count;
+            let db = 10.0 * mean.max(1e-20).log10();
+            let t = (db - opts.min_db) / (opts.max_db - opts.min_db);
+            let at = (y * width + x) * 3;
+            pixels[at..at + 3].copy_from_slice(&opts.colormap.color(t));
+        }
+    }
+
+    let mut png_bytes = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(&mut png_bytes, opts.width, opts.height);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().map_err(png_error)?;
+        writer.write_image_data(&pixels).map_err(png_error)?;
+    }
+    Ok(png_bytes)
+}
+
+fn png_error(err: png::EncodingError) -> AudioError {
+    AudioError::ProcessingFailed {
+        message: format!("png: {}", err),
+        stderr: String::new(),
+    }
+}
+
+/// In-memory variant of `spectrogram_path`; `ext` helps the decoder sniff.
+pub fn spectrogram_file(
+    input_bytes: Vec<u8>,
+    ext: &str,
+    opts: &SpectrogramOptions,
+) -> AudioResult<Vec<u8>> {
+    let tmp_in = Builder::new()
+        .suffix(&format!(".{}", ext))
+        .tempfile()
+        .map_err(AudioError::io("tmpfile in"))?;
+    fs::write(tmp_in.path(), &input_bytes).map_err(AudioError::io("write tmp in"))?;
+    let in_path = tmp_in.into_temp_path();
+
+    spectrogram_path(&in_path, opts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn columns_follow_decoded_frames() {
+        // 1000 frames into 10 columns: loud first half, silent second half
+        let mut buckets = Buckets::new(1, 10);
+        for i in 0..1000 {
+            buckets.next()[0] += if i < 500 { 1.0 } else { 0.0 };
+        }
+        assert!(buckets.counts.len() <= 20);
+
+        let (power, counts) = buckets.columns(10);
+        assert_eq!(counts.iter().sum::<u32>(), 1000);
+        assert!(counts.iter().all(|&c| c > 0));
+        let mean = |col: usize| power[col] / counts[col] as f32;
+        assert!((0..4).all(|col| mean(col) == 1.0));
+        assert!((5..10).all(|col| mean(col) == 0.0));
+    }
+}
@@ -0,0 +1,327 @@
+use crate::utils::error::{AudioError, AudioResult};
+use crate::utils::fade::{FadeWriter, Fades};
+use crate::utils::ffmpeg::ffprobe_chapters;
+use crate::utils::native::{Decoder, PcmWriter};
+use crate::utils::silence::{SilenceOptions, detect_silence_path};
+use crate::utils::temp::spool;
+use crate::utils::trim::Position;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+use tempfile::{NamedTempFile, TempPath};
+
+/// Part names when the request does not give a pattern.
+pub const DEFAULT_NAMING: &str = "{stem}_{index:02}.{ext}";
+
+/// Where to cut.
+#[derive(Debug, Clone)]
+pub enum SplitMode {
+    /// Cut at each position; n points give n + 1 parts.
+    Points(Vec<Position>),
+    /// Consecutive parts of this many milliseconds; the last may be shorter.
+    Length(u64),
+    /// The sound between silences; the silences themselves are dropped.
+    Silence(SilenceOptions),
+    /// One part per chapter embedded in the file.
+    Chapters,
+}
+
+/// A `[start, end)` frame range written to its own file.
+struct Part {
+    start: u64,
+    end: u64,
+    title: Option<String>,
+}
+
+/// Validated part naming pattern. Placeholders are `{stem}`, `{ext}`,
+/// `{title}` (chapter title, or the index when there is none) and
+/// `{index}`, numbered from 1, which takes a width such as `{index:02}`.
+/// One of the last two is required. Chapters sharing a title still share a
+/// name; the zip then numbers them (see `zip::unique_name`).
+#[derive(Debug, Clone)]
+pub struct Naming(String);
+
+impl Naming {
+    pub fn parse(pattern: &str) -> AudioResult<Self> {
+        let pattern = if pattern.is_empty() {
+            DEFAULT_NAMING
+        } else {
+            pattern
+        };
+        fill(pattern, "stem", "ext", 1, None)?;
+        if !pattern.contains("{index") && !pattern.contains("{title}") {
+            return Err(AudioError::InvalidArgument(format!(
+                "Invalid naming pattern '{}': must contain {{index}} or {{title}} so parts get distinct names",
+                pattern
+            )));
+        }
+        Ok(Self(pattern.to_string()))
+    }
+
+    fn name(&self, stem: &str, ext: &str, index: usize, title: Option<&str>) -> String {
+        // The pattern was checked by `parse`
+        fill(&self.0, stem, ext, index, title).unwrap_or_default()
+    }
+}
+
+fn fill(
+    pattern: &str,
+    stem: &str,
+    ext: &str,
+    index: usize,
+    title: Option<&str>,
+) -> AudioResult<String> {
+    let invalid = |why: &str| {
+        AudioError::InvalidArgument(format!("Invalid naming pattern '{}': {}", pattern, why))
+    };
+
+    let mut out = String::new();
+    let mut rest = pattern;
+    while let Some(open) = rest.find('{') {
+        out.push_str(&rest[..open]);
+        let close = rest[open..]
+            .find('}')
+            .ok_or_else(|| invalid("unclosed '{'"))?;
+        let token = &rest[open + 1..open + close];
+        let (field, width) = match token.split_once(':') {
+            Some((field, width)) => (field, Some(width)),
+            None => (token, None),
+        };
+        match (field, width) {
+            ("index", Some(width)) => {
+                let digits = width
+                    .parse::<usize>()
+                    .map_err(|_| invalid("width must be a number, e.g. {index:02}"))?;
+                if width.starts_with('0') {
+                    let _ = write!(out, "{:0digits$}", index);
+                } else {
+                    let _ = write!(out, "{:digits$}", index);
+                }
+            }
+            ("index", None) => {
+                let _ = write!(out, "{}", index);
+            }
+            ("stem", None) => out.push_str(stem),
+            ("ext", None) => out.push_str(ext),
+            ("title", None) => match title {
+                Some(title) => out.push_str(title),
+                None => {
+                    let _ = write!(out, "{}", index);
+                }
+            },
+            (_, Some(_)) => return Err(invalid("only {index} takes a width")),
+            (other, None) => {
+                return Err(invalid(&format!("unknown placeholder {{{}}}", other)));
+            }
+        }
+        rest = &rest[open + close + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// The parts `mode` cuts the file into, in order. Parts past the end of the
+/// audio come out empty and are skipped.
+fn plan(
+    in_path: &Path,
+    mode: &SplitMode,
+    sample_rate: u32,
+) -> AudioResult<Box<dyn Iterator<Item = Part>>> {
+    let part = |start, end| Part {
+        start,
+        end,
+        title: None,
+    };
+
+    match mode {
+        SplitMode::Points(points) => {
+            let mut bounds = vec![0];
+            for p in points {
+                let frame = p.frame(sample_rate);
+                if frame <= *bounds.last().unwrap() {
+                    return Err(AudioError::InvalidArgument(
+                        "cut points must be after the start and in ascending order".into(),
+                    ));
+                }
+                bounds.push(frame);
+            }
+            bounds.push(u64::MAX);
+            let parts: Vec<Part> = bounds.windows(2).map(|w| part(w[0], w[1])).collect();
+            Ok(Box::new(parts.into_iter()))
+        }
+        SplitMode::Length(ms) => {
+            if *ms == 0 {
+                return Err(AudioError::InvalidArgument(
+                    "segment length must be greater than 0".into(),
+                ));
+            }
+            let length = Position::millis(*ms)?.frame(sample_rate).max(1);
+            Ok(Box::new(
+                (0..).map(move |i| part(i * length, (i + 1) * length)),
+            ))
+        }
+        SplitMode::Silence(opts) => {
+            // A zero max gap removes every internal silence entirely
+            let ranges = detect_silence_path(in_path, opts)?.keep_ranges(Some(0))?;
+            let parts: Vec<Part> = ranges.into_iter().map(|(s, e)| part(s, e)).collect();
+            Ok(Box::new(parts.into_iter()))
+        }
+        SplitMode::Chapters => {
+            let chapters = ffprobe_chapters(in_path)?;
+            if chapters.is_empty() {
+                return Err(AudioError::InvalidArgument(
+                    "file has no chapters to split by".into(),
+                ));
+            }
+            let frame = |seconds: f64| (seconds.max(0.0) * sample_rate as f64).round() as u64;
+            let mut parts = Vec::with_capacity(chapters.len());
+            let mut prev_end = 0;
+            for chapter in chapters {
+                // Overlapping chapters are cut where the previous one ends
+                let start = frame(chapter.start).max(prev_end);
+                let end = frame(chapter.end);
+                if end <= start {
+                    continue;
+                }
+                prev_end = end;
+                parts.push(Part {
+                    start,
+                    end,
+                    title: chapter.title,
+                });
+            }
+            Ok(Box::new(parts.into_iter()))
+        }
+    }
+}
+
+pub fn split_file(
+    input_bytes: Vec<u8>,
+    ext: &str,
+    output_format: &str,
+    mode: &SplitMode,
+    naming: &Naming,
+    stem: &str,
+    fades: Fades,
+) -> AudioResult<Vec<(String, Vec<u8>)>> {
+    let in_path = spool(&input_bytes, ext)?;
+    split_path(&in_path, output_format, mode, naming, stem, fades)?
+        .into_iter()
+        .map(|(name, path)| {
+            fs::read(&path)
+                .map(|data| (name, data))
+                .map_err(AudioError::io("read split part"))
+        })
+        .collect()
+}
+
+/// Cut `in_path` into parts encoded as `output_format`, named by `naming`.
+/// The audio is decoded once and each frame is routed to its part, so
+/// every cut is sample-accurate; `fades` apply to each part.
+pub fn split_path(
+    in_path: &Path,
+    output_format: &str,
+    mode: &SplitMode,
+    naming: &Naming,
+    stem: &str,
+    fades: Fades,
+) -> AudioResult<Vec<(String, TempPath)>> {
+    let mut dec = Decoder::open(in_path)?;
+    let spec = dec.spec();
+    let channels = spec.channels as usize;
+    let mut parts = plan(in_path, mode, spec.sample_rate)?;
+
+    let mut outputs = Vec::new();
+    let mut part = parts.next();
+    let mut writer: Option<FadeWriter> = None;
+    let mut pos: u64 = 0;
+    // Part that ended with the block; whether it ends at a cut is only known
+    // once the decoder has looked past it.
+    let mut ended: Option<FadeWriter> = None;
+    while let Some(block) = dec.next_block()? {
+        let block_end = pos + (block.len() / channels) as u64;
+        while let Some(p) = &part {
+            let from = p.start.max(pos);
+            let to = p.end.min(block_end);
+            if from < to {
+                if writer.is_none() {
+                    let path = NamedTempFile::new()
+                        .map_err(AudioError::io("tmpfile part"))?
+                        .into_temp_path();
+                    let name =
+                        naming.name(stem, output_format, outputs.len() + 1, p.title.as_deref());
+                    let pcm = PcmWriter::create(&path, spec, output_format, 0)?.tags_from(in_path);
+                    writer = Some(FadeWriter::new(pcm, spec, fades, p.start > 0));
+                    outputs.push((name, path));
+                }
+                let a = (from - pos) as usize * channels;
+                let b = (to - pos) as usize * channels;
+                writer.as_mut().unwrap().write(&block[a..b])?;
+            }
+            if p.end > block_end {
+                break;
+            }
+            if let Some(w) = writer.take() {
+                if p.end < block_end {
+                    w.finish(true)?;
+                } else {
+                    ended = Some(w);
+                }
+            }
+            part = parts.next();
+        }
+        pos = block_end;
+        if let Some(w) = ended.take() {
+            w.finish(!dec.at_end()?)?;
+        }
+        if part.is_none() {
+            break;
+        }
+    }
+    if let Some(w) = writer.take() {
+        w.finish(false)?;
+    }
+
+    if outputs.is_empty() {
+        return Err(AudioError::InvalidArgument(
+            "no audio falls inside the requested parts".into(),
+        ));
+    }
+    Ok(outputs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_wav::{read_i16, wav_i16};
+
+    #[test]
+    fn naming_needs_index_or_title() {
+        assert!(Naming::parse("{stem}.{ext}").is_err());
+        assert!(Naming::parse("{stem}_{index:03}.{ext}").is_ok());
+        assert!(Naming::parse("{title}.{ext}").is_ok());
+        assert!(Naming::parse("{stem}_{size}").is_err());
+    }
+
+    #[test]
+    fn part_ending_at_end_of_input_is_not_faded_out() {
+        let input = wav_i16(8_000, 1, 8_000, |i, _| i as i16);
+
+        let naming = Naming::parse("").unwrap();
+        let parts = split_path(
+            &input,
+            "wav",
+            &SplitMode::Length(500),
+            &naming,
+            "in",
+            Fades::default(),
+        )
+        .unwrap();
+        let samples: Vec<Vec<i16>> = parts.iter().map(|(_, path)| read_i16(path)).collect();
+
+        assert_eq!(samples.len(), 2);
+        assert_eq!(samples[0].last(), Some(&0));
+        assert_eq!(samples[1].first(), Some(&0));
+        assert_eq!(samples[1].last(), Some(&7999));
+    }
+}
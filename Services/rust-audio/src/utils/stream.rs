@@ -0,0 +1,404 @@
+use crate::audio::{
+    AudioChunk, AudioHeader, BoostManualRequest, BoostManualStreamRequest, BoostNormalizeRequest,
+    BoostNormalizeStreamRequest, CompressPercentageRequest, CompressPercentageStreamRequest,
+    CompressQualityRequest, CompressQualityStreamRequest, CompressSizeRequest,
+    CompressSizeStreamRequest, ConvertRequest, ConvertStreamRequest, FadeRequest,
+    FadeStreamRequest, FileChunk, FileResult, LoudnessRequest, LoudnessStreamRequest, MergeRequest,
+    MergeStreamRequest, MetadataRequest, MetadataStreamRequest, PipelineRequest,
+    PipelineStreamRequest, ProbeRequest, ProbeStreamRequest, ReplayGainRequest,
+    ReplayGainStreamRequest, SilenceRequest, SilenceStreamRequest, SpectrogramRequest,
+    SpectrogramStreamRequest, SplitRequest, SplitStreamRequest, TrimRequest, TrimStreamRequest,
+    WaveformRequest, WaveformStreamRequest, audio_chunk,
+};
+use crate::utils::batch::{BatchOutput, ERRORS_FILE, FileOutcome};
+use crate::utils::error::{AudioError, AudioResult};
+use crate::utils::pool::pool;
+use crate::utils::zip::make_zip_path;
+use std::path::Path;
+use std::pin::Pin;
+use tempfile::{Builder, NamedTempFile, TempPath};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio_stream::Stream;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Response, Status, Streaming};
+
+/// Size of the data chunks we stream back to the client.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+pub type AudioChunkStream = Pin<Box<dyn Stream<Item = Result<AudioChunk, Status>> + Send>>;
+
+/// One message of a client-streaming upload.
+pub enum Part<H> {
+    Header(H),
+    Chunk(FileChunk),
+}
+
+/// Implemented by every `*StreamRequest` so the upload loop can be shared.
+pub trait UploadMessage {
+    type Header: UploadHeader;
+    fn into_part(self) -> Option<Part<Self::Header>>;
+}
+
+/// The settings message sent first on a stream; tells us which files to expect.
+pub trait UploadHeader {
+    fn file_names(&self) -> Vec<String>;
+    fn has_inline_data(&self) -> bool;
+}
+
+macro_rules! upload_message {
+    ($msg:ty, $module:ident, $header:ty) => {
+        impl UploadMessage for $msg {
+            type Header = $header;
+            fn into_part(self) -> Option<Part<$header>> {
+                match self.payload? {
+                    crate::audio::$module::Payload::Header(h) => Some(Part::Header(h)),
+                    crate::audio::$module::Payload::Chunk(c) => Some(Part::Chunk(c)),
+                }
+            }
+        }
+    };
+}
+
+macro_rules! batch_header {
+    ($header:ty) => {
+        impl UploadHeader for $header {
+            fn file_names(&self) -> Vec<String> {
+                self.filenames.clone()
+            }
+            fn has_inline_data(&self) -> bool {
+                !self.file_data.is_empty()
+            }
+        }
+    };
+}
+
+macro_rules! single_header {
+    ($header:ty) => {
+        impl UploadHeader for $header {
+            fn file_names(&self) -> Vec<String> {
+                vec![self.filename.clone()]
+            }
+            fn has_inline_data(&self) -> bool {
+                !self.file_data.is_empty()
+            }
+        }
+    };
+}
+
+upload_message!(ConvertStreamRequest, convert_stream_request, ConvertRequest);
+upload_message!(
+    CompressPercentageStreamRequest,
+    compress_percentage_stream_request,
+    CompressPercentageRequest
+);
+upload_message!(
+    CompressSizeStreamRequest,
+    compress_size_stream_request,
+    CompressSizeRequest
+);
+upload_message!(
+    CompressQualityStreamRequest,
+    compress_quality_stream_request,
+    CompressQualityRequest
+);
+upload_message!(TrimStreamRequest, trim_stream_request, TrimRequest);
+upload_message!(SplitStreamRequest, split_stream_request, SplitRequest);
+upload_message!(FadeStreamRequest, fade_stream_request, FadeRequest);
+upload_message!(MergeStreamRequest, merge_stream_request, MergeRequest);
+upload_message!(
+    MetadataStreamRequest,
+    metadata_stream_request,
+    MetadataRequest
+);
+upload_message!(
+    BoostManualStreamRequest,
+    boost_manual_stream_request,
+    BoostManualRequest
+);
+upload_message!(
+    PipelineStreamRequest,
+    pipeline_stream_request,
+    PipelineRequest
+);
+upload_message!(ProbeStreamRequest, probe_stream_request, ProbeRequest);
+upload_message!(
+    SpectrogramStreamRequest,
+    spectrogram_stream_request,
+    SpectrogramRequest
+);
+upload_message!(
+    WaveformStreamRequest,
+    waveform_stream_request,
+    WaveformRequest
+);
+upload_message!(
+    ReplayGainStreamRequest,
+    replay_gain_stream_request,
+    ReplayGainRequest
+);
+upload_message!(
+    LoudnessStreamRequest,
+    loudness_stream_request,
+    LoudnessRequest
+);
+upload_message!(SilenceStreamRequest, silence_stream_request, SilenceRequest);
+upload_message!(
+    BoostNormalizeStreamRequest,
+    boost_normalize_stream_request,
+    BoostNormalizeRequest
+);
+
+batch_header!(ConvertRequest);
+batch_header!(CompressPercentageRequest);
+batch_header!(CompressSizeRequest);
+batch_header!(CompressQualityRequest);
+batch_header!(MergeRequest);
+batch_header!(BoostManualRequest);
+batch_header!(BoostNormalizeRequest);
+batch_header!(ProbeRequest);
+batch_header!(LoudnessRequest);
+batch_header!(ReplayGainRequest);
+batch_header!(SilenceRequest);
+single_header!(TrimRequest);
+single_header!(SplitRequest);
+single_header!(FadeRequest);
+single_header!(MetadataRequest);
+single_header!(PipelineRequest);
+single_header!(WaveformRequest);
+single_header!(SpectrogramRequest);
+
+/// A fully received upload: the header plus one spooled temp file per filename.
+pub struct Upload<H> {
+    pub header: H,
+    pub files: Vec<(String, TempPath)>,
+}
+
+struct Spool {
+    file: tokio::fs::File,
+    path: TempPath,
+    written: u64,
+}
+
+/// Drain a client stream, writing each file's chunks to its own temp file so
+/// memory use does not grow with the upload size.
+pub async fn receive_upload<M: UploadMessage>(
+    mut stream: Streaming<M>,
+) -> Result<Upload<M::Header>, Status> {
+    let header = match stream.message().await?.and_then(UploadMessage::into_part) {
+        Some(Part::Header(h)) => h,
+        Some(Part::Chunk(_)) => {
+            return Err(Status::invalid_argument(
+                "first stream message must be the header",
+            ));
+        }
+        None => return Err(Status::invalid_argument("empty upload stream")),
+    };
+
+    if header.has_inline_data() {
+        return Err(Status::invalid_argument(
+            "file_data must be sent as chunks, not in the header",
+        ));
+    }
+
+    let names = header.file_names();
+    if names.is_empty() {
+        return Err(Status::invalid_argument("no files provided"));
+    }
+
+    let mut spools = Vec::with_capacity(names.len());
+    for name in &names {
+        let ext = Path::new(name)
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or("dat");
+        let tmp = Builder::new()
+            .suffix(&format!(".{}", ext))
+            .tempfile()
+            .map_err(AudioError::io("tmpfile"))?;
+        let (file, path) = tmp.into_parts();
+        spools.push(Spool {
+            file: tokio::fs::File::from_std(file),
+            path,
+            written: 0,
+        });
+    }
+
+    while let Some(msg) = stream.message().await? {
+        let chunk = match msg.into_part() {
+            Some(Part::Chunk(c)) => c,
+            Some(Part::Header(_)) => {
+                return Err(Status::invalid_argument("header sent twice"));
+            }
+            None => continue,
+        };
+
+        let spool = spools
+            .get_mut(chunk.file_index as usize)
+            .ok_or_else(|| Status::invalid_argument("file_index out of range"))?;
+        spool
+            .file
+            .write_all(&chunk.data)
+            .await
+            .map_err(AudioError::io("write spool"))?;
+        spool.written += chunk.data.len() as u64;
+    }
+
+    let mut files = Vec::with_capacity(spools.len());
+    for (name, mut spool) in names.into_iter().zip(spools) {
+        if spool.written == 0 {
+            return Err(Status::invalid_argument(format!(
+                "no data received for {}",
+                name
+            )));
+        }
+        spool
+            .file
+            .flush()
+            .await
+            .map_err(AudioError::io("flush spool"))?;
+        files.push((name, spool.path));
+    }
+
+    Ok(Upload { header, files })
+}
+
+/// Fresh temp path for a processing step to write its output into.
+pub fn output_path() -> AudioResult<TempPath> {
+    NamedTempFile::new()
+        .map(NamedTempFile::into_temp_path)
+        .map_err(AudioError::io("tmpfile out"))
+}
+
+/// Read path-based batch outputs back into memory for a unary response.
+pub async fn read_outputs<R>(
+    outcomes: Vec<FileOutcome<(TempPath, R)>>,
+) -> Vec<FileOutcome<(Vec<u8>, R)>> {
+    let mut read = Vec::with_capacity(outcomes.len());
+    for outcome in outcomes {
+        read.push(match outcome {
+            Ok((name, (path, report))) => tokio::fs::read(&path)
+                .await
+                .map(|bytes| (name, (bytes, report)))
+                .map_err(AudioError::io("read tmp out")),
+            Err(e) => Err(e),
+        });
+    }
+    read
+}
+
+/// Stream the result of a batch back: a single output is sent as-is, several
+/// outputs are zipped on disk first (same naming and errors.txt as
+/// `batch::respond`).
+pub async fn send_outputs(
+    batch: BatchOutput<TempPath>,
+    format: Option<String>,
+) -> Result<Response<AudioChunkStream>, Status> {
+    if batch.outputs.is_empty() && batch.has_failures() {
+        return Err(batch.failure());
+    }
+
+    if batch.outputs.len() == 1 && !batch.has_failures() {
+        let BatchOutput {
+            outputs, results, ..
+        } = batch;
+        let (filename, path) = outputs.into_iter().next().unwrap();
+        let format =
+            format.unwrap_or_else(|| filename.split('.').next_back().unwrap_or("mp3").to_string());
+        return Ok(Response::new(send_file_with_results(
+            path, format, filename, results,
+        )));
+    }
+
+    let report = batch.has_failures().then(|| batch.error_report());
+    let BatchOutput {
+        mut outputs,
+        results,
+        ..
+    } = batch;
+    if let Some(report) = report {
+        let report_path = output_path()?;
+        tokio::fs::write(&report_path, report)
+            .await
+            .map_err(AudioError::io("write errors.txt"))?;
+        outputs.push((ERRORS_FILE.to_string(), report_path));
+    }
+
+    let zip_path = output_path()?;
+    let zip_path = pool()
+        .run(move || make_zip_path(&outputs, &zip_path).map(|_| zip_path))
+        .await??;
+
+    Ok(Response::new(send_file_with_results(
+        zip_path,
+        "zip".to_string(),
+        "sonic-tools.zip".to_string(),
+        results,
+    )))
+}
+
+/// Stream a file from disk as one header followed by `CHUNK_SIZE` data chunks.
+/// The temp file is removed once the last chunk has been sent.
+pub fn send_file(path: TempPath, format: String, filename: String) -> AudioChunkStream {
+    send_file_with_results(path, format, filename, Vec::new())
+}
+
+/// `send_file` for batch outputs: the header also carries the per-file results.
+fn send_file_with_results(
+    path: TempPath,
+    format: String,
+    filename: String,
+    results: Vec<FileResult>,
+) -> AudioChunkStream {
+    let (tx, rx) = mpsc::channel(4);
+
+    tokio::spawn(async move {
+        let mut file = match tokio::fs::File::open(&path).await {
+            Ok(f) => f,
+            Err(e) => {
+                let _ = tx
+                    .send(Err(Status::internal(format!("open output: {}", e))))
+                    .await;
+                return;
+            }
+        };
+        let size = file.metadata().await.map(|m| m.len()).unwrap_or(0);
+
+        let header = AudioChunk {
+            payload: Some(audio_chunk::Payload::Header(AudioHeader {
+                format,
+                filename,
+                size,
+                results,
+            })),
+        };
+        if tx.send(Ok(header)).await.is_err() {
+            return; // client went away
+        }
+
+        loop {
+            let mut buf = vec![0u8; CHUNK_SIZE];
+            let n = match file.read(&mut buf).await {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) => {
+                    let _ = tx
+                        .send(Err(Status::internal(format!("read output: {}", e))))
+                        .await;
+                    return;
+                }
+            };
+            buf.truncate(n);
+            let chunk = AudioChunk {
+                payload: Some(audio_chunk::Payload::Data(buf)),
+            };
+            if tx.send(Ok(chunk)).await.is_err() {
+                return;
+            }
+        }
+
+        drop(path);
+    });
+
+    Box::pin(ReceiverStream::new(rx))
+}
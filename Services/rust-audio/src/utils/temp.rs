@@ -1,9 +1,20 @@
+use crate::utils::error::{AudioError, AudioResult};
 use tempfile::{NamedTempFile, TempPath};
 
-pub fn make_temp_with_ext(ext: &str) -> Result<(TempPath, String), String> {
-    let file = NamedTempFile::new().map_err(|e| format!("tmpfile: {}", e))?;
+pub fn make_temp_with_ext(ext: &str) -> AudioResult<(TempPath, String)> {
+    let file = NamedTempFile::new().map_err(AudioError::io("tmpfile"))?;
     let path = file.into_temp_path();
     // Append extension so ffmpeg knows format
     let path_str = format!("{}.{}", path.to_str().unwrap(), ext);
     Ok((path, path_str))
 }
+
+/// Write `bytes` to a temp file ending in `.{ext}`, so decoders can sniff it.
+pub fn spool(bytes: &[u8], ext: &str) -> AudioResult<TempPath> {
+    let tmp = tempfile::Builder::new()
+        .suffix(&format!(".{}", ext))
+        .tempfile()
+        .map_err(AudioError::io("tmpfile in"))?;
+    std::fs::write(tmp.path(), bytes).map_err(AudioError::io("write tmp in"))?;
+    Ok(tmp.into_temp_path())
+}
@@ -0,0 +1,80 @@
+//! WAV fixtures shared by the unit tests.
+
+use hound::{SampleFormat, WavReader, WavSpec, WavWriter};
+use std::path::Path;
+use tempfile::{NamedTempFile, TempPath};
+
+fn create(sample_rate: u32, channels: u16, float: bool) -> (TempPath, WavSpec) {
+    let path = NamedTempFile::with_suffix(".wav").unwrap().into_temp_path();
+    let spec = WavSpec {
+        channels,
+        sample_rate,
+        bits_per_sample: if float { 32 } else { 16 },
+        sample_format: if float {
+            SampleFormat::Float
+        } else {
+            SampleFormat::Int
+        },
+    };
+    (path, spec)
+}
+
+/// 16-bit WAV of `frames` frames; `sample(frame, channel)` gives each value.
+pub fn wav_i16(
+    sample_rate: u32,
+    channels: u16,
+    frames: u32,
+    sample: impl Fn(u32, u16) -> i16,
+) -> TempPath {
+    let (path, spec) = create(sample_rate, channels, false);
+    let mut writer = WavWriter::create(&path, spec).unwrap();
+    for i in 0..frames {
+        for c in 0..channels {
+            writer.write_sample(sample(i, c)).unwrap();
+        }
+    }
+    writer.finalize().unwrap();
+    path
+}
+
+/// Float WAV of `frames` frames; `sample(frame, channel)` gives each value.
+pub fn wav_f32(
+    sample_rate: u32,
+    channels: u16,
+    frames: u32,
+    sample: impl Fn(u32, u16) -> f32,
+) -> TempPath {
+    let (path, spec) = create(sample_rate, channels, true);
+    let mut writer = WavWriter::create(&path, spec).unwrap();
+    for i in 0..frames {
+        for c in 0..channels {
+            writer.write_sample(sample(i, c)).unwrap();
+        }
+    }
+    writer.finalize().unwrap();
+    path
+}
+
+/// 16-bit WAV whose frame `i` holds `i % 10000` on every channel, so a
+/// sample's value tells where it came from.
+pub fn ramp_wav(sample_rate: u32, channels: u16, frames: u32) -> TempPath {
+    wav_i16(sample_rate, channels, frames, |i, _| (i % 10000) as i16)
+}
+
+/// Sine of `hz` with peak `amplitude` (linear) at `sample_rate`, on every
+/// channel.
+pub fn sine(hz: f64, amplitude: f64, sample_rate: u32) -> impl Fn(u32, u16) -> f32 {
+    move |i, _| {
+        let t = i as f64 / sample_rate as f64;
+        (amplitude * (2.0 * std::f64::consts::PI * hz * t).sin()) as f32
+    }
+}
+
+/// Interleaved samples of a 16-bit WAV.
+pub fn read_i16(path: &Path) -> Vec<i16> {
+    WavReader::open(path)
+        .unwrap()
+        .samples()
+        .map(Result::unwrap)
+        .collect()
+}
@@ -1,215 +1,487 @@
-use crate::utils::conversion::convert_file;
+use crate::utils::error::{AudioError, AudioResult};
+use crate::utils::fade::{FadeWriter, Fades};
+use crate::utils::native::{Decoder, PcmWriter, copy_ranges};
+use crate::utils::silence::{SilenceOptions, detect_silence_path};
+use std::fs;
+use std::path::Path;
+use tempfile::NamedTempFile;
 
-pub fn trim_file(
-    input_bytes: Vec<u8>,
-    output_format: &str,
-    start_sec: Option<i32>,
-    end_sec: Option<i32>,
-    action: &str, // "keep" or "remove"
-) -> Result<Vec<u8>, String> {
-    use std::fs;
-    use std::io::Write;
-    use std::process::Command;
-    use tempfile::NamedTempFile;
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
 
-    // Sanity checks
-    if let (Some(s), Some(e)) = (start_sec, end_sec) {
-        if e <= s {
-            return Err("end_sec must be greater than start_sec".into());
-        }
+/// A cut position, resolved to a frame index once the sample rate is known.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Position {
+    /// Time from the start, in nanoseconds; rounds to the nearest frame.
+    Time(u64),
+    /// Frame index (one sample per channel).
+    Frame(u64),
+}
+
+impl Position {
+    pub fn seconds(s: u64) -> AudioResult<Self> {
+        Self::scaled(s, NANOS_PER_SECOND, "s")
     }
-    if action != "keep" && action != "remove" {
-        return Err("Invalid action (must be 'keep' or 'remove')".into());
-    }
-
-    // 1) Decode input -> WAV (robust intermediate)
-    // We don't know the input extension here; pass None.
-    let wav_bytes = convert_file(input_bytes, "wav", 0, None)?;
-
-    // Persist WAV to temp file for ffmpeg
-    let tmp_wav_in = NamedTempFile::new().map_err(|e| format!("tmpfile wav in: {}", e))?;
-    fs::write(tmp_wav_in.path(), &wav_bytes).map_err(|e| format!("write wav in: {}", e))?;
-    let wav_in_path = tmp_wav_in.into_temp_path();
-
-    // 2) Prepare temp output (still WAV after trimming/concatenation)
-    let tmp_wav_out = NamedTempFile::new().map_err(|e| format!("tmpfile wav out: {}", e))?;
-    let wav_out_path = tmp_wav_out.into_temp_path();
-
-    match action {
-        "keep" => {
-            // Single segment: [start, end) copied out of WAV
-            let mut args: Vec<String> = vec![
-                "-y".into(),
-                "-hide_banner".into(),
-                "-loglevel".into(),
-                "error".into(),
-                "-i".into(),
-                wav_in_path.to_str().ok_or("bad wav_in_path")?.into(),
-            ];
-
-            if let Some(s) = start_sec {
-                args.push("-ss".into());
-                args.push(s.to_string());
-            }
-            if let Some(e) = end_sec {
-                args.push("-to".into());
-                args.push(e.to_string());
-            }
 
-            // copy PCM samples to new WAV
-            args.extend([
-                "-c".into(),
-                "copy".into(),
-                "-f".into(),
-                "wav".into(),
-                wav_out_path.to_str().ok_or("bad wav_out_path")?.into(),
-            ]);
-
-            let out = Command::new("ffmpeg")
-                .args(&args)
-                .output()
-                .map_err(|e| format!("ffmpeg trim keep exec: {}", e))?;
-            if !out.status.success() {
-                return Err(format!(
-                    "ffmpeg trim keep failed: {}",
-                    String::from_utf8_lossy(&out.stderr)
-                ));
-            }
+    pub fn millis(ms: u64) -> AudioResult<Self> {
+        Self::scaled(ms, 1_000_000, "ms")
+    }
 
-            let trimmed_wav =
-                fs::read(&wav_out_path).map_err(|e| format!("read trimmed wav: {}", e))?;
-            // 3) Re-encode to requested container/codec using your central plan
-            let final_bytes = convert_file(trimmed_wav, output_format, 0, Some("wav"))?;
-            Ok(final_bytes)
+    /// `value` units of `nanos` each, rejecting times too long to represent.
+    fn scaled(value: u64, nanos: u64, unit: &str) -> AudioResult<Self> {
+        value.checked_mul(nanos).map(Self::Time).ok_or_else(|| {
+            AudioError::InvalidArgument(format!("position {} {} is too large", value, unit))
+        })
+    }
+
+    /// Parse a `[[HH:]MM:]SS[.fraction]` timecode, e.g. `1:02:03.5` or `12.35`.
+    pub fn timecode(text: &str) -> AudioResult<Self> {
+        let invalid = || {
+            AudioError::InvalidArgument(format!(
+                "Invalid timecode '{}' (expected [[HH:]MM:]SS[.fff])",
+                text
+            ))
+        };
+        let (whole, fraction) = text.trim().split_once('.').unwrap_or((text.trim(), ""));
+        if fraction.len() > 9 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(invalid());
         }
 
-        "remove" => {
-            use tempfile::TempPath;
-
-            let mut parts: Vec<String> = Vec::new();
-            let mut keepers: Vec<TempPath> = Vec::new(); // keep temp files alive
-
-            // Segment 1: before start
-            if let Some(s) = start_sec {
-                let p1 = NamedTempFile::new().map_err(|e| format!("tmpfile part1: {}", e))?;
-                let p1_path = p1.into_temp_path();
-                let args = vec![
-                    "-y".into(),
-                    "-hide_banner".into(),
-                    "-loglevel".into(),
-                    "error".into(),
-                    "-i".into(),
-                    wav_in_path.to_str().ok_or("bad wav_in_path")?.into(),
-                    "-to".into(),
-                    s.to_string(),
-                    "-c".into(),
-                    "copy".into(),
-                    "-f".into(),
-                    "wav".into(),
-                    p1_path.to_str().ok_or("bad p1")?.into(),
-                ];
-                let out = Command::new("ffmpeg")
-                    .args(&args)
-                    .output()
-                    .map_err(|e| format!("ffmpeg part1 exec: {}", e))?;
-                if !out.status.success() {
-                    return Err(format!(
-                        "ffmpeg part1 failed: {}",
-                        String::from_utf8_lossy(&out.stderr)
-                    ));
-                }
-                parts.push(p1_path.to_string_lossy().into_owned());
-                keepers.push(p1_path);
+        let parts: Vec<&str> = whole.split(':').collect();
+        if parts.len() > 3 {
+            return Err(invalid());
+        }
+        let mut seconds: u64 = 0;
+        for (i, part) in parts.iter().enumerate() {
+            if part.is_empty() || !part.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(invalid());
             }
-
-            // Segment 2: after end
-            if let Some(e) = end_sec {
-                let p2 = NamedTempFile::new().map_err(|e| format!("tmpfile part2: {}", e))?;
-                let p2_path = p2.into_temp_path();
-                let args = vec![
-                    "-y".into(),
-                    "-hide_banner".into(),
-                    "-loglevel".into(),
-                    "error".into(),
-                    "-i".into(),
-                    wav_in_path.to_str().ok_or("bad wav_in_path")?.into(),
-                    "-ss".into(),
-                    e.to_string(),
-                    "-c".into(),
-                    "copy".into(),
-                    "-f".into(),
-                    "wav".into(),
-                    p2_path.to_str().ok_or("bad p2")?.into(),
-                ];
-                let out = Command::new("ffmpeg")
-                    .args(&args)
-                    .output()
-                    .map_err(|e| format!("ffmpeg part2 exec: {}", e))?;
-                if !out.status.success() {
-                    return Err(format!(
-                        "ffmpeg part2 failed: {}",
-                        String::from_utf8_lossy(&out.stderr)
-                    ));
-                }
-                parts.push(p2_path.to_string_lossy().into_owned());
-                keepers.push(p2_path);
+            let value: u64 = part.parse().map_err(|_| invalid())?;
+            // Minutes and seconds after the leading field stay below 60
+            if i > 0 && value >= 60 {
+                return Err(invalid());
             }
+            seconds = seconds
+                .checked_mul(60)
+                .and_then(|s| s.checked_add(value))
+                .ok_or_else(invalid)?;
+        }
 
-            if parts.is_empty() {
-                // If neither start nor end was provided, there's nothing to remove
-                return Err("no parts to keep after removal".into());
-            }
+        let nanos = format!("{:0<9}", fraction).parse::<u64>().unwrap_or(0);
+        seconds
+            .checked_mul(NANOS_PER_SECOND)
+            .and_then(|s| s.checked_add(nanos))
+            .map(Self::Time)
+            .ok_or_else(invalid)
+    }
 
-            // Concat list file (for WAV parts)
-            let concat_list =
-                NamedTempFile::new().map_err(|e| format!("tmpfile concat list: {}", e))?;
-            let concat_path = concat_list.into_temp_path();
-            {
-                let mut f = fs::File::create(&concat_path)
-                    .map_err(|e| format!("create concat list: {}", e))?;
-                for p in &parts {
-                    // escape single quotes
-                    let esc = p.replace('\'', "'\\''");
-                    writeln!(f, "file '{}'", esc)
-                        .map_err(|e| format!("write concat list: {}", e))?;
-                }
+    /// Frame index at `sample_rate`.
+    pub fn frame(self, sample_rate: u32) -> u64 {
+        match self {
+            Self::Frame(frame) => frame,
+            Self::Time(nanos) => {
+                let scaled = nanos as u128 * sample_rate as u128;
+                ((scaled + NANOS_PER_SECOND as u128 / 2) / NANOS_PER_SECOND as u128) as u64
             }
+        }
+    }
+}
 
-            // Concat WAV parts -> single WAV
-            let out = Command::new("ffmpeg")
-                .args([
-                    "-y",
-                    "-hide_banner",
-                    "-loglevel",
-                    "error",
-                    "-f",
-                    "concat",
-                    "-safe",
-                    "0",
-                    "-i",
-                    concat_path.to_str().ok_or("bad concat path")?,
-                    "-c",
-                    "copy",
-                    "-f",
-                    "wav",
-                    wav_out_path.to_str().ok_or("bad wav_out_path")?,
-                ])
-                .output()
-                .map_err(|e| format!("ffmpeg concat exec: {}", e))?;
-            if !out.status.success() {
-                return Err(format!(
-                    "ffmpeg concat failed: {}",
-                    String::from_utf8_lossy(&out.stderr)
-                ));
+/// A `[start, end)` segment; an open start is the beginning of the file, an
+/// open end its end.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Range {
+    pub start: Option<Position>,
+    pub end: Option<Position>,
+}
+
+/// Resolve `ranges` at `sample_rate` into the frames to copy, in order.
+/// Ranges must be ascending and must not overlap (touching is fine); for
+/// "remove" the result is what lies between them.
+pub(crate) fn frame_ranges(
+    ranges: &[Range],
+    sample_rate: u32,
+    action: &str,
+) -> AudioResult<Vec<(u64, u64)>> {
+    let mut resolved: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (i, range) in ranges.iter().enumerate() {
+        let start = range.start.map_or(0, |p| p.frame(sample_rate));
+        let end = range.end.map_or(u64::MAX, |p| p.frame(sample_rate));
+        if end <= start {
+            return Err(AudioError::InvalidArgument(format!(
+                "range {}: end must be after start",
+                i
+            )));
+        }
+        if let Some(&(prev_start, prev_end)) = resolved.last() {
+            if start < prev_start {
+                return Err(AudioError::InvalidArgument(format!(
+                    "range {} starts before range {}; ranges must be in order",
+                    i,
+                    i - 1
+                )));
             }
+            if start < prev_end {
+                return Err(AudioError::InvalidArgument(format!(
+                    "range {} overlaps range {}",
+                    i,
+                    i - 1
+                )));
+            }
+        }
+        resolved.push((start, end));
+    }
 
-            // 3) Re-encode concatenated WAV to the requested format
-            let merged_wav =
-                fs::read(&wav_out_path).map_err(|e| format!("read merged wav: {}", e))?;
-            let final_bytes = convert_file(merged_wav, output_format, 0, Some("wav"))?;
-            Ok(final_bytes)
+    if action == "keep" {
+        return Ok(resolved);
+    }
+    let mut kept = Vec::with_capacity(resolved.len() + 1);
+    let mut pos = 0;
+    for (start, end) in resolved {
+        if start > pos {
+            kept.push((pos, start));
         }
+        pos = end;
+    }
+    if pos < u64::MAX {
+        kept.push((pos, u64::MAX));
+    }
+    if kept.is_empty() {
+        return Err(AudioError::InvalidArgument(
+            "no parts to keep after removal".into(),
+        ));
+    }
+    Ok(kept)
+}
+
+pub fn trim_file(
+    input_bytes: Vec<u8>,
+    output_format: &str,
+    ranges: &[Range],
+    action: &str, // "keep" or "remove"
+    fades: Fades,
+) -> AudioResult<Vec<u8>> {
+    let tmp_in = NamedTempFile::new().map_err(AudioError::io("tmpfile in"))?;
+    fs::write(tmp_in.path(), &input_bytes).map_err(AudioError::io("write tmp in"))?;
+    let in_path = tmp_in.into_temp_path();
+
+    let tmp_out = NamedTempFile::new().map_err(AudioError::io("tmpfile out"))?;
+    let out_path = tmp_out.into_temp_path();
+
+    trim_path(&in_path, &out_path, output_format, ranges, action, fades)?;
+
+    fs::read(&out_path).map_err(AudioError::io("read tmp out"))
+}
+
+/// Path-based core of `trim_file`: keep or remove every range in one pass.
+/// Cuts are made on decoded frames, so positions are sample-accurate
+/// whatever the input codec; `fades` shape the edges.
+pub fn trim_path(
+    in_path: &Path,
+    out_path: &Path,
+    output_format: &str,
+    ranges: &[Range],
+    action: &str, // "keep" or "remove"
+    fades: Fades,
+) -> AudioResult<()> {
+    // Sanity checks
+    if action != "keep" && action != "remove" {
+        return Err(AudioError::InvalidArgument(
+            "Invalid action (must be 'keep', 'remove' or 'strip_silence')".into(),
+        ));
+    }
+    if ranges.is_empty() {
+        return Err(AudioError::InvalidArgument("no trim ranges given".into()));
+    }
+
+    // Frames to keep, in order
+    let mut dec = Decoder::open(in_path)?;
+    let ranges = frame_ranges(ranges, dec.spec().sample_rate, action)?;
+
+    // Copy the kept samples and encode once (WAV is written directly)
+    write_ranges(
+        &mut dec,
+        out_path,
+        output_format,
+        &ranges,
+        fades,
+        action == "keep",
+    )
+}
+
+/// Copy `ranges` of `dec` into a new output, fading its edges and cuts.
+/// `requested`: the ranges are the caller's keep ranges, so one starting
+/// after the end of the input is an error rather than nothing to copy.
+fn write_ranges(
+    dec: &mut Decoder,
+    out_path: &Path,
+    output_format: &str,
+    ranges: &[(u64, u64)],
+    fades: Fades,
+    requested: bool,
+) -> AudioResult<()> {
+    let writer = PcmWriter::create(out_path, dec.spec(), output_format, 0)?;
+    let cut_in = ranges.first().is_some_and(|&(start, _)| start > 0);
+    let mut out = FadeWriter::new(writer, dec.spec(), fades, cut_in);
+    let copied = copy_ranges(dec, &mut out, ranges)?;
+    if requested && let Some(i) = ranges.iter().position(|&(start, _)| start >= copied.read) {
+        return Err(AudioError::InvalidArgument(format!(
+            "range {} starts after end of input",
+            i
+        )));
+    }
+    out.finish(copied.cut_out)
+}
+
+pub fn strip_silence_file(
+    input_bytes: Vec<u8>,
+    output_format: &str,
+    silence: &SilenceOptions,
+    max_gap_ms: Option<u32>,
+    fades: Fades,
+) -> AudioResult<Vec<u8>> {
+    let tmp_in = NamedTempFile::new().map_err(AudioError::io("tmpfile in"))?;
+    fs::write(tmp_in.path(), &input_bytes).map_err(AudioError::io("write tmp in"))?;
+    let in_path = tmp_in.into_temp_path();
+
+    let tmp_out = NamedTempFile::new().map_err(AudioError::io("tmpfile out"))?;
+    let out_path = tmp_out.into_temp_path();
+
+    strip_silence_path(
+        &in_path,
+        &out_path,
+        output_format,
+        silence,
+        max_gap_ms,
+        fades,
+    )?;
+
+    fs::read(&out_path).map_err(AudioError::io("read tmp out"))
+}
+
+/// Drop leading and trailing silence and, with `max_gap_ms`, shorten longer
+/// internal silences to that length. Detection runs over the decoded audio
+/// first, then the kept frames are copied in a second pass.
+pub fn strip_silence_path(
+    in_path: &Path,
+    out_path: &Path,
+    output_format: &str,
+    silence: &SilenceOptions,
+    max_gap_ms: Option<u32>,
+    fades: Fades,
+) -> AudioResult<()> {
+    let ranges = detect_silence_path(in_path, silence)?.keep_ranges(max_gap_ms)?;
+
+    let mut dec = Decoder::open(in_path)?;
+    write_ranges(&mut dec, out_path, output_format, &ranges, fades, false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_wav::{ramp_wav, read_i16};
+
+    /// Trim to WAV without fades and return the output's samples.
+    fn trim(input: &Path, ranges: &[Range], action: &str) -> Vec<i16> {
+        let fades = Fades {
+            micro: false,
+            ..Fades::default()
+        };
+        trim_with(input, ranges, action, fades)
+    }
+
+    fn trim_with(input: &Path, ranges: &[Range], action: &str, fades: Fades) -> Vec<i16> {
+        let out = NamedTempFile::with_suffix(".wav").unwrap().into_temp_path();
+        trim_path(input, &out, "wav", ranges, action, fades).unwrap();
+        read_i16(&out)
+    }
+
+    #[test]
+    fn keeps_millisecond_range_exactly() {
+        let input = ramp_wav(48_000, 2, 144_000);
+        let range = Range {
+            start: Some(Position::millis(1234).unwrap()),
+            end: Some(Position::millis(2345).unwrap()),
+        };
+        let out = trim(&input, &[range], "keep");
+
+        // 1.234 s and 2.345 s at 48 kHz are frames 59232 and 112560
+        assert_eq!(out.len(), (112_560 - 59_232) * 2);
+        assert_eq!(&out[..2], &[9232, 9232]);
+        assert_eq!(&out[out.len() - 2..], &[2559, 2559]);
+    }
+
+    #[test]
+    fn removes_sample_range_exactly() {
+        let input = ramp_wav(48_000, 2, 48_000);
+        let range = Range {
+            start: Some(Position::Frame(1000)),
+            end: Some(Position::Frame(2500)),
+        };
+        let out = trim(&input, &[range], "remove");
+
+        assert_eq!(out.len(), (48_000 - 1500) * 2);
+        assert_eq!(&out[1998..2002], &[999, 999, 2500, 2500]);
+    }
+
+    #[test]
+    fn keeps_from_timecode_to_end() {
+        let input = ramp_wav(44_100, 2, 88_200);
+        let start = Position::timecode("00:00:01.25").unwrap();
+        let range = Range {
+            start: Some(start),
+            end: None,
+        };
+        let out = trim(&input, &[range], "keep");
+
+        assert_eq!(out.len(), (88_200 - 55_125) * 2);
+        assert_eq!(&out[..2], &[5125, 5125]);
+    }
+
+    #[test]
+    fn removes_several_ranges_in_one_pass() {
+        let input = ramp_wav(8_000, 2, 8_000);
+        let frames = |start, end| Range {
+            start: Some(Position::Frame(start)),
+            end: Some(Position::Frame(end)),
+        };
+        let ranges = [frames(0, 100), frames(1000, 1500), frames(1500, 1600)];
+        let out = trim(&input, &ranges, "remove");
+
+        assert_eq!(out.len(), (8_000 - 100 - 600) * 2);
+        assert_eq!(&out[..2], &[100, 100]);
+        assert_eq!(&out[1798..1802], &[999, 999, 1600, 1600]);
+    }
+
+    #[test]
+    fn micro_fades_cut_edges_without_changing_length() {
+        let input = ramp_wav(8_000, 2, 8_000);
+        let range = Range {
+            start: Some(Position::Frame(2000)),
+            end: Some(Position::Frame(3000)),
+        };
+        let out = trim_with(&input, &[range], "remove", Fades::default());
+
+        assert_eq!(out.len(), 7_000 * 2);
+        // Both sides of the cut meet at silence; away from it nothing changes
+        assert_eq!(&out[3998..4002], &[0, 0, 0, 0]);
+        assert_eq!(&out[..2], &[0, 0]);
+        assert_eq!(&out[2000..2002], &[1000, 1000]);
+        assert_eq!(&out[out.len() - 2..], &[7999, 7999]);
+    }
+
+    #[test]
+    fn range_ending_at_end_of_input_is_not_faded_out() {
+        let input = ramp_wav(8_000, 2, 8_000);
+        let range = |end| Range {
+            start: Some(Position::Frame(1000)),
+            end: Some(Position::Frame(end)),
+        };
+
+        let out = trim_with(&input, &[range(8_000)], "keep", Fades::default());
+        assert_eq!(out.len(), 7_000 * 2);
+        assert_eq!(&out[..2], &[0, 0]);
+        assert_eq!(&out[out.len() - 2..], &[7999, 7999]);
+
+        let out = trim_with(&input, &[range(7_000)], "keep", Fades::default());
+        assert_eq!(&out[out.len() - 2..], &[0, 0]);
+    }
+
+    #[test]
+    fn rejects_keep_range_starting_after_end_of_input() {
+        let input = ramp_wav(8_000, 2, 8_000);
+        let out = NamedTempFile::with_suffix(".wav").unwrap().into_temp_path();
+        let range = |start| Range {
+            start: Some(Position::Frame(start)),
+            end: None,
+        };
+
+        let err = trim_path(
+            &input,
+            &out,
+            "wav",
+            &[range(9_000)],
+            "keep",
+            Fades::default(),
+        );
+        assert!(matches!(err, Err(AudioError::InvalidArgument(_))));
+        let err = trim_path(
+            &input,
+            &out,
+            "wav",
+            &[range(8_000)],
+            "keep",
+            Fades::default(),
+        );
+        assert!(matches!(err, Err(AudioError::InvalidArgument(_))));
+        // Removing past the end leaves the input as it is
+        assert_eq!(trim(&input, &[range(9_000)], "remove").len(), 8_000 * 2);
+    }
+
+    #[test]
+    fn fades_span_cuts_between_ranges() {
+        let input = ramp_wav(8_000, 2, 8_000);
+        let frames = |start, end| Range {
+            start: Some(Position::Frame(start)),
+            end: Some(Position::Frame(end)),
+        };
+        let ranges = [frames(1000, 2000), frames(4000, 5000), frames(7000, 7500)];
+        // 2000 frames in, longer than the first range; 1000 out, longer than the last
+        let fades = Fades {
+            in_ms: 250,
+            out_ms: 125,
+            ..Fades::default()
+        };
+        let out = trim_with(&input, &ranges, "keep", fades);
+        assert_eq!(out.len(), 2_500 * 2);
+
+        let expect = |frame: usize, source: f64, gain: f64| {
+            let got = out[frame * 2] as f64;
+            assert!(
+                (got - source * gain).abs() <= 2.0,
+                "frame {}: {} != {}",
+                frame,
+                got,
+                source * gain
+            );
+        };
+        // The fade-in goes on past the first cut
+        expect(1100, 4100.0, 0.55);
+        // The fade-out reaches back past the last cut
+        expect(1600, 4600.0, 0.8 * 0.899);
+        expect(2200, 7200.0, 0.299);
+        // Both sides of each cut still get their micro-fade
+        assert_eq!(&out[1998..2002], &[0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn rejects_unordered_or_overlapping_ranges() {
+        let frames = |start, end| Range {
+            start: Some(Position::Frame(start)),
+            end: Some(Position::Frame(end)),
+        };
+        assert!(frame_ranges(&[frames(500, 600), frames(100, 200)], 8_000, "keep").is_err());
+        assert!(frame_ranges(&[frames(100, 600), frames(500, 700)], 8_000, "keep").is_err());
+        assert!(frame_ranges(&[frames(100, 100)], 8_000, "keep").is_err());
+        assert!(frame_ranges(&[Range::default()], 8_000, "remove").is_err());
+    }
+
+    #[test]
+    fn parses_timecodes() {
+        assert_eq!(
+            Position::timecode("1:02:03.5").unwrap(),
+            Position::Time(3_723_500_000_000)
+        );
+        assert_eq!(Position::timecode("12.35").unwrap().frame(44_100), 544_635);
+        assert!(Position::timecode("1:60").is_err());
+        assert!(Position::timecode("1:2:3:4").is_err());
+        assert!(Position::timecode("abc").is_err());
+    }
 
-        _ => unreachable!(),
+    #[test]
+    fn rejects_positions_too_large_to_represent() {
+        assert!(Position::millis(u64::MAX).is_err());
+        assert!(Position::seconds(u64::MAX).is_err());
+        assert!(Position::timecode("99999999999999999999").is_err());
+        assert!(Position::timecode("5124095576:00:00").is_err());
     }
 }
@@ -0,0 +1,223 @@
+use crate::utils::error::{AudioError, AudioResult};
+use crate::utils::native::Decoder;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+use tempfile::Builder;
+
+/// audiowaveform data format version we produce (multi-channel capable).
+const DAT_VERSION: i32 = 2;
+/// Header flag: samples are stored as 8-bit instead of 16-bit.
+const FLAG_8BIT: u32 = 1;
+
+/// Min/max peaks per pixel and channel, laid out like audiowaveform's data:
+/// for each point, for each channel, `min` then `max`.
+pub struct Peaks {
+    pub sample_rate: u32,
+    pub samples_per_pixel: u32,
+    pub channels: u16,
+    /// 8 or 16.
+    pub bits: u32,
+    pub data: Vec<i16>,
+}
+
+impl Peaks {
+    /// Number of points (pixels).
+    pub fn length(&self) -> usize {
+        self.data.len() / (2 * self.channels as usize)
+    }
+
+    /// audiowaveform JSON (`--output-format json`).
+    pub fn to_json(&self) -> String {
+        let mut out = format!(
+            "{{\"version\":{},\"channels\":{},\"sample_rate\":{},\"samples_per_pixel\":{},\"bits\":{},\"length\":{},\"data\":[",
+            DAT_VERSION,
+            self.channels,
+            self.sample_rate,
+            self.samples_per_pixel,
+            self.bits,
+            self.length()
+        );
+        for (i, v) in self.data.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            let _ = write!(out, "{}", v);
+        }
+        out.push_str("]}");
+        out
+    }
+
+    /// audiowaveform binary `.dat` (little-endian header, then the data).
+    pub fn to_dat(&self) -> Vec<u8> {
+        let sample_size = if self.bits == 8 { 1 } else { 2 };
+        let mut out = Vec::with_capacity(24 + self.data.len() * sample_size);
+        out.extend_from_slice(&DAT_VERSION.to_le_bytes());
+        let flags = if self.bits == 8 { FLAG_8BIT } else { 0 };
+        out.extend_from_slice(&flags.to_le_bytes());
+        out.extend_from_slice(&(self.sample_rate as i32).to_le_bytes());
+        out.extend_from_slice(&(self.samples_per_pixel as i32).to_le_bytes());
+        out.extend_from_slice(&(self.length() as u32).to_le_bytes());
+        out.extend_from_slice(&(self.channels as i32).to_le_bytes());
+        for v in &self.data {
+            if self.bits == 8 {
+                out.push(*v as i8 as u8);
+            } else {
+                out.extend_from_slice(&v.to_le_bytes());
+            }
+        }
+        out
+    }
+}
+
+/// Decode `in_path` and reduce it to `points_per_second` min/max pairs per
+/// channel, scaled to `bits` (8 or 16) like audiowaveform does.
+pub fn waveform_path(in_path: &Path, points_per_second: u32, bits: u32) -> AudioResult<Peaks> {
+    if points_per_second == 0 {
+        return Err(AudioError::InvalidArgument(
+            "points_per_second must be greater than 0".into(),
+        ));
+    }
+    if bits != 8 && bits != 16 {
+        return Err(AudioError::InvalidArgument("bits must be 8 or 16".into()));
+    }
+
+    let mut dec = Decoder::open(in_path)?;
+    let spec = dec.spec();
+    let channels = spec.channels as usize;
+    let samples_per_pixel = (spec.sample_rate / points_per_second).max(1);
+    let scale = if bits == 8 {
+        i8::MAX as f32
+    } else {
+        i16::MAX as f32
+    };
+    let quantize = |v: f32| (v.clamp(-1.0, 1.0) * scale).round() as i16;
+
+    let mut data = Vec::new();
+    let mut min = vec![f32::MAX; channels];
+    let mut max = vec![f32::MIN; channels];
+    let mut count = 0;
+    let flush = |min: &mut [f32], max: &mut [f32], data: &mut Vec<i16>| {
+        for c in 0..channels {
+            data.push(quantize(min[c]));
+            data.push(quantize(max[c]));
+            min[c] = f32::MAX;
+            max[c] = f32::MIN;
+        }
+    };
+
+    while let Some(block) = dec.next_block()? {
+        for frame in block.chunks_exact(channels) {
+            for (c, s) in frame.iter().enumerate() {
+                min[c] = min[c].min(*s);
+                max[c] = max[c].max(*s);
+            }
+            count += 1;
+            if count == samples_per_pixel {
+                flush(&mut min, &mut max, &mut data);
+                count = 0;
+            }
+        }
+    }
+    // Trailing partial pixel
+    if count > 0 {
+        flush(&mut min, &mut max, &mut data);
+    }
+
+    Ok(Peaks {
+        sample_rate: spec.sample_rate,
+        samples_per_pixel,
+        channels: spec.channels,
+        bits,
+        data,
+    })
+}
+
+/// In-memory variant of `waveform_path`; `ext` helps the decoder sniff.
+pub fn waveform_file(
+    input_bytes: Vec<u8>,
+    ext: &str,
+    points_per_second: u32,
+    bits: u32,
+) -> AudioResult<Peaks> {
+    let tmp_in = Builder::new()
+        .suffix(&format!(".{}", ext))
+        .tempfile()
+        .map_err(AudioError::io("tmpfile in"))?;
+    fs::write(tmp_in.path(), &input_bytes).map_err(AudioError::io("write tmp in"))?;
+    let in_path = tmp_in.into_temp_path();
+
+    waveform_path(&in_path, points_per_second, bits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_wav::wav_i16;
+
+    /// 8 kHz stereo: the left channel ramps up by 10 per frame, the right
+    /// channel mirrors it, so each pixel's min/max are known.
+    fn peaks(bits: u32) -> Peaks {
+        let wav = wav_i16(8000, 2, 2050, |i, c| {
+            let v = (i * 10) as i16;
+            if c == 0 { v } else { -v }
+        });
+        waveform_path(&wav, 10, bits).unwrap()
+    }
+
+    /// `v` as decoded from 16-bit PCM and rescaled to `bits`.
+    fn scaled(v: i32, bits: u32) -> i16 {
+        let max = if bits == 8 { 127.0 } else { 32767.0 };
+        (v as f32 / 32768.0 * max).round() as i16
+    }
+
+    #[test]
+    fn dat_header_and_layout() {
+        let peaks = peaks(16);
+        assert_eq!(peaks.samples_per_pixel, 800);
+        // Two full pixels plus the trailing partial one.
+        assert_eq!(peaks.length(), 3);
+
+        let dat = peaks.to_dat();
+        let word = |i: usize| i32::from_le_bytes(dat[i * 4..i * 4 + 4].try_into().unwrap());
+        assert_eq!(
+            (0..6).map(word).collect::<Vec<_>>(),
+            [DAT_VERSION, 0, 8000, 800, 3, 2]
+        );
+        assert_eq!(dat.len(), 24 + 3 * 2 * 2 * 2);
+
+        // Per point: left min, left max, right min, right max.
+        let value = |i: usize| i16::from_le_bytes([dat[24 + 2 * i], dat[25 + 2 * i]]);
+        let expected = [(0, 7990), (8000, 15990), (16000, 20490)]
+            .iter()
+            .flat_map(|&(lo, hi)| [lo, hi, -hi, -lo])
+            .map(|v| scaled(v, 16))
+            .collect::<Vec<_>>();
+        assert_eq!((0..12).map(value).collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn dat_8bit_sets_flag() {
+        let dat = peaks(8).to_dat();
+        assert_eq!(u32::from_le_bytes(dat[4..8].try_into().unwrap()), FLAG_8BIT);
+        assert_eq!(dat.len(), 24 + 3 * 2 * 2);
+        assert_eq!(dat[24 + 1] as i8 as i16, scaled(7990, 8));
+        assert_eq!(dat[24 + 2] as i8 as i16, scaled(-7990, 8));
+    }
+
+    #[test]
+    fn json_layout() {
+        let peaks = Peaks {
+            sample_rate: 44_100,
+            samples_per_pixel: 441,
+            channels: 2,
+            bits: 8,
+            data: vec![-1, 2, -3, 4, -5, 6, -7, 8],
+        };
+        assert_eq!(
+            peaks.to_json(),
+            "{\"version\":2,\"channels\":2,\"sample_rate\":44100,\"samples_per_pixel\":441,\
+             \"bits\":8,\"length\":2,\"data\":[-1,2,-3,4,-5,6,-7,8]}"
+        );
+    }
+}
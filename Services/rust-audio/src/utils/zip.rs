@@ -1,8 +1,12 @@
+use crate::utils::error::{AudioError, AudioResult};
 use std::collections::HashMap;
+use std::fs::File;
 use std::io::Write;
+use std::path::Path;
+use zip::result::ZipError;
 use zip::write::FileOptions;
 
-pub fn make_zip(files: Vec<(String, Vec<u8>)>) -> Result<Vec<u8>, String> {
+pub fn make_zip(files: Vec<(String, Vec<u8>)>) -> AudioResult<Vec<u8>> {
     let mut buf = Vec::new();
     {
         let mut zip = zip::ZipWriter::new(std::io::Cursor::new(&mut buf));
@@ -12,29 +16,121 @@ pub fn make_zip(files: Vec<(String, Vec<u8>)>) -> Result<Vec<u8>, String> {
         let mut seen: HashMap<String, usize> = HashMap::new();
 
         for (name, data) in files {
-            // avoid paths; keep the visible name
-            let clean = name.replace('\\', "_").replace('/', "_");
-
-            let entry = seen.entry(clean.clone()).or_insert(0);
-            let final_name = if *entry == 0 {
-                clean.clone()
-            } else {
-                // append (n) before the extension
-                let (base, ext) = clean
-                    .rsplit_once('.')
-                    .map(|(b, e)| (b.to_string(), format!(".{e}")))
-                    .unwrap_or((clean.clone(), String::new()));
-                format!("{base}_({}){ext}", *entry) // no extra space
-            };
-            *entry += 1;
+            let final_name = unique_name(&mut seen, &name);
 
             println!("Adding {} ({} bytes)", final_name, data.len());
-            zip.start_file(&final_name, options)
-                .map_err(|e| e.to_string())?;
-            zip.write_all(&data).map_err(|e| e.to_string())?;
+            zip.start_file(&final_name, options).map_err(zip_error)?;
+            zip.write_all(&data)
+                .map_err(AudioError::io("write zip entry"))?;
         }
 
-        zip.finish().map_err(|e| e.to_string())?;
+        zip.finish().map_err(zip_error)?;
     }
     Ok(buf)
 }
+
+/// Same as `make_zip`, but entries are copied from files on disk straight into
+/// the archive at `out_path`, so nothing is buffered in memory.
+pub fn make_zip_path<P: AsRef<Path>>(files: &[(String, P)], out_path: &Path) -> AudioResult<()> {
+    let out = File::create(out_path).map_err(AudioError::io("create zip"))?;
+    let mut zip = zip::ZipWriter::new(out);
+    let options: FileOptions<()> =
+        FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+
+    let mut seen: HashMap<String, usize> = HashMap::new();
+
+    for (name, path) in files {
+        let final_name = unique_name(&mut seen, name);
+
+        let mut src = File::open(path).map_err(AudioError::io("open zip entry"))?;
+        println!("Adding {} from disk", final_name);
+        zip.start_file(&final_name, options).map_err(zip_error)?;
+        std::io::copy(&mut src, &mut zip).map_err(AudioError::io("copy zip entry"))?;
+    }
+
+    zip.finish().map_err(zip_error)?;
+    Ok(())
+}
+
+/// Longest entry name, in bytes.
+const MAX_NAME_LEN: usize = 200;
+
+/// `name` made safe as a flat archive entry. Names can come from user
+/// metadata (e.g. chapter titles), so path separators become `_`, control
+/// characters are dropped, runs of dots collapse (no `..`) and long names
+/// are cut to `MAX_NAME_LEN`, keeping a short extension.
+fn entry_name(name: &str) -> String {
+    let mut clean = String::with_capacity(name.len());
+    for c in name.chars() {
+        match c {
+            '/' | '\\' => clean.push('_'),
+            '.' if clean.ends_with('.') => {}
+            c if c.is_control() => {}
+            c => clean.push(c),
+        }
+    }
+    if clean.len() > MAX_NAME_LEN {
+        let ext = clean
+            .rfind('.')
+            .map(|i| clean[i..].to_string())
+            .filter(|ext| ext.len() <= 16)
+            .unwrap_or_default();
+        let mut end = MAX_NAME_LEN - ext.len();
+        while !clean.is_char_boundary(end) {
+            end -= 1;
+        }
+        clean = format!("{}{}", &clean[..end], ext);
+    }
+    if clean.is_empty() || clean == "." {
+        clean = "file".to_string();
+    }
+    clean
+}
+
+/// `name` made safe (see `entry_name`), or `name_(n).ext` if it was already
+/// handed out.
+pub(crate) fn unique_name(seen: &mut HashMap<String, usize>, name: &str) -> String {
+    let clean = entry_name(name);
+
+    let entry = seen.entry(clean.clone()).or_insert(0);
+    let final_name = if *entry == 0 {
+        clean.clone()
+    } else {
+        // append (n) before the extension
+        let (base, ext) = clean
+            .rsplit_once('.')
+            .map(|(b, e)| (b.to_string(), format!(".{e}")))
+            .unwrap_or((clean.clone(), String::new()));
+        format!("{base}_({}){ext}", *entry) // no extra space
+    };
+    *entry += 1;
+    final_name
+}
+
+fn zip_error(e: ZipError) -> AudioError {
+    AudioError::Io {
+        context: "zip".to_string(),
+        source: std::io::Error::other(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entry_names_are_flat_and_bounded() {
+        assert_eq!(entry_name("../../etc/passwd"), "._._etc_passwd");
+        assert_eq!(entry_name("a\\b\u{0}\nc...wav"), "a_bc.wav");
+        assert_eq!(entry_name(".."), "file");
+
+        let long = format!("{}.flac", "é".repeat(300));
+        let name = entry_name(&long);
+        assert!(name.len() <= MAX_NAME_LEN);
+        assert!(name.ends_with("é.flac"));
+
+        let mut seen = HashMap::new();
+        assert_eq!(unique_name(&mut seen, "Intro.mp3"), "Intro.mp3");
+        assert_eq!(unique_name(&mut seen, "Intro.mp3"), "Intro_(1).mp3");
+    }
+}